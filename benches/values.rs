@@ -0,0 +1,72 @@
+//! Times arithmetic-heavy scripts, to compare the tagged enum `Value` with the NaN-boxed one
+//!
+//! Run once with each representation and compare:
+//!
+//!     cargo bench --bench values
+//!     cargo bench --bench values --features nan-boxing
+
+use bylox::vm::Vm;
+
+use std::time::Duration;
+use std::time::Instant;
+
+const RUNS: usize = 5;
+
+const SCRIPTS: &[(&str, &str)] = &[
+    (
+        "fib",
+        "fun fib(n) { if (n < 2) return n; return fib(n - 2) + fib(n - 1); }
+        fib(24);",
+    ),
+    (
+        "sum loop",
+        "{
+            var sum = 0;
+            for (var i = 0; i < 1000000; i = i + 1) {
+                sum = sum + i * 2 - i / 2;
+            }
+        }",
+    ),
+    (
+        "float math",
+        "{
+            var x = 0.5;
+            var v = 0;
+            for (var i = 0; i < 300000; i = i + 1) {
+                var a = -x * 1.5 + v;
+                v = v + a * 0.01;
+                x = x + v * 0.01;
+                if (x > 10 or x < -10) x = 0.5;
+            }
+        }",
+    ),
+];
+
+fn time(source: &str) -> Duration {
+    let mut vm = Vm::new();
+
+    let start = Instant::now();
+    vm.interpret(source).expect("benchmark script failed");
+    start.elapsed()
+}
+
+fn main() {
+    let representation = if cfg!(feature = "nan-boxing") {
+        "nan-boxing"
+    } else {
+        "tagged enum"
+    };
+    println!("value representation: {}", representation);
+
+    for (name, source) in SCRIPTS {
+        let mut times: Vec<Duration> = (0..RUNS).map(|_| time(source)).collect();
+        times.sort();
+
+        println!(
+            "{:>12}: min {:>10.3?}  median {:>10.3?}",
+            name,
+            times[0],
+            times[RUNS / 2]
+        );
+    }
+}
@@ -6,6 +6,136 @@ use clap::Parser;
 #[command(version = "1.0")]
 #[command(about = "Lox interpreter", long_about = None)]
 pub struct ArgStruct {
-    /// The script to run
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// The script to run, either source or compiled with `bylox compile`
     pub script: Option<std::path::PathBuf>,
+
+    /// Disassemble each instruction as it runs
+    #[arg(long, global = true)]
+    pub trace: bool,
+
+    /// Print the stack before each instruction
+    #[arg(long, global = true)]
+    pub dump_stack: bool,
+
+    /// Print the heap's objects before each instruction
+    #[arg(long, global = true)]
+    pub dump_heap: bool,
+
+    /// Disassemble each function once it is compiled
+    #[arg(long, global = true)]
+    pub disassemble: bool,
+
+    /// How much to optimize scripts: 0 compiles them as written, 1 folds constants and combines
+    /// instructions
+    #[arg(
+        short = 'O',
+        long,
+        global = true,
+        value_name = "LEVEL",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u8).range(0..=1)
+    )]
+    pub opt_level: u8,
+
+    /// Stop scripts after they execute this many instructions
+    #[arg(long, global = true, value_name = "COUNT")]
+    pub max_instructions: Option<u64>,
+
+    /// Stop scripts once the stack holds more than this many values
+    #[arg(long, global = true, value_name = "COUNT")]
+    pub max_stack: Option<usize>,
+
+    /// Stop scripts once their objects take up more than this many bytes
+    #[arg(long, global = true, value_name = "BYTES")]
+    pub max_memory: Option<usize>,
+}
+
+impl ArgStruct {
+    pub fn vm_options(&self) -> crate::vm::VmOptions {
+        crate::vm::VmOptions::default()
+            .trace(self.trace)
+            .dump_stack(self.dump_stack)
+            .dump_heap(self.dump_heap)
+            .disassemble(self.disassemble)
+            .opt_level(match self.opt_level {
+                0 => crate::OptLevel::O0,
+                _ => crate::OptLevel::O1,
+            })
+            .limits(self.limits())
+    }
+
+    fn limits(&self) -> crate::vm::Limits {
+        let mut limits = crate::vm::Limits::default();
+        if let Some(instructions) = self.max_instructions {
+            limits = limits.instructions(instructions);
+        }
+        if let Some(stack) = self.max_stack {
+            limits = limits.stack(stack);
+        }
+        if let Some(memory) = self.max_memory {
+            limits = limits.memory(memory);
+        }
+        limits
+    }
+}
+
+#[derive(clap::Subcommand)]
+pub enum Command {
+    /// Compile a script to bytecode without running it
+    Compile {
+        /// The script to compile
+        script: std::path::PathBuf,
+
+        /// Where to write the bytecode, by default the script's path with an `.lxc` extension
+        #[arg(short, long)]
+        output: Option<std::path::PathBuf>,
+    },
+    /// Parse a script without compiling or running it, reporting any syntax errors
+    Parse {
+        /// The script to parse
+        script: std::path::PathBuf,
+
+        /// Print the syntax tree
+        #[arg(long)]
+        dump_ast: bool,
+    },
+    /// Assemble a bytecode listing, in the format `--disassemble` prints, and run it
+    Asm {
+        /// The listing to assemble
+        listing: std::path::PathBuf,
+
+        /// Write the bytecode to this path instead of running it
+        #[arg(short, long)]
+        output: Option<std::path::PathBuf>,
+    },
+    /// Run the `.lox` scripts in a directory, checking them against their `// expect:` comments
+    Test {
+        /// The directory to search for scripts
+        dir: std::path::PathBuf,
+
+        /// Only run scripts whose path contains this
+        #[arg(short, long)]
+        filter: Option<String>,
+
+        /// How many scripts to run at once, by default one per CPU
+        #[arg(short, long)]
+        jobs: Option<usize>,
+    },
+    /// Run programs through both the bytecode `Vm` and a reference tree-walking interpreter,
+    /// reporting where they differ
+    Diff {
+        /// Scripts, or directories to search for `.lox` scripts
+        paths: Vec<std::path::PathBuf>,
+
+        /// How many random programs to generate and compare
+        #[arg(short, long, default_value_t = 0)]
+        random: usize,
+
+        /// The seed of the first random program, by default taken from the clock
+        #[arg(short, long)]
+        seed: Option<u64>,
+    },
 }
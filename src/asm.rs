@@ -0,0 +1,670 @@
+//! An assembler for the listings the disassembler prints
+//!
+//! A listing is made of sections, each starting with a `=== name ===` header, holding one
+//! instruction per line. The first section is the script, and each function it loads with a
+//! closure instruction follows it, in the order of their constants, with their own nested
+//! functions after them. A listing without any header is a single script section.
+//!
+//! Instruction lines may start with the offset and line columns the disassembler prints. The
+//! offset is ignored, a `|` keeps the previous line, and a lone number is taken as the line.
+//! Besides what the disassembler prints, hand-written listings may use:
+//!
+//! - `; comments` until the end of a line
+//! - `name:` to label the next instruction, and `-> name` as a jump target
+//! - constants without an index, like `OP_CONSTANT 1.5`, which are added to the pool
+//! - global variables by name alone, like `OP_GET_GLOBAL count`
+//! - `arity N` after a function's name in its header
+//!
+//! ```text
+//! === <script> ===
+//! 0000 0001 OP_CONSTANT      0 "count"
+//! loop:
+//!           OP_PRINT
+//!           OP_NIL
+//!           OP_JUMP_IF_FALSE -> loop
+//! ```
+
+use crate::chunk::Chunk;
+use crate::chunk::OpCode;
+use crate::chunk::Operand;
+use crate::heap::Heap;
+use crate::table::Globals;
+use crate::value::Function;
+use crate::value::Value;
+
+use std::collections::HashMap;
+
+/// Why a listing couldn't be assembled
+#[derive(Debug)]
+pub struct AsmError {
+    /// The line of the listing the problem was found on
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::error::Error for AsmError {}
+
+impl std::fmt::Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+fn error<T>(line: usize, message: impl Into<String>) -> Result<T, AsmError> {
+    Err(AsmError {
+        line,
+        message: message.into(),
+    })
+}
+
+/// Assembles a listing into a script function, allocating its constants on `heap`
+///
+/// Global variables named in the listing are given slots in `globals`, in the order of the
+/// slots the listing shows for them. Like the compiler, this never collects garbage.
+pub(crate) fn assemble(
+    listing: &str,
+    heap: &mut Heap,
+    globals: &mut Globals,
+) -> Result<Function, AsmError> {
+    let sections = parse(listing)?;
+
+    let global_slots = resolve_globals(&sections, heap, globals);
+
+    let mut assembler = Assembler {
+        sections: &sections,
+        next_section: 0,
+        heap,
+        global_slots,
+    };
+
+    let mut script = assembler.function(0)?;
+    script.name = None;
+
+    if let Some(section) = sections.get(assembler.next_section) {
+        return error(section.line, "no closure loads this function");
+    }
+
+    Ok(script)
+}
+
+/// A lexical token on an instruction line
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    String(String),
+}
+
+impl Token {
+    fn word(&self) -> Option<&str> {
+        match self {
+            Token::Word(word) => Some(word),
+            Token::String(_) => None,
+        }
+    }
+}
+
+struct Section {
+    /// The listing line of the header, or of the first instruction when there is no header
+    line: usize,
+    name: String,
+    arity: usize,
+    statements: Vec<Statement>,
+}
+
+struct Statement {
+    /// The listing line the statement is on
+    line: usize,
+    /// The source line annotation, `None` for `|` or none at all
+    source_line: Option<usize>,
+    kind: StatementKind,
+}
+
+enum StatementKind {
+    Label(String),
+    Instruction(OpCode, Vec<Token>),
+    Upvalue { is_local: bool, index: u8 },
+}
+
+fn tokenize(line_number: usize, line: &str) -> Result<Vec<Token>, AsmError> {
+    let mut tokens = vec![];
+    let mut chars = line.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == ';' {
+            break;
+        } else if c == '"' {
+            chars.next();
+            let mut string = String::new();
+            loop {
+                match chars.next() {
+                    None => return error(line_number, "unterminated string"),
+                    Some('"') => break,
+                    Some('\\') => string.push(unescape(line_number, &mut chars)?),
+                    Some(c) => string.push(c),
+                }
+            }
+            tokens.push(Token::String(string));
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == ';' {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Word(word));
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Reads the rest of an escape sequence written by `str::escape_debug`
+fn unescape(
+    line: usize,
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+) -> Result<char, AsmError> {
+    match chars.next() {
+        Some('n') => Ok('\n'),
+        Some('t') => Ok('\t'),
+        Some('r') => Ok('\r'),
+        Some('0') => Ok('\0'),
+        Some(c @ ('\\' | '"' | '\'')) => Ok(c),
+        Some('u') => {
+            if chars.next() != Some('{') {
+                return error(line, "expected `{` in unicode escape");
+            }
+            let mut hex = String::new();
+            loop {
+                match chars.next() {
+                    Some('}') => break,
+                    Some(c) => hex.push(c),
+                    None => return error(line, "unterminated unicode escape"),
+                }
+            }
+            match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                Some(c) => Ok(c),
+                None => error(line, format!("invalid unicode escape `{}`", hex)),
+            }
+        }
+        _ => error(line, "invalid escape sequence"),
+    }
+}
+
+fn parse(listing: &str) -> Result<Vec<Section>, AsmError> {
+    let mut sections: Vec<Section> = vec![];
+
+    for (index, text) in listing.lines().enumerate() {
+        let line = index + 1;
+
+        if let Some(header) = text.trim().strip_prefix("===") {
+            let Some(header) = header.strip_suffix("===") else {
+                return error(line, "expected `===` after the section name");
+            };
+            let (name, arity) = parse_header(line, header.trim())?;
+            sections.push(Section {
+                line,
+                name,
+                arity,
+                statements: vec![],
+            });
+            continue;
+        }
+
+        let mut tokens = tokenize(line, text)?;
+        if tokens.is_empty() {
+            continue;
+        }
+
+        if sections.is_empty() {
+            sections.push(Section {
+                line,
+                name: "<script>".to_string(),
+                arity: 0,
+                statements: vec![],
+            });
+        }
+        let section = sections.last_mut().unwrap();
+
+        // the offset and line columns
+        let prefix = tokens
+            .iter()
+            .take(2)
+            .take_while(|token| {
+                token
+                    .word()
+                    .is_some_and(|word| word == "|" || word.bytes().all(|b| b.is_ascii_digit()))
+            })
+            .count();
+        let source_line = match prefix {
+            0 => None,
+            _ => match tokens[prefix - 1].word().unwrap() {
+                "|" => None,
+                number => Some(parse_number(line, number)?),
+            },
+        };
+        tokens.drain(..prefix);
+
+        let Some(first) = tokens.first().and_then(Token::word).map(str::to_string) else {
+            return error(line, "expected an instruction");
+        };
+
+        let kind = if let Some(label) = first.strip_suffix(':') {
+            if tokens.len() != 1 || label.is_empty() {
+                return error(line, "expected a label on its own");
+            }
+            StatementKind::Label(label.to_string())
+        } else if first == "local" || first == "upvalue" {
+            let index = match &tokens[1..] {
+                [Token::Word(index)] => parse_number(line, index)?,
+                _ => return error(line, format!("expected an index after `{}`", first)),
+            };
+            let Ok(index) = u8::try_from(index) else {
+                return error(line, "upvalue index is too large");
+            };
+            StatementKind::Upvalue {
+                is_local: first == "local",
+                index,
+            }
+        } else if let Some(opcode) = OpCode::from_name(&first) {
+            StatementKind::Instruction(opcode, tokens.split_off(1))
+        } else {
+            return error(line, format!("unknown instruction `{}`", first));
+        };
+
+        section.statements.push(Statement {
+            line,
+            source_line,
+            kind,
+        });
+    }
+
+    if sections.is_empty() {
+        return error(1, "the listing is empty");
+    }
+
+    Ok(sections)
+}
+
+fn parse_header(line: usize, header: &str) -> Result<(String, usize), AsmError> {
+    let words: Vec<&str> = header.split_whitespace().collect();
+    match words[..] {
+        [name] => Ok((name.to_string(), 0)),
+        [name, "arity", arity] => Ok((name.to_string(), parse_number(line, arity)?)),
+        _ => error(line, "expected a function name and optional `arity N`"),
+    }
+}
+
+fn parse_number(line: usize, word: &str) -> Result<usize, AsmError> {
+    match word.parse() {
+        Ok(number) => Ok(number),
+        Err(_) => error(line, format!("expected a number, found `{}`", word)),
+    }
+}
+
+/// Gives each global variable named in the listing a slot, in the order the listing numbers them
+fn resolve_globals(
+    sections: &[Section],
+    heap: &mut Heap,
+    globals: &mut Globals,
+) -> HashMap<String, usize> {
+    let mut named: Vec<(usize, &str)> = vec![];
+
+    for statement in sections.iter().flat_map(|section| &section.statements) {
+        let StatementKind::Instruction(opcode, tokens) = &statement.kind else {
+            continue;
+        };
+        if !matches!(opcode.operands(), [Operand::Global | Operand::LongGlobal]) {
+            continue;
+        }
+        match &tokens[..] {
+            [Token::Word(slot), Token::Word(name)] => {
+                named.push((slot.parse().unwrap_or(usize::MAX), name));
+            }
+            [Token::Word(name)] if name.parse::<usize>().is_err() => named.push((usize::MAX, name)),
+            _ => (),
+        }
+    }
+
+    named.sort_by_key(|(slot, _)| *slot);
+
+    let mut slots = HashMap::new();
+    for (_, name) in named {
+        if !slots.contains_key(name) {
+            let symbol = heap.intern_symbol(name);
+            slots.insert(name.to_string(), globals.resolve(symbol));
+        }
+    }
+    slots
+}
+
+/// A constant pool entry as it is assembled
+enum Constant {
+    Value(Value),
+    /// A function whose section hasn't been assembled yet
+    Function {
+        name: String,
+        /// The number of upvalues listed after the closure that loads it
+        upvalue_count: Option<usize>,
+    },
+}
+
+impl Constant {
+    fn same_as(&self, other: &Constant) -> bool {
+        match (self, other) {
+            (Constant::Value(a), Constant::Value(b)) => match (a.as_number(), b.as_number()) {
+                (Some(a), Some(b)) => a.to_bits() == b.to_bits(),
+                _ => a == b,
+            },
+            (Constant::Function { name: a, .. }, Constant::Function { name: b, .. }) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// A jump whose distance is filled in once every label is known
+struct Fixup {
+    line: usize,
+    /// The offset of the jump's operand
+    operand: usize,
+    target: Target,
+    forward: bool,
+}
+
+enum Target {
+    Offset(usize),
+    Label(String),
+}
+
+struct Assembler<'a> {
+    sections: &'a [Section],
+    next_section: usize,
+    heap: &'a mut Heap,
+    global_slots: HashMap<String, usize>,
+}
+
+impl<'a> Assembler<'a> {
+    /// Assembles the next section, then the sections of the functions it loads
+    fn function(&mut self, upvalue_count: usize) -> Result<Function, AsmError> {
+        let sections = self.sections;
+        let section = &sections[self.next_section];
+        self.next_section += 1;
+
+        let mut chunk = Chunk::default();
+        let mut constants: Vec<Option<Constant>> = vec![];
+        let mut labels = HashMap::new();
+        let mut fixups = vec![];
+        let mut source_line = 1;
+        // the function constant of the last closure, which upvalue lines add to
+        let mut closure = None;
+
+        for statement in &section.statements {
+            let line = statement.line;
+            if let Some(annotated) = statement.source_line {
+                source_line = annotated;
+            }
+
+            let (opcode, tokens) = match &statement.kind {
+                StatementKind::Label(label) => {
+                    if labels.insert(label.clone(), chunk.len()).is_some() {
+                        return error(line, format!("label `{}` is already defined", label));
+                    }
+                    continue;
+                }
+                StatementKind::Upvalue { is_local, index } => {
+                    let Some(Some(Constant::Function { upvalue_count, .. })) =
+                        closure.and_then(|index: usize| constants.get_mut(index))
+                    else {
+                        return error(line, "upvalues must follow a closure instruction");
+                    };
+                    *upvalue_count = Some(upvalue_count.unwrap_or(0) + 1);
+                    chunk.write(*is_local as u8, source_line);
+                    chunk.write(*index, source_line);
+                    continue;
+                }
+                StatementKind::Instruction(opcode, tokens) => (*opcode, tokens.as_slice()),
+            };
+
+            closure = None;
+            chunk.write(opcode as u8, source_line);
+
+            let mut tokens = tokens;
+            if matches!(
+                opcode,
+                OpCode::Invoke | OpCode::LongInvoke | OpCode::SuperInvoke | OpCode::LongSuperInvoke
+            ) {
+                let arg_count = match tokens {
+                    [Token::Word(open), Token::Word(args), rest @ ..] if args == "args)" => {
+                        tokens = rest;
+                        open.strip_prefix('(')
+                            .map(|count| parse_number(line, count))
+                    }
+                    _ => None,
+                };
+                let Some(arg_count) = arg_count else {
+                    return error(line, "expected `(N args)`");
+                };
+                let Ok(arg_count) = u8::try_from(arg_count?) else {
+                    return error(line, "too many arguments");
+                };
+
+                let index = self.constant(line, tokens, opcode, &mut constants)?;
+                write_operand(
+                    &mut chunk,
+                    line,
+                    index,
+                    opcode.operands()[0].size(),
+                    source_line,
+                )?;
+                chunk.write(arg_count, source_line);
+                continue;
+            }
+
+            match opcode.operands() {
+                [] => {
+                    if !tokens.is_empty() {
+                        return error(line, format!("{} takes no operands", opcode.name()));
+                    }
+                }
+                [Operand::Byte] => {
+                    let [Token::Word(byte)] = tokens else {
+                        return error(line, format!("expected one operand for {}", opcode.name()));
+                    };
+                    let Ok(byte) = u8::try_from(parse_number(line, byte)?) else {
+                        return error(line, "operand is too large for one byte");
+                    };
+                    chunk.write(byte, source_line);
+                }
+                [operand @ (Operand::Constant | Operand::LongConstant)] => {
+                    let index = self.constant(line, tokens, opcode, &mut constants)?;
+                    if matches!(opcode, OpCode::Closure | OpCode::LongClosure) {
+                        closure = Some(index);
+                        if let Some(Constant::Function { upvalue_count, .. }) =
+                            &mut constants[index]
+                        {
+                            upvalue_count.get_or_insert(0);
+                        }
+                    }
+                    write_operand(&mut chunk, line, index, operand.size(), source_line)?;
+                }
+                [operand @ (Operand::Global | Operand::LongGlobal)] => {
+                    let slot = match tokens {
+                        [Token::Word(_), Token::Word(name)] => self.global_slots[name],
+                        [Token::Word(word)] => match word.parse() {
+                            Ok(slot) => slot,
+                            Err(_) => self.global_slots[word],
+                        },
+                        _ => return error(line, "expected a global slot or name"),
+                    };
+                    write_operand(&mut chunk, line, slot, operand.size(), source_line)?;
+                }
+                [operand @ (Operand::Jump | Operand::Loop)] => {
+                    let target = match tokens {
+                        [Token::Word(_), Token::Word(arrow), Token::Word(target)]
+                        | [Token::Word(arrow), Token::Word(target)]
+                            if arrow == "->" =>
+                        {
+                            target
+                        }
+                        _ => return error(line, "expected `-> target`"),
+                    };
+                    let target = match target.parse() {
+                        Ok(offset) => Target::Offset(offset),
+                        Err(_) => Target::Label(target.clone()),
+                    };
+                    fixups.push(Fixup {
+                        line,
+                        operand: chunk.len(),
+                        target,
+                        forward: *operand == Operand::Jump,
+                    });
+                    chunk.write(0, source_line);
+                    chunk.write(0, source_line);
+                }
+                _ => unreachable!("invoke operands are handled above"),
+            }
+        }
+
+        for fixup in fixups {
+            let target = match &fixup.target {
+                Target::Offset(offset) => *offset,
+                Target::Label(label) => match labels.get(label) {
+                    Some(offset) => *offset,
+                    None => return error(fixup.line, format!("undefined label `{}`", label)),
+                },
+            };
+            let end = fixup.operand + 2;
+            let distance = if fixup.forward {
+                target.checked_sub(end)
+            } else {
+                end.checked_sub(target)
+            };
+            let Some(distance) = distance.and_then(|distance| u16::try_from(distance).ok()) else {
+                return error(fixup.line, "jump target is out of range");
+            };
+            let [high, low] = distance.to_be_bytes();
+            chunk.patch(fixup.operand, high);
+            chunk.patch(fixup.operand + 1, low);
+        }
+
+        // the functions this one loads follow it, in the order of their constants
+        for constant in constants.iter_mut() {
+            let Some(Constant::Function {
+                name,
+                upvalue_count,
+            }) = constant
+            else {
+                continue;
+            };
+            let Some(nested) = sections.get(self.next_section) else {
+                return error(
+                    section.line,
+                    format!("missing the section for `<fn {}>`", name),
+                );
+            };
+            if nested.name != *name {
+                let message = format!("expected the section for `<fn {}>`", name);
+                return error(nested.line, message);
+            }
+            let function = self.function(upvalue_count.unwrap_or(0))?;
+            *constant = Some(Constant::Value(self.heap.alloc(function).into()));
+        }
+
+        for (index, constant) in constants.into_iter().enumerate() {
+            match constant {
+                Some(Constant::Value(value)) => {
+                    chunk.add_constant(value);
+                }
+                _ => return error(section.line, format!("constant {} is never defined", index)),
+            }
+        }
+
+        Ok(Function {
+            arity: section.arity,
+            upvalue_count,
+            chunk,
+            name: Some(section.name.clone()),
+        })
+    }
+
+    /// Adds the constant operand in `tokens` to the pool and returns its index
+    fn constant(
+        &mut self,
+        line: usize,
+        tokens: &[Token],
+        opcode: OpCode,
+        constants: &mut Vec<Option<Constant>>,
+    ) -> Result<usize, AsmError> {
+        let (index, value) = match tokens {
+            [Token::Word(index), value @ ..]
+                if !value.is_empty() && index.parse::<usize>().is_ok() =>
+            {
+                (Some(index.parse().unwrap()), value)
+            }
+            value => (None, value),
+        };
+
+        let constant = match value {
+            [Token::String(s)] => Constant::Value(self.heap.intern(s).into()),
+            [Token::Word(word)] if word == "nil" => Constant::Value(Value::NIL),
+            [Token::Word(word)] if word == "true" => Constant::Value(true.into()),
+            [Token::Word(word)] if word == "false" => Constant::Value(false.into()),
+            [Token::Word(word)] => match word.parse::<f64>() {
+                Ok(n) => Constant::Value(n.into()),
+                Err(_) => return error(line, format!("invalid constant `{}`", word)),
+            },
+            [Token::Word(fn_), Token::Word(name)] if fn_ == "<fn" && name.ends_with('>') => {
+                Constant::Function {
+                    name: name.trim_end_matches('>').to_string(),
+                    upvalue_count: None,
+                }
+            }
+            _ => return error(line, format!("expected a constant for {}", opcode.name())),
+        };
+
+        let is_function = matches!(constant, Constant::Function { .. });
+        let is_closure = matches!(opcode, OpCode::Closure | OpCode::LongClosure);
+        if is_function != is_closure {
+            return error(line, format!("{} cannot take this constant", opcode.name()));
+        }
+
+        let index = index.unwrap_or(constants.len());
+        if index >= constants.len() {
+            constants.resize_with(index + 1, || None);
+        }
+        match &constants[index] {
+            Some(existing) if !existing.same_as(&constant) => error(
+                line,
+                format!("constant {} is already defined differently", index),
+            ),
+            Some(_) => Ok(index),
+            None => {
+                constants[index] = Some(constant);
+                Ok(index)
+            }
+        }
+    }
+}
+
+/// Writes a big-endian operand, checking that it fits
+fn write_operand(
+    chunk: &mut Chunk,
+    line: usize,
+    value: usize,
+    size: usize,
+    source_line: usize,
+) -> Result<(), AsmError> {
+    if value >= 1 << (8 * size) {
+        return error(
+            line,
+            format!("operand {} is too large for {} bytes", value, size),
+        );
+    }
+    for byte in &(value as u32).to_be_bytes()[4 - size..] {
+        chunk.write(*byte, source_line);
+    }
+    Ok(())
+}
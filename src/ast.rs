@@ -0,0 +1,500 @@
+//! The syntax tree the parser builds and the compiler lowers to bytecode
+//!
+//! Every node owns its data and records the span of source it was parsed from. Printing a
+//! [`Program`] shows the tree one node per line, indented under its parent, with each node's
+//! byte range.
+
+use crate::diagnostic::Span;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+    pub statements: Vec<Stmt>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identifier {
+    pub name: String,
+    pub span: Span,
+}
+
+/// An operator and the span of its token
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Operator<K> {
+    pub kind: K,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StmtKind {
+    Expression(Expr),
+    Print(Expr),
+    Var {
+        name: Identifier,
+        initializer: Option<Expr>,
+    },
+    Block(Vec<Stmt>),
+    If {
+        condition: Expr,
+        /// The `)` closing the condition
+        right_paren: Span,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+    },
+    While {
+        condition: Expr,
+        /// The `)` closing the condition
+        right_paren: Span,
+        body: Box<Stmt>,
+    },
+    For {
+        /// A `Var` or `Expression` statement
+        initializer: Option<Box<Stmt>>,
+        condition: Option<Expr>,
+        /// The `;` after the condition
+        semicolon: Span,
+        increment: Option<Expr>,
+        /// The `)` closing the clauses
+        right_paren: Span,
+        body: Box<Stmt>,
+    },
+    Function(Function),
+    Class(Class),
+    Return(Option<Expr>),
+}
+
+/// A function declaration or method, spanning from its name to the end of its body
+#[derive(Clone, Debug, PartialEq)]
+pub struct Function {
+    pub name: Identifier,
+    pub params: Vec<Identifier>,
+    pub body: Vec<Stmt>,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Class {
+    pub name: Identifier,
+    pub superclass: Option<Identifier>,
+    pub methods: Vec<Function>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+impl Drop for Expr {
+    // a long chain such as `a.b.c` nests too deeply to be dropped recursively, so operands are
+    // moved out onto a stack and dropped once they have none of their own
+    fn drop(&mut self) {
+        let mut operands = vec![];
+        take_operands(&mut self.kind, &mut operands);
+        while let Some(mut operand) = operands.pop() {
+            take_operands(&mut operand.kind, &mut operands);
+        }
+    }
+}
+
+fn take_operands(kind: &mut ExprKind, operands: &mut Vec<Expr>) {
+    let mut take = |operand: &mut Box<Expr>| {
+        if !matches!(
+            operand.kind,
+            ExprKind::Literal(_) | ExprKind::Variable(_) | ExprKind::This
+        ) {
+            let empty = Expr {
+                kind: ExprKind::Literal(Literal::Nil),
+                span: operand.span,
+            };
+            operands.push(std::mem::replace(&mut **operand, empty));
+        }
+    };
+
+    match kind {
+        ExprKind::Literal(_) | ExprKind::Variable(_) | ExprKind::This | ExprKind::Super { .. } => {}
+        ExprKind::Grouping(operand)
+        | ExprKind::Assign { value: operand, .. }
+        | ExprKind::Unary { operand, .. }
+        | ExprKind::Get {
+            object: operand, ..
+        } => take(operand),
+        ExprKind::Binary { left, right, .. } | ExprKind::Logical { left, right, .. } => {
+            take(left);
+            take(right);
+        }
+        ExprKind::Set { object, value, .. } => {
+            take(object);
+            take(value);
+        }
+        ExprKind::Call { callee, arguments } => {
+            take(callee);
+            operands.append(arguments);
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExprKind {
+    Literal(Literal),
+    Grouping(Box<Expr>),
+    Variable(Identifier),
+    Assign {
+        name: Identifier,
+        value: Box<Expr>,
+    },
+    Unary {
+        operator: Operator<UnaryOp>,
+        operand: Box<Expr>,
+    },
+    Binary {
+        left: Box<Expr>,
+        operator: Operator<BinaryOp>,
+        right: Box<Expr>,
+    },
+    Logical {
+        left: Box<Expr>,
+        operator: Operator<LogicalOp>,
+        right: Box<Expr>,
+    },
+    Call {
+        callee: Box<Expr>,
+        arguments: Vec<Expr>,
+    },
+    Get {
+        object: Box<Expr>,
+        name: Identifier,
+    },
+    Set {
+        object: Box<Expr>,
+        name: Identifier,
+        value: Box<Expr>,
+    },
+    This,
+    Super {
+        method: Identifier,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Literal {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    /// The string with its escapes already processed
+    String(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryOp {
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogicalOp {
+    And,
+    Or,
+}
+
+impl std::fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            UnaryOp::Negate => "-",
+            UnaryOp::Not => "!",
+        })
+    }
+}
+
+impl std::fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            BinaryOp::Equal => "==",
+            BinaryOp::NotEqual => "!=",
+            BinaryOp::Greater => ">",
+            BinaryOp::GreaterEqual => ">=",
+            BinaryOp::Less => "<",
+            BinaryOp::LessEqual => "<=",
+            BinaryOp::Add => "+",
+            BinaryOp::Subtract => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Remainder => "%",
+        })
+    }
+}
+
+impl std::fmt::Display for LogicalOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            LogicalOp::And => "and",
+            LogicalOp::Or => "or",
+        })
+    }
+}
+
+impl std::fmt::Display for Literal {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Literal::Nil => write!(f, "nil"),
+            Literal::Bool(b) => write!(f, "{}", b),
+            Literal::Number(n) => write!(f, "{}", n),
+            Literal::String(s) => write!(f, "{:?}", s),
+        }
+    }
+}
+
+/// Prints a program as an indented tree
+///
+/// ```text
+/// Print 0..14
+///   Binary + 6..13
+///     Literal 1 6..7
+///     Variable x 10..11
+/// ```
+impl std::fmt::Display for Program {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut printer = Printer { f, depth: 0 };
+        for statement in &self.statements {
+            printer.stmt(None, statement)?;
+        }
+        Ok(())
+    }
+}
+
+struct Printer<'f, 'w> {
+    f: &'f mut std::fmt::Formatter<'w>,
+    depth: usize,
+}
+
+impl Printer<'_, '_> {
+    /// Prints one node, with `role` saying what it is to its parent if that isn't obvious
+    fn node(
+        &mut self,
+        role: Option<&str>,
+        text: std::fmt::Arguments,
+        span: Span,
+    ) -> std::fmt::Result {
+        write!(self.f, "{:1$}", "", self.depth * 2)?;
+        if let Some(role) = role {
+            write!(self.f, "{}: ", role)?;
+        }
+        writeln!(self.f, "{} {}..{}", text, span.start, span.end)
+    }
+
+    fn children(&mut self, print: impl FnOnce(&mut Self) -> std::fmt::Result) -> std::fmt::Result {
+        self.depth += 1;
+        let result = print(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn stmt(&mut self, role: Option<&str>, stmt: &Stmt) -> std::fmt::Result {
+        let span = stmt.span;
+        match &stmt.kind {
+            StmtKind::Expression(expr) => {
+                self.node(role, format_args!("Expression"), span)?;
+                self.children(|p| p.expr(None, expr))
+            }
+            StmtKind::Print(expr) => {
+                self.node(role, format_args!("Print"), span)?;
+                self.children(|p| p.expr(None, expr))
+            }
+            StmtKind::Var { name, initializer } => {
+                self.node(role, format_args!("Var {}", name.name), span)?;
+                self.children(|p| initializer.iter().try_for_each(|e| p.expr(None, e)))
+            }
+            StmtKind::Block(statements) => {
+                self.node(role, format_args!("Block"), span)?;
+                self.children(|p| statements.iter().try_for_each(|s| p.stmt(None, s)))
+            }
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.node(role, format_args!("If"), span)?;
+                self.children(|p| {
+                    p.expr(Some("condition"), condition)?;
+                    p.stmt(Some("then"), then_branch)?;
+                    else_branch.iter().try_for_each(|s| p.stmt(Some("else"), s))
+                })
+            }
+            StmtKind::While {
+                condition, body, ..
+            } => {
+                self.node(role, format_args!("While"), span)?;
+                self.children(|p| {
+                    p.expr(Some("condition"), condition)?;
+                    p.stmt(Some("body"), body)
+                })
+            }
+            StmtKind::For {
+                initializer,
+                condition,
+                increment,
+                body,
+                ..
+            } => {
+                self.node(role, format_args!("For"), span)?;
+                self.children(|p| {
+                    initializer
+                        .iter()
+                        .try_for_each(|s| p.stmt(Some("initializer"), s))?;
+                    condition
+                        .iter()
+                        .try_for_each(|e| p.expr(Some("condition"), e))?;
+                    increment
+                        .iter()
+                        .try_for_each(|e| p.expr(Some("increment"), e))?;
+                    p.stmt(Some("body"), body)
+                })
+            }
+            StmtKind::Function(function) => self.function(role, "Function", function),
+            StmtKind::Class(class) => {
+                match &class.superclass {
+                    Some(superclass) => self.node(
+                        role,
+                        format_args!("Class {} < {}", class.name.name, superclass.name),
+                        span,
+                    )?,
+                    None => self.node(role, format_args!("Class {}", class.name.name), span)?,
+                }
+                self.children(|p| {
+                    class
+                        .methods
+                        .iter()
+                        .try_for_each(|method| p.function(None, "Method", method))
+                })
+            }
+            StmtKind::Return(value) => {
+                self.node(role, format_args!("Return"), span)?;
+                self.children(|p| value.iter().try_for_each(|e| p.expr(None, e)))
+            }
+        }
+    }
+
+    fn function(
+        &mut self,
+        role: Option<&str>,
+        kind: &str,
+        function: &Function,
+    ) -> std::fmt::Result {
+        let params: Vec<&str> = function.params.iter().map(|p| p.name.as_str()).collect();
+        let params = params.join(", ");
+        let text = format_args!("{} {}({})", kind, function.name.name, params);
+        self.node(role, text, function.span)?;
+        self.children(|p| function.body.iter().try_for_each(|s| p.stmt(None, s)))
+    }
+
+    fn expr(&mut self, role: Option<&str>, expr: &Expr) -> std::fmt::Result {
+        // infix expressions nest to the left, so a long chain such as `1 + 2 + 3` is printed
+        // down to its innermost operand and then back out rather than recursively
+        let depth = self.depth;
+        let mut chain = vec![];
+        let mut role = role;
+        let mut innermost = expr;
+        loop {
+            self.depth = depth + chain.len();
+            self.label(role, innermost)?;
+            let Some((left_role, left)) = left_operand(innermost) else {
+                break;
+            };
+            chain.push(innermost);
+            role = left_role;
+            innermost = left;
+        }
+
+        self.children(|p| p.operands(innermost))?;
+        while let Some(outer) = chain.pop() {
+            self.depth = depth + chain.len();
+            self.children(|p| p.operands(outer))?;
+        }
+        Ok(())
+    }
+
+    /// Prints the line for an expression itself, without its operands
+    fn label(&mut self, role: Option<&str>, expr: &Expr) -> std::fmt::Result {
+        let span = expr.span;
+        match &expr.kind {
+            ExprKind::Literal(literal) => {
+                self.node(role, format_args!("Literal {}", literal), span)
+            }
+            ExprKind::Grouping(_) => self.node(role, format_args!("Grouping"), span),
+            ExprKind::Variable(name) => {
+                self.node(role, format_args!("Variable {}", name.name), span)
+            }
+            ExprKind::Assign { name, .. } => {
+                self.node(role, format_args!("Assign {}", name.name), span)
+            }
+            ExprKind::Unary { operator, .. } => {
+                self.node(role, format_args!("Unary {}", operator.kind), span)
+            }
+            ExprKind::Binary { operator, .. } => {
+                self.node(role, format_args!("Binary {}", operator.kind), span)
+            }
+            ExprKind::Logical { operator, .. } => {
+                self.node(role, format_args!("Logical {}", operator.kind), span)
+            }
+            ExprKind::Call { .. } => self.node(role, format_args!("Call"), span),
+            ExprKind::Get { name, .. } => self.node(role, format_args!("Get {}", name.name), span),
+            ExprKind::Set { name, .. } => self.node(role, format_args!("Set {}", name.name), span),
+            ExprKind::This => self.node(role, format_args!("This"), span),
+            ExprKind::Super { method } => {
+                self.node(role, format_args!("Super {}", method.name), span)
+            }
+        }
+    }
+
+    /// Prints the operands of an expression other than its left one
+    fn operands(&mut self, expr: &Expr) -> std::fmt::Result {
+        match &expr.kind {
+            ExprKind::Grouping(operand)
+            | ExprKind::Assign { value: operand, .. }
+            | ExprKind::Unary { operand, .. }
+            | ExprKind::Binary { right: operand, .. }
+            | ExprKind::Logical { right: operand, .. } => self.expr(None, operand),
+            ExprKind::Call { arguments, .. } => {
+                arguments.iter().try_for_each(|e| self.expr(None, e))
+            }
+            ExprKind::Set { value, .. } => self.expr(Some("value"), value),
+            ExprKind::Literal(_)
+            | ExprKind::Variable(_)
+            | ExprKind::Get { .. }
+            | ExprKind::This
+            | ExprKind::Super { .. } => Ok(()),
+        }
+    }
+}
+
+/// The operand printed first among an infix expression's children, with its role
+fn left_operand(expr: &Expr) -> Option<(Option<&'static str>, &Expr)> {
+    match &expr.kind {
+        ExprKind::Binary { left, .. } | ExprKind::Logical { left, .. } => Some((None, left)),
+        ExprKind::Get { object, .. } => Some((None, object)),
+        ExprKind::Set { object, .. } => Some((Some("object"), object)),
+        ExprKind::Call { callee, .. } => Some((Some("callee"), callee)),
+        _ => None,
+    }
+}
@@ -0,0 +1,353 @@
+//! The `.lxc` file format for compiled scripts
+//!
+//! Integers are little-endian, and strings are a `u32` byte length followed by UTF-8. A file is:
+//!
+//! - the magic bytes `MAGIC`, then the `u16` format and opcode versions
+//! - a `u32` count of global variable names, then the names in slot order
+//! - the top-level script, as a function
+//!
+//! A function is its name (a `0` byte for the script, or a `1` byte and the name), its `u32`
+//! arity and upvalue count, then its chunk:
+//!
+//! - a `u32` length, then the code
+//! - a `u32` count of constants, then each one as a tag byte and its payload
+//! - a `u32` count of line changes, then each as a `u32` offset and line
+//!
+//! Global slots in the code are numbered as in the file's own name table, and are renumbered to
+//! the loading `Vm`'s slots as the file is read.
+
+use crate::chunk::Chunk;
+use crate::chunk::Operand;
+use crate::chunk::OPCODE_VERSION;
+use crate::debug::LineMap;
+use crate::heap::Heap;
+use crate::heap::ObjRef;
+use crate::table::Globals;
+use crate::value::Function;
+use crate::value::Value;
+use crate::verify;
+
+pub const MAGIC: &[u8; 4] = b"LXC\0";
+
+/// The version of the layout described above, separate from the opcode numbering
+pub const FORMAT_VERSION: u16 = 1;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+/// Why a compiled file couldn't be loaded
+#[derive(Debug)]
+pub struct LoadError {
+    /// The byte offset in the file where the problem was found
+    pub offset: usize,
+    pub message: String,
+}
+
+impl std::error::Error for LoadError {}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} (at byte {})", self.message, self.offset)
+    }
+}
+
+/// Whether the bytes look like a compiled file rather than source code
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Serializes a script function, whose global slots are numbered by `globals`
+pub fn write(function: &Function, globals: &Globals, heap: &Heap) -> Vec<u8> {
+    let mut writer = Writer { bytes: vec![] };
+
+    writer.bytes.extend_from_slice(MAGIC);
+    writer.u16(FORMAT_VERSION);
+    writer.u16(OPCODE_VERSION);
+
+    writer.len(globals.len());
+    for (name, _) in globals.iter() {
+        writer.string(heap.resolve(name).unwrap());
+    }
+
+    writer.function(function);
+    writer.bytes
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u16(&mut self, n: u16) {
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn len(&mut self, len: usize) {
+        self.u32(len.try_into().expect("too large to serialize"));
+    }
+
+    fn string(&mut self, string: &str) {
+        self.len(string.len());
+        self.bytes.extend_from_slice(string.as_bytes());
+    }
+
+    fn function(&mut self, function: &Function) {
+        match &function.name {
+            Some(name) => {
+                self.bytes.push(1);
+                self.string(name);
+            }
+            None => self.bytes.push(0),
+        }
+        self.len(function.arity);
+        self.len(function.upvalue_count);
+
+        let chunk = &function.chunk;
+
+        self.len(chunk.len());
+        self.bytes.extend_from_slice(chunk.code());
+
+        self.len(chunk.constants().len());
+        for constant in chunk.constants() {
+            self.constant(constant);
+        }
+
+        let lines = chunk.lines().pairs();
+        self.len(lines.len());
+        for (offset, line) in lines {
+            self.len(*offset);
+            self.len(*line);
+        }
+    }
+
+    fn constant(&mut self, constant: &Value) {
+        if constant.is_nil() {
+            self.bytes.push(TAG_NIL);
+        } else if let Some(b) = constant.as_bool() {
+            self.bytes.push(if b { TAG_TRUE } else { TAG_FALSE });
+        } else if let Some(n) = constant.as_number() {
+            self.bytes.push(TAG_NUMBER);
+            self.bytes.extend_from_slice(&n.to_le_bytes());
+        } else if let Some(s) = constant.as_string() {
+            self.bytes.push(TAG_STRING);
+            self.string(s);
+        } else if let Some(function) = constant.as_function() {
+            self.bytes.push(TAG_FUNCTION);
+            self.function(function);
+        } else {
+            panic!("cannot serialize constant {:?}", constant);
+        }
+    }
+}
+
+/// Loads a script function, allocating its constants on `heap` and giving its global variables
+/// slots in `globals`
+///
+/// Like the compiler, this never collects garbage.
+pub(crate) fn read(
+    bytes: &[u8],
+    heap: &mut Heap,
+    globals: &mut Globals,
+) -> Result<ObjRef, LoadError> {
+    let mut reader = Reader {
+        bytes,
+        offset: 0,
+        heap,
+        global_slots: vec![],
+        depth: 0,
+    };
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(reader.error_at(0, "not a compiled Lox file"));
+    }
+    if reader.u16()? != FORMAT_VERSION {
+        return Err(reader.error_at(4, "unsupported format version"));
+    }
+    if reader.u16()? != OPCODE_VERSION {
+        return Err(reader.error_at(6, "compiled for a different set of opcodes"));
+    }
+
+    let global_count = reader.len()?;
+    for _ in 0..global_count {
+        let name = reader.string()?;
+        let name = reader.heap.intern_symbol(&name);
+        reader.global_slots.push(globals.resolve(name));
+    }
+
+    let function = reader.function()?;
+    if reader.offset != bytes.len() {
+        return Err(reader.error("trailing bytes after the script"));
+    }
+
+    Ok(reader.heap.alloc(function))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    heap: &'a mut Heap,
+    /// The loading `Vm`'s slot for each of the file's global slots
+    global_slots: Vec<usize>,
+    /// How many functions enclose the one being read
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, message: impl Into<String>) -> LoadError {
+        self.error_at(self.offset, message)
+    }
+
+    fn error_at(&self, offset: usize, message: impl Into<String>) -> LoadError {
+        LoadError {
+            offset,
+            message: message.into(),
+        }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], LoadError> {
+        let bytes: &'a [u8] = self.bytes;
+        match bytes.get(self.offset..).and_then(|rest| rest.get(..count)) {
+            Some(taken) => {
+                self.offset += count;
+                Ok(taken)
+            }
+            None => Err(self.error("unexpected end of file")),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, LoadError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn len(&mut self) -> Result<usize, LoadError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn string(&mut self) -> Result<String, LoadError> {
+        let len = self.len()?;
+        let start = self.offset;
+        let bytes = self.take(len)?;
+        match std::str::from_utf8(bytes) {
+            Ok(string) => Ok(string.to_string()),
+            Err(_) => Err(self.error_at(start, "string is not valid UTF-8")),
+        }
+    }
+
+    fn function(&mut self) -> Result<Function, LoadError> {
+        // functions are read recursively, so a file nesting them deeply could overflow the stack
+        if self.depth == verify::NESTING_MAX {
+            return Err(self.error("functions are nested too deeply"));
+        }
+        self.depth += 1;
+        let function = self.function_body();
+        self.depth -= 1;
+        function
+    }
+
+    fn function_body(&mut self) -> Result<Function, LoadError> {
+        let name = match self.u8()? {
+            0 => None,
+            1 => Some(self.string()?),
+            _ => return Err(self.error_at(self.offset - 1, "invalid function name tag")),
+        };
+        let arity = self.len()?;
+        let upvalue_count = self.len()?;
+
+        let code_len = self.len()?;
+        let code_start = self.offset;
+        let code = self.take(code_len)?.to_vec();
+
+        let constant_count = self.len()?;
+        let mut constants = Vec::with_capacity(constant_count.min(self.bytes.len()));
+        for _ in 0..constant_count {
+            constants.push(self.constant()?);
+        }
+
+        let line_count = self.len()?;
+        let mut lines = LineMap::default();
+        let mut last_offset = None;
+        for _ in 0..line_count {
+            let offset = self.len()?;
+            let line = self.len()?;
+            if last_offset.is_some_and(|last| offset <= last) {
+                return Err(self.error("line offsets are out of order"));
+            }
+            last_offset = Some(offset);
+            lines.add(offset, line);
+        }
+
+        let mut function = Function {
+            arity,
+            upvalue_count,
+            chunk: Chunk::from_parts(code, constants, lines),
+            name,
+        };
+        self.renumber_globals(&mut function, code_start)?;
+        Ok(function)
+    }
+
+    fn constant(&mut self) -> Result<Value, LoadError> {
+        let tag_offset = self.offset;
+        match self.u8()? {
+            TAG_NIL => Ok(Value::NIL),
+            TAG_FALSE => Ok(false.into()),
+            TAG_TRUE => Ok(true.into()),
+            TAG_NUMBER => Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()).into()),
+            TAG_STRING => {
+                let string = self.string()?;
+                Ok(self.heap.intern(&string).into())
+            }
+            TAG_FUNCTION => {
+                let function = self.function()?;
+                Ok(self.heap.alloc(function).into())
+            }
+            tag => Err(self.error_at(tag_offset, format!("invalid constant tag {}", tag))),
+        }
+    }
+
+    /// Renumbers the global slots in a function's code to the loading `Vm`'s
+    ///
+    /// The code is decoded as the verifier does, which checks that every instruction is complete
+    /// and its operands are in range. The rest of the verifier's checks are left to the `Vm`.
+    fn renumber_globals(
+        &self,
+        function: &mut Function,
+        code_start: usize,
+    ) -> Result<(), LoadError> {
+        let instructions = verify::decode(function, self.global_slots.len())
+            .map_err(|e| self.error_at(code_start + e.offset, e.message))?;
+
+        for instruction in instructions {
+            let Some(&operand) = instruction.opcode.operands().first() else {
+                continue;
+            };
+            if !matches!(operand, Operand::Global | Operand::LongGlobal) {
+                continue;
+            }
+
+            let slot = self.global_slots[instruction.operands[0]];
+            if slot >= 1 << (8 * operand.size()) {
+                let message = format!("too many globals to load slot {}", slot);
+                return Err(self.error_at(code_start + instruction.offset, message));
+            }
+            let bytes = (slot as u32).to_be_bytes();
+            for (i, byte) in bytes[4 - operand.size()..].iter().enumerate() {
+                function.chunk.patch(instruction.offset + 1 + i, *byte);
+            }
+        }
+
+        Ok(())
+    }
+}
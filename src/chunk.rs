@@ -3,8 +3,16 @@ use crate::value::Value;
 
 use derive_try_from_primitive::TryFromPrimitive;
 
+/// Identifies the numbering of `OpCode`, so compiled files from other versions are rejected
+///
+/// Bump this whenever opcodes are added, removed, reordered or change their operands.
+pub const OPCODE_VERSION: u16 = 2;
+
+/// How many constants or global slots the long forms of instructions can index
+pub const CONSTANTS_MAX: usize = 1 << 24;
+
 #[repr(u8)]
-#[derive(TryFromPrimitive)]
+#[derive(TryFromPrimitive, Clone, Copy, PartialEq, Eq, Debug)]
 pub enum OpCode {
     Constant,
     LongConstant,
@@ -12,6 +20,16 @@ pub enum OpCode {
     True,
     False,
     Pop,
+    GetLocal,
+    SetLocal,
+    GetUpvalue,
+    SetUpvalue,
+    GetProperty,
+    GetLongProperty,
+    SetProperty,
+    SetLongProperty,
+    GetSuper,
+    GetLongSuper,
     GetGlobal,
     GetLongGlobal,
     DefineGlobal,
@@ -29,7 +47,81 @@ pub enum OpCode {
     Not,
     Negate,
     Print,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    Closure,
+    LongClosure,
+    CloseUpvalue,
     Return,
+    Invoke,
+    LongInvoke,
+    SuperInvoke,
+    LongSuperInvoke,
+    Class,
+    LongClass,
+    Inherit,
+    Method,
+    LongMethod,
+    NotEqual,
+    GreaterEqual,
+    LessEqual,
+}
+
+/// What an operand following an opcode refers to
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Operand {
+    /// A local slot, upvalue index or argument count
+    Byte,
+    /// An index into the constant pool
+    Constant,
+    LongConstant,
+    /// A global variable slot
+    Global,
+    LongGlobal,
+    /// The distance of a forward jump from the end of the instruction
+    Jump,
+    /// The distance of a backward jump from the end of the instruction
+    Loop,
+}
+
+impl Operand {
+    /// The number of bytes the operand takes up
+    pub fn size(self) -> usize {
+        match self {
+            Operand::Byte | Operand::Constant | Operand::Global => 1,
+            Operand::Jump | Operand::Loop => 2,
+            Operand::LongConstant | Operand::LongGlobal => 3,
+        }
+    }
+}
+
+impl OpCode {
+    /// The operands that follow the opcode, in order
+    ///
+    /// `Closure` and `LongClosure` are also followed by two bytes for each upvalue of the
+    /// function they name: whether it captures a local, and the local's slot or upvalue index.
+    pub fn operands(self) -> &'static [Operand] {
+        use OpCode::*;
+        match self {
+            Nil | True | False | Pop | Equal | NotEqual | Greater | GreaterEqual | Less
+            | LessEqual | Add | Subtract | Multiply | Divide | Remainder | Not | Negate | Print
+            | CloseUpvalue | Return | Inherit => &[],
+            GetLocal | SetLocal | GetUpvalue | SetUpvalue | Call => &[Operand::Byte],
+            Constant | GetProperty | SetProperty | GetSuper | Closure | Class | Method => {
+                &[Operand::Constant]
+            }
+            LongConstant | GetLongProperty | SetLongProperty | GetLongSuper | LongClosure
+            | LongClass | LongMethod => &[Operand::LongConstant],
+            GetGlobal | DefineGlobal | SetGlobal => &[Operand::Global],
+            GetLongGlobal | DefineLongGlobal | SetLongGlobal => &[Operand::LongGlobal],
+            Jump | JumpIfFalse => &[Operand::Jump],
+            Loop => &[Operand::Loop],
+            Invoke | SuperInvoke => &[Operand::Constant, Operand::Byte],
+            LongInvoke | LongSuperInvoke => &[Operand::LongConstant, Operand::Byte],
+        }
+    }
 }
 
 #[derive(Default)]
@@ -40,16 +132,51 @@ pub struct Chunk {
 }
 
 impl Chunk {
+    /// Puts together a chunk from parts that have already been checked
+    pub(crate) fn from_parts(code: Vec<u8>, constants: Vec<Value>, lines: LineMap) -> Chunk {
+        Chunk {
+            code,
+            constants,
+            lines,
+        }
+    }
+
     pub fn read(&self, index: usize) -> u8 {
         self.code[index]
     }
 
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    pub fn lines(&self) -> &LineMap {
+        &self.lines
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    /// Overwrites an already written byte, used for backpatching jumps
+    pub fn patch(&mut self, index: usize, byte: u8) {
+        self.code[index] = byte;
+    }
+
     pub fn write(&mut self, byte: u8, line: usize) {
         let offset = self.code.len();
         self.code.push(byte);
         self.lines.add(offset, line);
     }
 
+    /// Adds a constant and writes the instruction that loads it
+    ///
+    /// # Panics
+    ///
+    /// If the chunk already holds `CONSTANTS_MAX` constants.
     pub fn write_constant(&mut self, constant: Value, line: usize) {
         let id = self.add_constant(constant);
         match id {
@@ -67,7 +194,7 @@ impl Chunk {
                     self.write(b, line);
                 }
             }
-            _ => panic!("reached constant limit fo 2^24"),
+            _ => panic!("reached constant limit of 2^24"),
         }
     }
 
@@ -79,13 +206,93 @@ impl Chunk {
     pub fn get_constant(&self, id: usize) -> &Value {
         &self.constants[id]
     }
+
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
+    }
+
+    /// The approximate number of bytes used by the chunk's code and constants
+    pub fn size(&self) -> usize {
+        self.code.len() + self.constants.len() * std::mem::size_of::<Value>()
+    }
 }
 
 pub fn constant_is_long(id: usize) -> bool {
     match id {
         0..=0xff => false,
         0x100..=0xffffff => true,
-        _ => panic!("reached constant limit fo 2^24"),
+        _ => panic!("reached constant limit of 2^24"),
+    }
+}
+
+/// Looks up the name of the global variable in a slot, for disassembly
+pub type GlobalNames<'a> = dyn Fn(usize) -> Option<&'a str> + 'a;
+
+impl OpCode {
+    /// The mnemonic the disassembler prints and the assembler reads
+    pub fn name(self) -> &'static str {
+        use OpCode::*;
+        match self {
+            Constant => "OP_CONSTANT",
+            LongConstant => "OP_CONSTANT_LONG",
+            Nil => "OP_NIL",
+            True => "OP_TRUE",
+            False => "OP_FALSE",
+            Pop => "OP_POP",
+            GetLocal => "OP_GET_LOCAL",
+            SetLocal => "OP_SET_LOCAL",
+            GetUpvalue => "OP_GET_UPVALUE",
+            SetUpvalue => "OP_SET_UPVALUE",
+            GetProperty => "OP_GET_PROPERTY",
+            GetLongProperty => "OP_GET_LONG_PROPERTY",
+            SetProperty => "OP_SET_PROPERTY",
+            SetLongProperty => "OP_SET_LONG_PROPERTY",
+            GetSuper => "OP_GET_SUPER",
+            GetLongSuper => "OP_GET_LONG_SUPER",
+            GetGlobal => "OP_GET_GLOBAL",
+            GetLongGlobal => "OP_GET_LONG_GLOBAL",
+            DefineGlobal => "OP_DEFINE_GLOBAL",
+            DefineLongGlobal => "OP_DEFINE_LONG_GLOBAL",
+            SetGlobal => "OP_SET_GLOBAL",
+            SetLongGlobal => "OP_SET_LONG_GLOBAL",
+            Equal => "OP_EQUAL",
+            Greater => "OP_GREATER",
+            Less => "OP_LESS",
+            Add => "OP_ADD",
+            Subtract => "OP_SUBTRACT",
+            Multiply => "OP_MULTIPLY",
+            Divide => "OP_DIVIDE",
+            Remainder => "OP_REMAINDER",
+            Not => "OP_NOT",
+            Negate => "OP_NEGATE",
+            Print => "OP_PRINT",
+            Jump => "OP_JUMP",
+            JumpIfFalse => "OP_JUMP_IF_FALSE",
+            Loop => "OP_LOOP",
+            Call => "OP_CALL",
+            Closure => "OP_CLOSURE",
+            LongClosure => "OP_CLOSURE_LONG",
+            CloseUpvalue => "OP_CLOSE_UPVALUE",
+            Return => "OP_RETURN",
+            Invoke => "OP_INVOKE",
+            LongInvoke => "OP_INVOKE_LONG",
+            SuperInvoke => "OP_SUPER_INVOKE",
+            LongSuperInvoke => "OP_SUPER_INVOKE_LONG",
+            Class => "OP_CLASS",
+            LongClass => "OP_CLASS_LONG",
+            Inherit => "OP_INHERIT",
+            Method => "OP_METHOD",
+            LongMethod => "OP_METHOD_LONG",
+            NotEqual => "OP_NOT_EQUAL",
+            GreaterEqual => "OP_GREATER_EQUAL",
+            LessEqual => "OP_LESS_EQUAL",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<OpCode> {
+        (0..=u8::MAX)
+            .filter_map(|byte| OpCode::try_from(byte).ok())
+            .find(|opcode| opcode.name() == name)
     }
 }
 
@@ -94,79 +301,191 @@ impl Chunk {
         self.lines.get_line(offset)
     }
 
-    pub fn disassemble(&self, name: &str) {
-        println!("=== {} ===", name);
+    pub fn write_disassembly(
+        &self,
+        f: &mut dyn std::fmt::Write,
+        name: &str,
+        globals: &GlobalNames,
+    ) -> std::fmt::Result {
+        writeln!(f, "=== {} ===", name)?;
 
         let mut offset = 0;
         while offset < self.code.len() {
-            offset = self.disassemble_instruction(offset);
+            offset = self.write_instruction(f, offset, globals)?;
         }
+
+        Ok(())
     }
 
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
-        print!("{:04} ", offset);
+    /// Writes one line per instruction, plus one per upvalue a closure captures, and returns
+    /// the offset of the next instruction
+    pub fn write_instruction(
+        &self,
+        f: &mut dyn std::fmt::Write,
+        offset: usize,
+        globals: &GlobalNames,
+    ) -> Result<usize, std::fmt::Error> {
+        write!(f, "{:04} ", offset)?;
 
         let line = self.get_line(offset);
 
         if offset > 0 && self.get_line(offset - 1) == line {
-            print!("   | ");
+            write!(f, "   | ")?;
         } else {
-            print!("{:04} ", line);
-        }
-
-        fn simple_instruction(name: &str, offset: usize) -> usize {
-            println!("{}", name);
-            offset + 1
+            write!(f, "{:04} ", line)?;
         }
 
         let instruction = self.code[offset];
+        let Ok(opcode) = OpCode::try_from(instruction) else {
+            writeln!(f, "Unknown opcode {}", instruction)?;
+            return Ok(offset + 1);
+        };
+
         use OpCode::*;
-        match instruction.try_into() {
-            Ok(i) => match i {
-                Constant => self.constant_instruction("OP_CONSTANT", offset),
-                LongConstant => self.long_constant_instruction("OP_CONSTANT_LONG", offset),
-                Nil => simple_instruction("OP_NIL", offset),
-                True => simple_instruction("OP_TRUE", offset),
-                False => simple_instruction("OP_FALSE", offset),
-                Pop => simple_instruction("OP_POP", offset),
-                GetGlobal => self.constant_instruction("OP_GET_GLOBAL", offset),
-                GetLongGlobal => self.long_constant_instruction("OP_GET_LONG_GLOBAL", offset),
-                DefineGlobal => self.constant_instruction("OP_DEFINE_GLOBAL", offset),
-                DefineLongGlobal => self.long_constant_instruction("OP_DEFINE_LONG_GLOBAL", offset),
-                SetGlobal => self.constant_instruction("OP_SET_GLOBAL", offset),
-                SetLongGlobal => self.long_constant_instruction("OP_SET_LONG_GLOBAL", offset),
-                Equal => simple_instruction("OP_EQUAL", offset),
-                Greater => simple_instruction("OP_GREATER", offset),
-                Less => simple_instruction("OP_LESS", offset),
-                Add => simple_instruction("OP_ADD", offset),
-                Subtract => simple_instruction("OP_SUBTRACT", offset),
-                Multiply => simple_instruction("OP_MULTIPLY", offset),
-                Divide => simple_instruction("OP_DIVIDE", offset),
-                Remainder => simple_instruction("OP_REMAINDER", offset),
-                Not => simple_instruction("OP_NOT", offset),
-                Negate => simple_instruction("OP_NEGATE", offset),
-                Print => simple_instruction("OP_PRINT", offset),
-                Return => simple_instruction("OP_RETURN", offset),
-            },
-            _ => {
-                println!("Unknown opcode {}", instruction);
-                offset + 1
+        let name = opcode.name();
+        match opcode {
+            Constant | GetProperty | SetProperty | GetSuper | Class | Method => {
+                self.constant_instruction(f, name, 1, offset)
+            }
+            LongConstant | GetLongProperty | SetLongProperty | GetLongSuper | LongClass
+            | LongMethod => self.constant_instruction(f, name, 3, offset),
+            GetLocal | SetLocal | GetUpvalue | SetUpvalue | Call => {
+                let slot = self.code[offset + 1];
+                writeln!(f, "{:16} {}", name, slot)?;
+                Ok(offset + 2)
+            }
+            GetGlobal | DefineGlobal | SetGlobal => {
+                self.global_instruction(f, name, 1, offset, globals)
+            }
+            GetLongGlobal | DefineLongGlobal | SetLongGlobal => {
+                self.global_instruction(f, name, 3, offset, globals)
+            }
+            Jump | JumpIfFalse => self.jump_instruction(f, name, true, offset),
+            Loop => self.jump_instruction(f, name, false, offset),
+            Closure => self.closure_instruction(f, name, 1, offset),
+            LongClosure => self.closure_instruction(f, name, 3, offset),
+            Invoke | SuperInvoke => self.invoke_instruction(f, name, 1, offset),
+            LongInvoke | LongSuperInvoke => self.invoke_instruction(f, name, 3, offset),
+            Nil | True | False | Pop | Equal | NotEqual | Greater | GreaterEqual | Less
+            | LessEqual | Add | Subtract | Multiply | Divide | Remainder | Not | Negate | Print
+            | CloseUpvalue | Return | Inherit => {
+                writeln!(f, "{}", name)?;
+                Ok(offset + 1)
             }
         }
     }
 
-    fn constant_instruction(&self, name: &str, offset: usize) -> usize {
-        let constant = self.code[offset + 1];
-        let value = &self.constants[constant as usize];
-        println!("{:16} {} {}", name, constant, value);
-        offset + 2
+    fn read_operand(&self, offset: usize, size: usize) -> usize {
+        self.code[offset..offset + size]
+            .iter()
+            .fold(0, |int, byte| (int << 8) | *byte as usize)
     }
 
-    fn long_constant_instruction(&self, name: &str, offset: usize) -> usize {
-        let constant_bytes = &self.code[offset..(offset + 4)];
-        let constant = u32::from_be_bytes(constant_bytes.try_into().unwrap()) & 0xffffff;
-        let value = &self.constants[constant as usize];
-        println!("{:16} {} {}", name, constant, value);
-        offset + 4
+    fn global_instruction(
+        &self,
+        f: &mut dyn std::fmt::Write,
+        name: &str,
+        slot_size: usize,
+        offset: usize,
+        globals: &GlobalNames,
+    ) -> Result<usize, std::fmt::Error> {
+        let slot = self.read_operand(offset + 1, slot_size);
+        match globals(slot) {
+            Some(global) => writeln!(f, "{:16} {} {}", name, slot, global)?,
+            None => writeln!(f, "{:16} {}", name, slot)?,
+        }
+        Ok(offset + 1 + slot_size)
+    }
+
+    fn jump_instruction(
+        &self,
+        f: &mut dyn std::fmt::Write,
+        name: &str,
+        forward: bool,
+        offset: usize,
+    ) -> Result<usize, std::fmt::Error> {
+        let jump = self.read_operand(offset + 1, 2);
+        let next = offset + 3;
+        let target = if forward { next + jump } else { next - jump };
+        writeln!(f, "{:16} {:04} -> {:04}", name, offset, target)?;
+        Ok(next)
+    }
+
+    fn closure_instruction(
+        &self,
+        f: &mut dyn std::fmt::Write,
+        name: &str,
+        constant_size: usize,
+        offset: usize,
+    ) -> Result<usize, std::fmt::Error> {
+        let constant = self.read_operand(offset + 1, constant_size);
+        let value = &self.constants[constant];
+        writeln!(f, "{:16} {} {}", name, constant, Listed(value))?;
+
+        let mut offset = offset + 1 + constant_size;
+
+        let upvalue_count = value
+            .as_function()
+            .map_or(0, |function| function.upvalue_count);
+        for _ in 0..upvalue_count {
+            let is_local = self.code[offset];
+            let index = self.code[offset + 1];
+            writeln!(
+                f,
+                "{:04}    |                     {} {}",
+                offset,
+                if is_local == 1 { "local" } else { "upvalue" },
+                index
+            )?;
+            offset += 2;
+        }
+
+        Ok(offset)
+    }
+
+    fn invoke_instruction(
+        &self,
+        f: &mut dyn std::fmt::Write,
+        name: &str,
+        constant_size: usize,
+        offset: usize,
+    ) -> Result<usize, std::fmt::Error> {
+        let constant = self.read_operand(offset + 1, constant_size);
+        let arg_count = self.code[offset + 1 + constant_size];
+        let value = &self.constants[constant];
+        writeln!(
+            f,
+            "{:16} ({} args) {} {}",
+            name,
+            arg_count,
+            constant,
+            Listed(value)
+        )?;
+        Ok(offset + 2 + constant_size)
+    }
+
+    fn constant_instruction(
+        &self,
+        f: &mut dyn std::fmt::Write,
+        name: &str,
+        constant_size: usize,
+        offset: usize,
+    ) -> Result<usize, std::fmt::Error> {
+        let constant = self.read_operand(offset + 1, constant_size);
+        let value = &self.constants[constant];
+        writeln!(f, "{:16} {} {}", name, constant, Listed(value))?;
+        Ok(offset + 1 + constant_size)
+    }
+}
+
+/// Formats a constant the way the assembler reads it back, with strings quoted
+struct Listed<'a>(&'a Value);
+
+impl std::fmt::Display for Listed<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.0.as_string() {
+            Some(s) => write!(f, "\"{}\"", s.escape_debug()),
+            None => write!(f, "{}", self.0),
+        }
     }
 }
@@ -1,146 +1,260 @@
+use crate::ast::BinaryOp;
+use crate::ast::Class;
+use crate::ast::Expr;
+use crate::ast::ExprKind;
+use crate::ast::Identifier;
+use crate::ast::Literal;
+use crate::ast::LogicalOp;
+use crate::ast::Stmt;
+use crate::ast::StmtKind;
+use crate::ast::UnaryOp;
 use crate::chunk::constant_is_long;
 use crate::chunk::Chunk;
 use crate::chunk::OpCode;
-use crate::scanner::Scanner;
-use crate::scanner::Token;
-use crate::scanner::TokenKind;
+use crate::chunk::CONSTANTS_MAX;
+use crate::diagnostic::Diagnostic;
+use crate::diagnostic::Span;
+use crate::heap::Heap;
+use crate::heap::ObjRef;
+use crate::optimize::fold_program;
+use crate::optimize::OptLevel;
+use crate::parser::parse_with_errors;
+use crate::table::Globals;
+use crate::table::Symbol;
+use crate::value::Function;
 use crate::value::Value;
 use crate::vm::InterpretError;
 
-use derive_try_from_primitive::TryFromPrimitive;
-
-struct Parser<'a> {
-    scanner: Scanner<'a>,
-    current: Option<Token<'a>>,
-    previous: Option<Token<'a>>,
-    had_error: bool,
+use std::collections::HashMap;
+
+/// Lowers a syntax tree to bytecode, finding the errors the parser can't, such as reading a
+/// local variable in its own initializer
+struct Generator<'a> {
+    source: &'a str,
+    /// The byte offset of every newline in the source, for finding the line code comes from
+    newlines: Vec<usize>,
+    /// The token the code being emitted comes from, which gives its line and is where errors in
+    /// it are reported
+    at: Span,
+    /// Every error found so far, in source order
+    diagnostics: Vec<Diagnostic>,
+    /// Whether the declaration being compiled already has an error, so others in it are dropped
     panic_mode: bool,
-    chunk: Chunk,
+    /// One compiler per function being compiled, innermost last
+    compilers: Vec<Compiler<'a>>,
+    /// One entry per class body enclosing the code being compiled, innermost last
+    classes: Vec<ClassCompiler>,
+    /// Where string and function constants are allocated
+    heap: &'a mut Heap,
+    globals: &'a mut Globals,
+    opt_level: OptLevel,
 }
 
-type ParseFn = fn(&mut Parser<'_>, bool) -> ();
+struct ClassCompiler {
+    has_superclass: bool,
+}
 
-/*
-struct Rule {
-    prefix: ParseFn,
-    infix: ParseFn,
-    precedence: Precedence,
+struct Compiler<'a> {
+    function: Function,
+    kind: FunctionKind,
+    locals: Vec<Local<'a>>,
+    upvalues: Vec<UpvalueRef>,
+    scope_depth: usize,
+    /// The index of each number and string constant in the chunk, for sharing equal ones
+    constants: HashMap<ConstantKey, usize>,
 }
-*/
-
-#[repr(u8)]
-#[derive(TryFromPrimitive, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-enum Precedence {
-    None,
-    Assignment, // =
-    Or,         // or
-    And,        // and
-    Equality,   // == !=
-    Comparison, // < > <= >=
-    Term,       // + -
-    Factor,     // * /
-    Unary,      // ! -
-    Call,       // . ()
-    Primary,
+
+/// A constant value that every instruction loading an equal one can share
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    /// The bits of a number, which keep `0` and `-0` apart
+    Number(u64),
+    String(Symbol),
 }
 
-impl<'a> Parser<'a> {
-    pub fn new(scanner: Scanner<'a>) -> Parser<'a> {
-        Parser {
-            scanner,
-            current: None,
-            previous: None,
-            had_error: false,
-            panic_mode: false,
-            chunk: Chunk::default(),
+impl ConstantKey {
+    fn new(value: &Value) -> Option<ConstantKey> {
+        if let Some(number) = value.as_number() {
+            return Some(ConstantKey::Number(number.to_bits()));
         }
+        value.as_symbol().map(ConstantKey::String)
     }
+}
 
-    fn advance(&mut self) {
-        self.previous = self.current.clone();
+/// Where a closure finds a captured variable when it is created
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct UpvalueRef {
+    /// The slot of a local in the enclosing function, or the index of one of its upvalues
+    index: u8,
+    is_local: bool,
+}
 
-        loop {
-            self.current = Some(self.scanner.scan_token());
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FunctionKind {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
 
-            match &self.current {
-                Some(t) => match t.kind {
-                    TokenKind::UnterminatedString => {
-                        self.report_error_at_current("unterminated string")
-                    }
-                    TokenKind::UnexpectedCharacter => {
-                        self.report_error_at_current("unexpected character")
-                    }
-                    _ => break,
+impl<'a> Compiler<'a> {
+    fn new(kind: FunctionKind, name: Option<String>) -> Compiler<'a> {
+        Compiler {
+            function: Function {
+                arity: 0,
+                upvalue_count: 0,
+                chunk: Chunk::default(),
+                name,
+            },
+            kind,
+            // slot zero holds the function being called, or the receiver in methods
+            locals: vec![Local {
+                name: match kind {
+                    FunctionKind::Function | FunctionKind::Script => "",
+                    FunctionKind::Initializer | FunctionKind::Method => "this",
                 },
-                None => todo!(),
-            }
+                span: None,
+                depth: Some(0),
+                is_captured: false,
+            }],
+            upvalues: vec![],
+            scope_depth: 0,
+            constants: HashMap::new(),
         }
     }
+}
 
-    // match() in book
-    fn check_advance(&mut self, kind: TokenKind) -> bool {
-        if !self.check(kind) {
-            false
-        } else {
-            self.advance();
-            true
-        }
+/// The maximum number of locals in scope at once, limited by the one-byte slot operand
+const LOCALS_MAX: usize = 256;
+
+/// The maximum number of variables a closure can capture, limited by the one-byte upvalue operands
+const UPVALUES_MAX: usize = 256;
+
+struct Local<'a> {
+    name: &'a str,
+    /// Where the variable is declared, `None` for the slot the compiler reserves
+    span: Option<Span>,
+    /// `None` while the variable's initializer is being compiled
+    depth: Option<usize>,
+    /// Whether a closure captures the variable, so it must be hoisted when it goes out of scope
+    is_captured: bool,
+}
+
+/// The last token of an expression, which code emitted after it is attributed to
+pub(crate) fn last_token(expr: &Expr) -> Span {
+    match &expr.kind {
+        ExprKind::Assign { value: last, .. }
+        | ExprKind::Unary { operand: last, .. }
+        | ExprKind::Binary { right: last, .. }
+        | ExprKind::Logical { right: last, .. }
+        | ExprKind::Set { value: last, .. } => last_token(last),
+        ExprKind::Get { name, .. } | ExprKind::Super { method: name } => name.span,
+        // closed by a `)`
+        ExprKind::Grouping(_) | ExprKind::Call { .. } => last_char(expr.span),
+        ExprKind::Literal(_) | ExprKind::Variable(_) | ExprKind::This => expr.span,
     }
+}
 
-    fn check(&mut self, kind: TokenKind) -> bool {
-        match &self.current {
-            Some(t) => t.kind == kind,
-            None => false,
-        }
+/// The operand compiled before the rest of an infix expression, if it has one
+///
+/// A method called straight away is invoked, so its receiver is compiled first, and a call to a
+/// `super` method has no left operand.
+fn left_operand(expr: &Expr) -> Option<&Expr> {
+    match &expr.kind {
+        ExprKind::Binary { left, .. } | ExprKind::Logical { left, .. } => Some(left),
+        ExprKind::Get { object, .. } | ExprKind::Set { object, .. } => Some(object),
+        ExprKind::Call { callee, .. } => match &callee.kind {
+            ExprKind::Get { object, .. } => Some(object),
+            ExprKind::Super { .. } => None,
+            _ => Some(callee),
+        },
+        _ => None,
     }
+}
 
-    // errorAtCurrent() in book
-    fn report_error_at_current(&mut self, message: &str) {
-        self.report_error_at(self.current.clone().unwrap(), message)
+/// The last character of a span, for nodes ending in a single-character token such as `;` or `}`
+fn last_char(span: Span) -> Span {
+    Span::new(span.end.saturating_sub(1).max(span.start), span.end)
+}
+
+impl<'a> Generator<'a> {
+    fn new(
+        source: &'a str,
+        heap: &'a mut Heap,
+        globals: &'a mut Globals,
+        opt_level: OptLevel,
+    ) -> Generator<'a> {
+        Generator {
+            source,
+            newlines: source
+                .match_indices('\n')
+                .map(|(offset, _)| offset)
+                .collect(),
+            at: Span::new(0, 0),
+            diagnostics: vec![],
+            panic_mode: false,
+            compilers: vec![Compiler::new(FunctionKind::Script, None)],
+            classes: vec![],
+            heap,
+            globals,
+            opt_level,
+        }
     }
 
-    // error() in book
-    fn report_error_at_previous(&mut self, message: &str) {
-        self.report_error_at(self.previous.clone().unwrap(), message)
+    fn compiler(&self) -> &Compiler<'a> {
+        self.compilers.last().unwrap()
     }
 
-    // errorAt() in book
-    fn report_error_at(&mut self, token: Token<'_>, message: &str) {
-        if self.panic_mode {
-            return;
-        }
+    fn compiler_mut(&mut self) -> &mut Compiler<'a> {
+        self.compilers.last_mut().unwrap()
+    }
 
-        self.panic_mode = true;
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.compiler_mut().function.chunk
+    }
 
-        eprint!("[line {}] Error", token.line);
+    /// The one-based line of a byte offset in the source
+    fn line_of(&self, offset: usize) -> usize {
+        self.newlines.partition_point(|newline| *newline < offset) + 1
+    }
 
-        match token.kind {
-            TokenKind::UnterminatedString | TokenKind::UnexpectedCharacter => print!(""),
-            TokenKind::Eof => eprint!(" at end"),
-            _ => eprint!(" at `{}`", token.span),
-        }
+    fn report_error_at_previous(&mut self, message: &str) {
+        let diagnostic = self.error_at(self.at, message);
+        self.report(diagnostic)
+    }
 
-        eprintln!(": {}", message);
-        self.had_error = true;
+    fn error_at(&self, span: Span, message: &str) -> Diagnostic {
+        let line = self.line_of(span.start);
+        let line_start = match line {
+            1 => 0,
+            _ => self.newlines[line - 2] + 1,
+        };
+        let column = self.source[line_start..span.start].chars().count() + 1;
+        Diagnostic::error(message, span, line, column)
     }
 
-    fn consume(&mut self, kind: TokenKind, message: &str) {
-        if self.current.as_ref().unwrap().kind == kind {
-            self.advance();
+    /// Records a diagnostic, unless the declaration being compiled already has one
+    fn report(&mut self, diagnostic: Diagnostic) {
+        if self.panic_mode {
             return;
         }
 
-        self.report_error_at_current(message);
+        self.panic_mode = true;
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn line(&self) -> usize {
+        self.line_of(self.at.start)
     }
 
     fn emit_byte(&mut self, byte: u8) {
-        self.chunk.write(byte, self.previous.as_ref().unwrap().line)
+        let line = self.line();
+        self.chunk().write(byte, line)
     }
 
     fn emit_bytes(&mut self, bytes: &[u8]) {
         for byte in bytes {
-            self.chunk
-                .write(*byte, self.previous.as_ref().unwrap().line)
+            self.emit_byte(*byte)
         }
     }
 
@@ -149,324 +263,822 @@ impl<'a> Parser<'a> {
 
         for i in 1..=size_in_bytes {
             let byte = bytes[size_in_bytes - i];
-            self.chunk.write(byte, self.previous.as_ref().unwrap().line)
+            self.emit_byte(byte)
         }
     }
 
-    fn emit_constant(&mut self, value: Value) {
-        self.chunk
-            .write_constant(value, self.previous.as_ref().unwrap().line);
+    /// Emits a jump with a placeholder operand, returning the operand's offset for patching
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        self.emit_byte(instruction as u8);
+        self.emit_bytes(&[0xff, 0xff]);
+        self.chunk().len() - 2
     }
-}
 
-// parsing rules
-impl Parser<'_> {
-    fn parse_precedence(&mut self, precedence: Precedence) {
-        self.advance();
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the jump offset itself
+        let jump = self.chunk().len() - offset - 2;
+
+        if jump > u16::MAX as usize {
+            self.report_error_at_previous("Too much code to jump over.");
+        }
 
-        let prefix_rule = get_rule_prefix(&self.previous.as_ref().unwrap().kind);
+        let [high, low] = (jump as u16).to_be_bytes();
+        self.chunk().patch(offset, high);
+        self.chunk().patch(offset + 1, low);
+    }
 
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OpCode::Loop as u8);
 
-        let can_assign = if let Some(rule) = prefix_rule {
-            let can_assign = precedence <= Precedence::Assignment;
-            rule(self, can_assign);
-            can_assign
+        // +2 to adjust for the loop operand
+        let offset = self.chunk().len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.report_error_at_previous("Loop body too large.");
+        }
+
+        self.emit_int(offset, 2);
+    }
+
+    /// Emits an instruction taking a constant index or global slot, using the long form if the
+    /// index needs it
+    fn emit_constant_op(&mut self, short: OpCode, long: OpCode, constant: usize) {
+        if constant_is_long(constant) {
+            self.emit_byte(long as u8);
+            self.emit_int(constant, 3);
         } else {
-            self.report_error_at_previous("Expect expression.");
-            return
-        };
+            self.emit_bytes(&[short as u8, constant as u8]);
+        }
+    }
+
+    fn emit_return(&mut self) {
+        if self.compiler().kind == FunctionKind::Initializer {
+            // initializers implicitly return the instance
+            self.emit_bytes(&[OpCode::GetLocal as u8, 0]);
+        } else {
+            self.emit_byte(OpCode::Nil as u8);
+        }
+
+        self.emit_byte(OpCode::Return as u8);
+    }
+
+    fn emit_constant(&mut self, value: Value) {
+        let constant = self.make_constant(value);
+        self.emit_constant_op(OpCode::Constant, OpCode::LongConstant, constant);
+    }
 
-        while precedence as u8 <= get_rule_precedence(&self.current.as_ref().unwrap().kind) as u8 {
-            self.advance();
-            let infix_rule = get_rule_infix(&self.previous.as_ref().unwrap().kind);
-            infix_rule.unwrap()(self, can_assign);
+    /// Adds a constant to the current chunk, reporting an error if it has run out of room
+    ///
+    /// When optimizing, a number or string equal to one already in the chunk reuses it.
+    fn make_constant(&mut self, value: Value) -> usize {
+        let key = ConstantKey::new(&value).filter(|_| self.opt_level >= OptLevel::O1);
+        if let Some(&constant) = key.and_then(|key| self.compiler().constants.get(&key)) {
+            return constant;
         }
 
-        if can_assign && self.check_advance(TokenKind::Equal) {
-            self.report_error_at_previous("Invalid assignment target.");
+        let constant = self.chunk().add_constant(value);
+        if constant >= CONSTANTS_MAX {
+            self.report_error_at_previous("Too many constants in one chunk.");
+            return 0;
         }
+        if let Some(key) = key {
+            self.compiler_mut().constants.insert(key, constant);
+        }
+        constant
     }
+}
+
+// variables and scopes
+impl<'a> Generator<'a> {
+    /// The global slot for a variable being declared, or zero if it's a local
+    fn variable_slot(&mut self, name: &str) -> usize {
+        if self.compiler().scope_depth > 0 {
+            return 0;
+        }
 
-    fn parse_variable(&mut self, error_message: &str) -> usize {
-        self.consume(TokenKind::Identifier, error_message);
-        let token = self.previous.clone().unwrap();
-        self.identifier_constant(token)
+        self.global_slot(name)
     }
 
-    fn define_variable(&mut self, global: usize) {
-        if constant_is_long(global) {
-            self.emit_byte(OpCode::DefineLongGlobal as u8);
-            self.emit_int(global, 3);
-        } else {
-            self.emit_bytes(&[OpCode::DefineGlobal as u8, global as u8]);
+    fn declare_variable(&mut self, name: &'a Identifier) {
+        let compiler = self.compiler();
+
+        if compiler.scope_depth == 0 {
+            return;
+        }
+
+        let mut previous_declaration = None;
+        for local in compiler.locals.iter().rev() {
+            if let Some(depth) = local.depth {
+                if depth < compiler.scope_depth {
+                    break;
+                }
+            }
+
+            if local.name == name.name {
+                previous_declaration = Some(local.span);
+                break;
+            }
+        }
+
+        if let Some(span) = previous_declaration {
+            let message = "Already a variable with this name in this scope.";
+            let mut diagnostic = self.error_at(name.span, message);
+            if let Some(span) = span {
+                diagnostic = diagnostic.with_secondary(span, "first declared here");
+            }
+            self.report(diagnostic);
         }
+
+        self.add_local(&name.name, Some(name.span));
     }
 
-    fn identifier_constant(&mut self, token: Token) -> usize {
-        self.chunk.add_constant(token.span.to_string().into())
+    fn add_local(&mut self, name: &'a str, span: Option<Span>) {
+        if self.compiler().locals.len() == LOCALS_MAX {
+            self.report_error_at_previous("Too many local variables in function.");
+            return;
+        }
+
+        self.compiler_mut().locals.push(Local {
+            name,
+            span,
+            depth: None,
+            is_captured: false,
+        });
     }
 
-    fn expression(&mut self) {
-        self.parse_precedence(Precedence::Assignment);
+    fn resolve_local(&mut self, compiler: usize, name: &str) -> Option<u8> {
+        let locals = &self.compilers[compiler].locals;
+        let index = locals.iter().rposition(|local| local.name == name)?;
+
+        if locals[index].depth.is_none() {
+            self.report_error_at_previous("Can't read local variable in its own initializer.");
+        }
+
+        Some(index as u8)
     }
 
-    fn declaration(&mut self) {
-        if self.check_advance(TokenKind::Var) {
-            self.variable_declaration();
-        } else {
-            self.statement();
+    fn resolve_upvalue(&mut self, compiler: usize, name: &str) -> Option<u8> {
+        if compiler == 0 {
+            return None;
         }
 
-        if self.panic_mode {
-            self.synchronize();
+        let enclosing = compiler - 1;
+
+        if let Some(local) = self.resolve_local(enclosing, name) {
+            self.compilers[enclosing].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(
+                compiler,
+                UpvalueRef {
+                    index: local,
+                    is_local: true,
+                },
+            ));
         }
+
+        let upvalue = self.resolve_upvalue(enclosing, name)?;
+        Some(self.add_upvalue(
+            compiler,
+            UpvalueRef {
+                index: upvalue,
+                is_local: false,
+            },
+        ))
     }
 
-    fn variable_declaration(&mut self) {
-        let global = self.parse_variable("Expect variable name.");
+    fn add_upvalue(&mut self, compiler: usize, upvalue: UpvalueRef) -> u8 {
+        let upvalues = &self.compilers[compiler].upvalues;
 
-        if self.check_advance(TokenKind::Equal) {
-            self.expression();
-        } else {
-            self.emit_byte(OpCode::Nil as u8);
+        if let Some(index) = upvalues.iter().position(|u| *u == upvalue) {
+            return index as u8;
         }
 
-        self.consume(TokenKind::Semicolon, "Expect `;`.");
+        if upvalues.len() == UPVALUES_MAX {
+            self.report_error_at_previous("Too many closure variables in function.");
+            return 0;
+        }
 
-        self.define_variable(global);
+        let compiler = &mut self.compilers[compiler];
+        compiler.upvalues.push(upvalue);
+        compiler.function.upvalue_count = compiler.upvalues.len();
+        (compiler.upvalues.len() - 1) as u8
     }
 
-    fn statement(&mut self) {
-        if self.check_advance(TokenKind::Print) {
-            self.print_statement();
-        } else {
-            self.expression_statement();
+    fn mark_initialized(&mut self) {
+        let compiler = self.compiler_mut();
+
+        if compiler.scope_depth == 0 {
+            return;
+        }
+
+        let depth = compiler.scope_depth;
+        if let Some(local) = compiler.locals.last_mut() {
+            local.depth = Some(depth);
         }
     }
 
-    fn expression_statement(&mut self) {
-        self.expression();
-        self.consume(TokenKind::Semicolon, "Expect `;`.");
-        self.emit_byte(OpCode::Pop as u8);
+    fn define_variable(&mut self, global: usize) {
+        if self.compiler().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_constant_op(OpCode::DefineGlobal, OpCode::DefineLongGlobal, global);
     }
 
-    fn print_statement(&mut self) {
-        self.expression();
-        self.consume(TokenKind::Semicolon, "Expect `;`.");
-        self.emit_byte(OpCode::Print as u8);
+    fn identifier_constant(&mut self, name: &str) -> usize {
+        let name = self.heap.intern(name);
+        self.make_constant(name.into())
     }
 
-    fn synchronize(&mut self) {
-        self.panic_mode = false;
+    fn global_slot(&mut self, name: &str) -> usize {
+        let name = self.heap.intern_symbol(name);
+        let slot = self.globals.resolve(name);
+        if slot >= CONSTANTS_MAX {
+            self.report_error_at_previous("Too many global variables.");
+            return 0;
+        }
+        slot
+    }
 
-        use TokenKind::*;
+    fn begin_scope(&mut self) {
+        self.compiler_mut().scope_depth += 1;
+    }
 
-        while let Some(t) = &self.current {
-            match t.kind {
-                Class | Fun | Var | For | If | While | Print | Return | Eof => break,
-                _ => self.advance(),
+    fn end_scope(&mut self) {
+        self.compiler_mut().scope_depth -= 1;
+
+        while let Some(local) = self.compiler().locals.last() {
+            match local.depth {
+                Some(depth) if depth <= self.compiler().scope_depth => break,
+                _ => {
+                    if local.is_captured {
+                        self.emit_byte(OpCode::CloseUpvalue as u8);
+                    } else {
+                        self.emit_byte(OpCode::Pop as u8);
+                    }
+                    self.compiler_mut().locals.pop();
+                }
             }
         }
     }
 
-    fn grouping(&mut self, _can_assign: bool) {
-        self.expression();
-        self.consume(TokenKind::RightParen, "Expect `)`.");
+    /// Emits code to read a variable, or to assign it `value`
+    fn named_variable(&mut self, name: &str, value: Option<&'a Expr>) {
+        let compiler = self.compilers.len() - 1;
+
+        let local_ops = if let Some(slot) = self.resolve_local(compiler, name) {
+            Some((OpCode::GetLocal, OpCode::SetLocal, slot))
+        } else {
+            self.resolve_upvalue(compiler, name)
+                .map(|index| (OpCode::GetUpvalue, OpCode::SetUpvalue, index))
+        };
+
+        if let Some((get_op, set_op, arg)) = local_ops {
+            if let Some(value) = value {
+                self.expression(value);
+                self.at = last_token(value);
+                self.emit_bytes(&[set_op as u8, arg]);
+            } else {
+                self.emit_bytes(&[get_op as u8, arg]);
+            }
+            return;
+        }
+
+        let global = self.global_slot(name);
+
+        if let Some(value) = value {
+            self.expression(value);
+            self.at = last_token(value);
+            self.emit_constant_op(OpCode::SetGlobal, OpCode::SetLongGlobal, global);
+        } else {
+            self.emit_constant_op(OpCode::GetGlobal, OpCode::GetLongGlobal, global);
+        }
     }
+}
 
-    fn number(&mut self, _can_assign: bool) {
-        let string = self.previous.as_ref().unwrap().span;
-        let number = string.parse::<f64>().unwrap();
-        self.emit_constant(number.into());
+// statements
+impl<'a> Generator<'a> {
+    /// Compiles a statement where declarations are allowed, which is where an error stops being
+    /// the reason for dropping others
+    fn declaration(&mut self, stmt: &'a Stmt) {
+        self.statement(stmt);
+        self.panic_mode = false;
     }
 
-    fn unary(&mut self, _can_assign: bool) {
-        let operator_kind = self.previous.as_ref().unwrap().kind;
+    fn statement(&mut self, stmt: &'a Stmt) {
+        match &stmt.kind {
+            StmtKind::Expression(expr) => self.discarded_expression(expr, last_char(stmt.span)),
+            StmtKind::Print(expr) => {
+                self.expression(expr);
+                self.at = last_char(stmt.span);
+                self.emit_byte(OpCode::Print as u8);
+            }
+            StmtKind::Var { name, initializer } => {
+                self.variable_declaration(name, initializer.as_ref(), stmt.span)
+            }
+            StmtKind::Block(statements) => {
+                self.begin_scope();
+                self.block(statements, stmt.span);
+                self.end_scope();
+            }
+            StmtKind::If {
+                condition,
+                right_paren,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(condition);
+                self.at = *right_paren;
+
+                let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_byte(OpCode::Pop as u8);
+                self.statement(then_branch);
+
+                let else_jump = self.emit_jump(OpCode::Jump);
+
+                self.patch_jump(then_jump);
+                self.emit_byte(OpCode::Pop as u8);
 
-        self.parse_precedence(Precedence::Assignment);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
 
-        match operator_kind {
-            TokenKind::Bang => self.emit_byte(OpCode::Not as u8),
-            TokenKind::Minus => self.emit_byte(OpCode::Negate as u8),
-            _ => unreachable!(),
+                self.patch_jump(else_jump);
+            }
+            StmtKind::While {
+                condition,
+                right_paren,
+                body,
+            } => {
+                let loop_start = self.chunk().len();
+
+                self.expression(condition);
+                self.at = *right_paren;
+
+                let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_byte(OpCode::Pop as u8);
+                self.statement(body);
+                self.emit_loop(loop_start);
+
+                self.patch_jump(exit_jump);
+                self.emit_byte(OpCode::Pop as u8);
+            }
+            StmtKind::For {
+                initializer,
+                condition,
+                semicolon,
+                increment,
+                right_paren,
+                body,
+            } => {
+                self.begin_scope();
+
+                if let Some(initializer) = initializer {
+                    self.statement(initializer);
+                }
+
+                let mut loop_start = self.chunk().len();
+
+                let exit_jump = condition.as_ref().map(|condition| {
+                    self.expression(condition);
+                    self.at = *semicolon;
+
+                    let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+                    self.emit_byte(OpCode::Pop as u8);
+                    exit_jump
+                });
+
+                if let Some(increment) = increment {
+                    // the increment is compiled before the body but runs after it
+                    self.at = *semicolon;
+                    let body_jump = self.emit_jump(OpCode::Jump);
+                    let increment_start = self.chunk().len();
+
+                    self.discarded_expression(increment, last_token(increment));
+
+                    self.at = *right_paren;
+                    self.emit_loop(loop_start);
+                    loop_start = increment_start;
+                    self.patch_jump(body_jump);
+                }
+
+                self.statement(body);
+                self.emit_loop(loop_start);
+
+                if let Some(exit_jump) = exit_jump {
+                    self.patch_jump(exit_jump);
+                    self.emit_byte(OpCode::Pop as u8);
+                }
+
+                self.end_scope();
+            }
+            StmtKind::Function(function) => {
+                self.at = function.name.span;
+                self.declare_variable(&function.name);
+                let global = self.variable_slot(&function.name.name);
+                // a function may refer to itself, so it is initialized before its body is compiled
+                self.mark_initialized();
+                self.function(function, FunctionKind::Function);
+                self.define_variable(global);
+            }
+            StmtKind::Class(class) => self.class_declaration(class, stmt.span),
+            StmtKind::Return(value) => self.return_statement(value.as_ref(), stmt.span),
         }
-    }
 
-    fn binary(&mut self, _can_assign: bool) {
-        let operator_kind = self.previous.as_ref().unwrap().kind;
+        // code emitted after a statement comes from its closing `;` or `}`
+        self.at = last_char(stmt.span);
+    }
 
-        let precedence = get_rule_precedence(&operator_kind);
+    fn block(&mut self, statements: &'a [Stmt], span: Span) {
+        for statement in statements {
+            self.declaration(statement);
+        }
 
-        self.parse_precedence((precedence as u8 + 1).try_into().unwrap());
+        self.at = last_char(span);
+    }
 
-        match operator_kind {
-            TokenKind::BangEqual => self.emit_bytes(&[OpCode::Equal as u8, OpCode::Not as u8]),
-            TokenKind::EqualEqual => self.emit_byte(OpCode::Equal as u8),
-            TokenKind::Greater => self.emit_byte(OpCode::Greater as u8),
-            TokenKind::GreaterEqual => self.emit_bytes(&[OpCode::Less as u8, OpCode::Not as u8]),
-            TokenKind::Less => self.emit_byte(OpCode::Less as u8),
-            TokenKind::LessEqual => self.emit_bytes(&[OpCode::Greater as u8, OpCode::Not as u8]),
-            TokenKind::Plus => self.emit_byte(OpCode::Add as u8),
-            TokenKind::Minus => self.emit_byte(OpCode::Subtract as u8),
-            TokenKind::Star => self.emit_byte(OpCode::Multiply as u8),
-            TokenKind::Slash => self.emit_byte(OpCode::Divide as u8),
-            TokenKind::Percent => self.emit_byte(OpCode::Remainder as u8),
-            _ => unreachable!(),
+    fn variable_declaration(
+        &mut self,
+        name: &'a Identifier,
+        initializer: Option<&'a Expr>,
+        span: Span,
+    ) {
+        self.at = name.span;
+        self.declare_variable(name);
+        let global = self.variable_slot(&name.name);
+
+        match initializer {
+            Some(initializer) => self.expression(initializer),
+            None => self.emit_byte(OpCode::Nil as u8),
         }
+
+        self.at = last_char(span);
+        self.define_variable(global);
     }
 
-    fn literal(&mut self, _can_assign: bool) {
-        let kind = self.previous.as_ref().unwrap().kind;
+    fn class_declaration(&mut self, class: &'a Class, span: Span) {
+        self.at = class.name.span;
+        let name_constant = self.identifier_constant(&class.name.name);
+        self.declare_variable(&class.name);
+        let global = self.variable_slot(&class.name.name);
+
+        self.emit_constant_op(OpCode::Class, OpCode::LongClass, name_constant);
+        self.define_variable(global);
+
+        self.classes.push(ClassCompiler {
+            has_superclass: false,
+        });
+
+        if let Some(superclass) = &class.superclass {
+            self.at = superclass.span;
+            self.named_variable(&superclass.name, None);
+
+            if class.name.name == superclass.name {
+                self.report_error_at_previous("A class can't inherit from itself.");
+            }
+
+            // the superclass lives in a local named `super` so methods can capture it
+            self.begin_scope();
+            self.add_local("super", None);
+            self.define_variable(0);
 
-        match kind {
-            TokenKind::Nil => self.emit_byte(OpCode::Nil as u8),
-            TokenKind::True => self.emit_byte(OpCode::True as u8),
-            TokenKind::False => self.emit_byte(OpCode::False as u8),
-            _ => unreachable!(),
+            self.named_variable(&class.name.name, None);
+            self.emit_byte(OpCode::Inherit as u8);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        // load the class so methods can be attached to it
+        self.named_variable(&class.name.name, None);
+        for method in &class.methods {
+            self.at = method.name.span;
+            let constant = self.identifier_constant(&method.name.name);
+
+            let kind = if method.name.name == "init" {
+                FunctionKind::Initializer
+            } else {
+                FunctionKind::Method
+            };
+
+            self.function(method, kind);
+            self.emit_constant_op(OpCode::Method, OpCode::LongMethod, constant);
+        }
+        self.at = last_char(span);
+        self.emit_byte(OpCode::Pop as u8);
+
+        if self.classes.pop().unwrap().has_superclass {
+            self.end_scope();
         }
     }
 
-    fn string(&mut self, _can_assign: bool) {
-        let token = self.previous.as_ref().unwrap();
+    fn function(&mut self, function: &'a crate::ast::Function, kind: FunctionKind) {
+        let name = function.name.name.clone();
+        self.compilers.push(Compiler::new(kind, Some(name)));
+        self.begin_scope();
+
+        for param in &function.params {
+            self.compiler_mut().function.arity += 1;
 
-        match parse_string(&token.span[1..(token.span.len() - 1)]) {
-            Ok(s) => self.emit_constant(s.into()),
-            Err(s) => self.report_error_at_previous(s),
+            self.at = param.span;
+            self.declare_variable(param);
+            self.define_variable(0);
+        }
+
+        self.block(&function.body, function.span);
+
+        // no end_scope() needed, the frame's slots are discarded on return
+        let (compiled, upvalues) = self.end_compiler();
+
+        let compiled = self.heap.alloc(compiled);
+        let constant = self.make_constant(compiled.into());
+        self.emit_constant_op(OpCode::Closure, OpCode::LongClosure, constant);
+
+        for upvalue in upvalues {
+            self.emit_bytes(&[upvalue.is_local as u8, upvalue.index]);
         }
     }
 
-    fn variable(&mut self, can_assign: bool) {
-        self.named_variable(self.previous.clone().unwrap(), can_assign);
+    fn end_compiler(&mut self) -> (Function, Vec<UpvalueRef>) {
+        self.emit_return();
+
+        let compiler = self.compilers.pop().unwrap();
+
+        (compiler.function, compiler.upvalues)
     }
 
-    fn named_variable(&mut self, name_token: Token, can_assign: bool) {
-        let global = self.identifier_constant(name_token);
+    fn return_statement(&mut self, value: Option<&'a Expr>, span: Span) {
+        // the `return` keyword
+        self.at = Span::new(span.start, span.start + "return".len());
 
-        if can_assign && self.check_advance(TokenKind::Equal) {
-            // set
-            self.expression();
-            if constant_is_long(global) {
-                self.emit_byte(OpCode::SetLongGlobal as u8);
-                self.emit_int(global, 3);
-            } else {
-                self.emit_bytes(&[OpCode::SetGlobal as u8, global as u8]);
+        if self.compiler().kind == FunctionKind::Script {
+            self.report_error_at_previous("Can't return from top-level code.");
+        }
+
+        match value {
+            None => {
+                self.at = last_char(span);
+                self.emit_return();
             }
-        } else {
-            // get
-            if constant_is_long(global) {
-                self.emit_byte(OpCode::GetLongGlobal as u8);
-                self.emit_int(global, 3);
-            } else {
-                self.emit_bytes(&[OpCode::GetGlobal as u8, global as u8]);
+            Some(value) => {
+                if self.compiler().kind == FunctionKind::Initializer {
+                    self.report_error_at_previous("Can't return a value from an initializer.");
+                }
+
+                self.expression(value);
+                self.at = last_char(span);
+                self.emit_byte(OpCode::Return as u8);
             }
         }
     }
 }
 
-fn get_rule_prefix(token: &TokenKind) -> Option<ParseFn> {
-    let literal = |self_: &mut Parser<'_>, can_assign: bool| Parser::literal(self_, can_assign);
-    Some(match token {
-        TokenKind::LeftParen => |self_: &mut Parser<'_>, can_assign: bool| Parser::grouping(self_, can_assign),
-        TokenKind::Minus => |self_: &mut Parser<'_>, can_assign: bool| Parser::unary(self_, can_assign),
-        TokenKind::Bang => |self_: &mut Parser<'_>, can_assign: bool| Parser::unary(self_, can_assign),
-        TokenKind::Number => |self_: &mut Parser<'_>, can_assign: bool| Parser::number(self_, can_assign),
-        TokenKind::Nil => literal,
-        TokenKind::False => literal,
-        TokenKind::True => literal,
-        TokenKind::String => |self_: &mut Parser<'_>, can_assign: bool| Parser::string(self_, can_assign),
-        TokenKind::Identifier => |self_: &mut Parser<'_>, can_assign: bool| Parser::variable(self_, can_assign),
-        _ => return None,
-    })
-}
+// expressions
+impl<'a> Generator<'a> {
+    fn expression(&mut self, expr: &'a Expr) {
+        // infix expressions nest to the left, so a long chain such as `a.b.c` is compiled from
+        // its innermost operand out rather than recursively
+        let mut chain = vec![];
+        let mut innermost = expr;
+        while let Some(operand) = left_operand(innermost) {
+            chain.push(innermost);
+            innermost = operand;
+        }
 
-fn get_rule_infix(token: &TokenKind) -> Option<ParseFn> {
-    let binary = |self_: &mut Parser<'_>, can_assign: bool| Parser::binary(self_, can_assign);
-    Some(match token {
-        TokenKind::Plus => binary,
-        TokenKind::Minus => binary,
-        TokenKind::Star => binary,
-        TokenKind::Slash => binary,
-        TokenKind::Percent => binary,
-        TokenKind::EqualEqual => binary,
-        TokenKind::Greater => binary,
-        TokenKind::GreaterEqual => binary,
-        TokenKind::Less => binary,
-        TokenKind::LessEqual => binary,
-        _ => return None,
-    })
-}
+        self.prefix(innermost);
+        for expr in chain.into_iter().rev() {
+            self.infix(expr);
+        }
+    }
 
-fn get_rule_precedence(token: &TokenKind) -> Precedence {
-    match token {
-        TokenKind::Plus => Precedence::Term,
-        TokenKind::Minus => Precedence::Term,
-        TokenKind::Star => Precedence::Factor,
-        TokenKind::Slash => Precedence::Factor,
-        TokenKind::Percent => Precedence::Factor,
-        TokenKind::BangEqual => Precedence::Equality,
-        TokenKind::EqualEqual => Precedence::Equality,
-        TokenKind::Greater => Precedence::Comparison,
-        TokenKind::GreaterEqual => Precedence::Comparison,
-        TokenKind::Less => Precedence::Comparison,
-        TokenKind::LessEqual => Precedence::Comparison,
-        _ => Precedence::None,
+    /// Compiles an expression with no operand on its left
+    fn prefix(&mut self, expr: &'a Expr) {
+        match &expr.kind {
+            ExprKind::Literal(literal) => {
+                self.at = expr.span;
+                match literal {
+                    Literal::Nil => self.emit_byte(OpCode::Nil as u8),
+                    Literal::Bool(true) => self.emit_byte(OpCode::True as u8),
+                    Literal::Bool(false) => self.emit_byte(OpCode::False as u8),
+                    Literal::Number(number) => self.emit_constant((*number).into()),
+                    Literal::String(string) => {
+                        let string = self.heap.intern(string);
+                        self.emit_constant(string.into())
+                    }
+                }
+            }
+            ExprKind::Grouping(inner) => self.expression(inner),
+            ExprKind::Variable(name) => {
+                self.at = name.span;
+                self.named_variable(&name.name, None);
+            }
+            ExprKind::Assign { name, value } => {
+                self.at = name.span;
+                self.named_variable(&name.name, Some(value));
+            }
+            ExprKind::Unary { operator, operand } => {
+                self.expression(operand);
+                self.at = last_token(operand);
+
+                match operator.kind {
+                    UnaryOp::Not => self.emit_byte(OpCode::Not as u8),
+                    UnaryOp::Negate => self.emit_byte(OpCode::Negate as u8),
+                }
+            }
+            ExprKind::This => {
+                self.at = expr.span;
+
+                if self.classes.is_empty() {
+                    self.report_error_at_previous("Can't use `this` outside of a class.");
+                    return;
+                }
+
+                self.named_variable("this", None);
+            }
+            ExprKind::Super { method } => {
+                let name = self.super_method(expr.span, method);
+
+                self.named_variable("super", None);
+                self.emit_constant_op(OpCode::GetSuper, OpCode::GetLongSuper, name);
+            }
+            ExprKind::Call { callee, arguments } => {
+                let ExprKind::Super { method } = &callee.kind else {
+                    unreachable!("only calls to `super` methods have no left operand")
+                };
+                let name = self.super_method(callee.span, method);
+
+                self.arguments(arguments);
+                self.at = last_char(expr.span);
+                self.named_variable("super", None);
+                self.emit_constant_op(OpCode::SuperInvoke, OpCode::LongSuperInvoke, name);
+                self.emit_byte(arguments.len() as u8);
+            }
+            _ => unreachable!("an infix expression"),
+        }
     }
-}
 
-fn parse_string(escaped_string: &str) -> Result<String, &'static str> {
-    let mut final_string = String::with_capacity(escaped_string.len());
-    let mut iter = escaped_string.chars();
-
-    while let Some(ch) = iter.next() {
-        final_string.push(match ch {
-            '\\' => match iter.next() {
-                Some(esc) => match esc {
-                    'n' => '\n',
-                    'r' => '\r',
-                    't' => '\t',
-                    '\\' => '\\',
-                    '0' => '\0',
-                    '\'' => '\'',
-                    '\"' => '\"',
-                    'x' => return Err("unimplemented string escape `\\x`"),
-                    'u' => return Err("unimplemented string escape `\\u`"),
-                    _ => return Err("unrecognized string escape"),
-                },
-                None => return Err("unexpected end of string"),
-            },
-            _ => ch,
-        });
+    /// Compiles the rest of an expression whose left operand is already on the stack
+    fn infix(&mut self, expr: &'a Expr) {
+        match &expr.kind {
+            ExprKind::Binary {
+                operator, right, ..
+            } => {
+                self.expression(right);
+                self.at = last_token(right);
+
+                let fused = self.opt_level >= OptLevel::O1;
+                match operator.kind {
+                    BinaryOp::NotEqual if fused => self.emit_byte(OpCode::NotEqual as u8),
+                    BinaryOp::NotEqual => {
+                        self.emit_bytes(&[OpCode::Equal as u8, OpCode::Not as u8])
+                    }
+                    BinaryOp::Equal => self.emit_byte(OpCode::Equal as u8),
+                    BinaryOp::Greater => self.emit_byte(OpCode::Greater as u8),
+                    BinaryOp::GreaterEqual if fused => self.emit_byte(OpCode::GreaterEqual as u8),
+                    BinaryOp::GreaterEqual => {
+                        self.emit_bytes(&[OpCode::Less as u8, OpCode::Not as u8])
+                    }
+                    BinaryOp::Less => self.emit_byte(OpCode::Less as u8),
+                    BinaryOp::LessEqual if fused => self.emit_byte(OpCode::LessEqual as u8),
+                    BinaryOp::LessEqual => {
+                        self.emit_bytes(&[OpCode::Greater as u8, OpCode::Not as u8])
+                    }
+                    BinaryOp::Add => self.emit_byte(OpCode::Add as u8),
+                    BinaryOp::Subtract => self.emit_byte(OpCode::Subtract as u8),
+                    BinaryOp::Multiply => self.emit_byte(OpCode::Multiply as u8),
+                    BinaryOp::Divide => self.emit_byte(OpCode::Divide as u8),
+                    BinaryOp::Remainder => self.emit_byte(OpCode::Remainder as u8),
+                }
+            }
+            ExprKind::Logical {
+                operator, right, ..
+            } => {
+                self.at = operator.span;
+
+                let end_jump = match operator.kind {
+                    LogicalOp::And => {
+                        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+                        self.emit_byte(OpCode::Pop as u8);
+                        end_jump
+                    }
+                    LogicalOp::Or => {
+                        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+                        let end_jump = self.emit_jump(OpCode::Jump);
+
+                        self.patch_jump(else_jump);
+                        self.emit_byte(OpCode::Pop as u8);
+                        end_jump
+                    }
+                };
+
+                self.expression(right);
+                self.at = last_token(right);
+                self.patch_jump(end_jump);
+            }
+            ExprKind::Call { callee, arguments } => {
+                if let ExprKind::Get { name, .. } = &callee.kind {
+                    // a method called straight away is invoked without creating a bound method
+                    self.at = name.span;
+                    let name = self.identifier_constant(&name.name);
+
+                    self.arguments(arguments);
+                    self.at = last_char(expr.span);
+                    self.emit_constant_op(OpCode::Invoke, OpCode::LongInvoke, name);
+                    self.emit_byte(arguments.len() as u8);
+                } else {
+                    self.arguments(arguments);
+                    self.at = last_char(expr.span);
+                    self.emit_bytes(&[OpCode::Call as u8, arguments.len() as u8]);
+                }
+            }
+            ExprKind::Get { name, .. } => {
+                self.at = name.span;
+                let name = self.identifier_constant(&name.name);
+                self.emit_constant_op(OpCode::GetProperty, OpCode::GetLongProperty, name);
+            }
+            ExprKind::Set { name, value, .. } => {
+                self.at = name.span;
+                let name = self.identifier_constant(&name.name);
+
+                self.expression(value);
+                self.at = last_token(value);
+                self.emit_constant_op(OpCode::SetProperty, OpCode::SetLongProperty, name);
+            }
+            _ => unreachable!("a prefix expression"),
+        }
     }
 
-    Ok(final_string)
-}
+    /// Compiles an expression and pops its value, with the `Pop` attributed to `end`
+    ///
+    /// When optimizing, a literal is left out rather than loaded only to be popped.
+    fn discarded_expression(&mut self, expr: &'a Expr, end: Span) {
+        if self.opt_level >= OptLevel::O1 && matches!(expr.kind, ExprKind::Literal(_)) {
+            return;
+        }
 
-pub fn compile(source: &str) -> Result<Chunk, InterpretError> {
-    let scanner = Scanner::new(source);
+        self.expression(expr);
+        self.at = end;
+        self.emit_byte(OpCode::Pop as u8);
+    }
 
-    let mut parser = Parser::new(scanner);
+    fn arguments(&mut self, arguments: &'a [Expr]) {
+        for argument in arguments {
+            self.expression(argument);
+        }
+    }
 
-    parser.advance();
+    /// Checks a `super` expression is in a subclass and loads the receiver, returning the
+    /// constant naming the method
+    fn super_method(&mut self, span: Span, method: &Identifier) -> usize {
+        // the `super` keyword
+        self.at = Span::new(span.start, span.start + "super".len());
 
-    while let Some(t) = &parser.current {
-        if t.kind == TokenKind::Eof {
-            break;
-        } else {
-            parser.declaration();
+        match self.classes.last() {
+            None => self.report_error_at_previous("Can't use `super` outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.report_error_at_previous("Can't use `super` in a class with no superclass.")
+            }
+            _ => (),
         }
+
+        self.at = method.span;
+        let name = self.identifier_constant(&method.name);
+
+        self.named_variable("this", None);
+        name
+    }
+}
+
+/// Compiles a script into a function, allocating its constants on `heap` and resolving its
+/// global variables to slots in `globals`
+///
+/// The script is parsed into a tree, which is then optimized as `opt_level` asks and lowered to
+/// bytecode. The heap is never collected during compilation. If the script has errors, every
+/// one found is returned in an `InterpretError::CompileError`.
+pub(crate) fn compile(
+    source: &str,
+    heap: &mut Heap,
+    globals: &mut Globals,
+    opt_level: OptLevel,
+) -> Result<ObjRef, InterpretError> {
+    let (mut program, mut diagnostics) = parse_with_errors(source);
+
+    if opt_level >= OptLevel::O1 {
+        fold_program(&mut program);
     }
 
-    parser.consume(TokenKind::Eof, "Expect end of expression.");
+    let mut generator = Generator::new(source, heap, globals, opt_level);
 
-    if parser.had_error {
-        return Err(InterpretError::CompileError("compile error"));
+    for statement in &program.statements {
+        generator.declaration(statement);
     }
 
-    parser.emit_byte(OpCode::Return as u8);
+    generator.at = Span::new(source.len(), source.len());
+    let (function, _) = generator.end_compiler();
 
-    if !parser.had_error {
-        parser.chunk.disassemble("code");
+    // the parser's and generator's errors are each in source order, so interleave them
+    diagnostics.append(&mut generator.diagnostics);
+    diagnostics.sort_by_key(|diagnostic| diagnostic.primary.span.start);
+    if !diagnostics.is_empty() {
+        return Err(InterpretError::CompileError(diagnostics));
     }
 
-    Ok(parser.chunk)
+    Ok(generator.heap.alloc(function))
 }
@@ -0,0 +1,241 @@
+//! Runs `.lox` scripts and checks them against the expectations written in their comments
+//!
+//! The annotations follow the Crafting Interpreters test suite:
+//!
+//! - `// expect: TEXT` expects the script to print a line of `TEXT`
+//! - `// Error at 'x': MESSAGE` expects a compile error on the same line, and
+//!   `// [line N] Error at 'x': MESSAGE` one on line `N`
+//! - `// expect runtime error: MESSAGE` expects the script to fail on the same line
+
+use crate::diagnostic::Diagnostic;
+use crate::vm::InterpretError;
+use crate::vm::OutputBuffer;
+use crate::vm::Vm;
+
+use std::path::Path;
+use std::path::PathBuf;
+
+/// What a script is expected to do, read from its comments
+#[derive(Debug, Default, PartialEq)]
+pub struct Expectations {
+    /// The lines the script prints
+    pub output: Vec<String>,
+    /// Compile errors in the form `[line N] Error at 'x': message`
+    pub compile_errors: Vec<String>,
+    /// The message and line of the runtime error that stops the script
+    pub runtime_error: Option<(String, usize)>,
+}
+
+impl Expectations {
+    pub fn parse(source: &str) -> Expectations {
+        let mut expectations = Expectations::default();
+
+        for (index, line) in source.lines().enumerate() {
+            let number = index + 1;
+            let Some((_, comment)) = line.split_once("// ") else {
+                continue;
+            };
+
+            if let Some(output) = comment.strip_prefix("expect: ") {
+                expectations.output.push(output.to_string());
+            } else if let Some(message) = comment.strip_prefix("expect runtime error: ") {
+                expectations.runtime_error = Some((message.to_string(), number));
+            } else if comment.starts_with("[line ") {
+                expectations.compile_errors.push(comment.to_string());
+            } else if comment.starts_with("Error") {
+                let error = format!("[line {}] {}", number, comment);
+                expectations.compile_errors.push(error);
+            }
+        }
+
+        expectations
+    }
+}
+
+/// How a script went against its expectations
+#[derive(Debug)]
+pub struct Outcome {
+    pub path: PathBuf,
+    /// What went differently than expected, empty if the script passed
+    pub failures: Vec<String>,
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Runs a script in a fresh `Vm` and lists every way it differs from its expectations
+pub fn check(source: &str) -> Vec<String> {
+    let expected = Expectations::parse(source);
+    let mut failures = vec![];
+
+    let output = OutputBuffer::default();
+    let mut vm = Vm::new().with_output(output.clone());
+    let result = vm.interpret(source);
+
+    let output = output.contents();
+    let output: Vec<&str> = output.lines().collect();
+    if output != expected.output {
+        let diff = diff(&expected.output, &output);
+        failures.push(format!("output differs (- expected, + actual):\n{}", diff));
+    }
+
+    match result {
+        Ok(()) => {
+            if let Some((message, line)) = &expected.runtime_error {
+                let failure = format!("expected runtime error on line {}: {}", line, message);
+                failures.push(failure);
+            }
+            if !expected.compile_errors.is_empty() {
+                failures.push("expected compile errors, but it compiled".to_string());
+            }
+        }
+        Err(InterpretError::CompileError(diagnostics)) => {
+            let errors: Vec<String> = diagnostics
+                .iter()
+                .map(|diagnostic| error_line(diagnostic, source))
+                .collect();
+            if errors != expected.compile_errors {
+                let diff = diff(&expected.compile_errors, &errors);
+                failures.push(format!(
+                    "compile errors differ (- expected, + actual):\n{}",
+                    diff
+                ));
+            }
+        }
+        Err(InterpretError::RuntimeError(error)) => {
+            let actual = (error.message.clone(), error.line().unwrap_or(0));
+            match &expected.runtime_error {
+                Some(expected) if *expected == actual => (),
+                Some((message, line)) => failures.push(format!(
+                    "expected runtime error on line {}: {}\n     got runtime error on line {}: {}",
+                    line, message, actual.1, actual.0
+                )),
+                None => failures.push(format!(
+                    "unexpected runtime error on line {}: {}",
+                    actual.1, actual.0
+                )),
+            }
+        }
+        Err(error) => failures.push(format!("unexpected error: {}", error)),
+    }
+
+    failures
+}
+
+/// Formats a diagnostic the way the Crafting Interpreters suite expects compile errors
+fn error_line(diagnostic: &Diagnostic, source: &str) -> String {
+    let span = diagnostic.primary.span;
+    let location = if span.start < span.end {
+        format!(" at '{}'", &source[span.start..span.end])
+    } else if span.start >= source.len() {
+        " at end".to_string()
+    } else {
+        String::new()
+    };
+    format!(
+        "[line {}] Error{}: {}",
+        diagnostic.line, location, diagnostic.message
+    )
+}
+
+/// Shows how to turn `expected` into `actual` a line at a time, using a longest common
+/// subsequence
+pub(crate) fn diff(expected: &[impl AsRef<str>], actual: &[impl AsRef<str>]) -> String {
+    let expected: Vec<&str> = expected.iter().map(AsRef::as_ref).collect();
+    let actual: Vec<&str> = actual.iter().map(AsRef::as_ref).collect();
+    let (n, m) = (expected.len(), actual.len());
+
+    // common[i][j] is the length of the longest common subsequence of expected[i..] and
+    // actual[j..]
+    let mut common = vec![vec![0; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            common[i][j] = if expected[i] == actual[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && expected[i] == actual[j] {
+            lines.push(format!("  {}", expected[i]));
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(format!("- {}", expected[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+ {}", actual[j]));
+            j += 1;
+        }
+    }
+    lines.join("\n")
+}
+
+/// Finds every `.lox` file under `dir` whose path contains `filter`, sorted by path
+pub fn find_tests(dir: &Path, filter: Option<&str>) -> std::io::Result<Vec<PathBuf>> {
+    let mut tests = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_some_and(|extension| extension == "lox")
+                && filter.is_none_or(|filter| path.to_string_lossy().contains(filter))
+            {
+                tests.push(path);
+            }
+        }
+    }
+
+    tests.sort();
+    Ok(tests)
+}
+
+pub fn run_file(path: &Path) -> Outcome {
+    let failures = match std::fs::read_to_string(path) {
+        Ok(source) => check(&source),
+        Err(error) => vec![format!("could not read the script: {}", error)],
+    };
+
+    Outcome {
+        path: path.to_path_buf(),
+        failures,
+    }
+}
+
+/// Runs scripts on up to `jobs` threads, returning their outcomes in the order given
+pub fn run_all(paths: &[PathBuf], jobs: usize) -> Vec<Outcome> {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Mutex;
+
+    let next = AtomicUsize::new(0);
+    let outcomes = Mutex::new(vec![]);
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, paths.len().max(1)) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = paths.get(index) else {
+                    break;
+                };
+                let outcome = run_file(path);
+                outcomes.lock().unwrap().push((index, outcome));
+            });
+        }
+    });
+
+    let mut outcomes = outcomes.into_inner().unwrap();
+    outcomes.sort_by_key(|(index, _)| *index);
+    outcomes.into_iter().map(|(_, outcome)| outcome).collect()
+}
@@ -25,6 +25,11 @@ impl LineMap {
         self.push(offset, line);
     }
 
+    /// Each offset at which the line changes, and the new line
+    pub fn pairs(&self) -> &[(usize, usize)] {
+        &self.pairs
+    }
+
     pub fn get_line(&self, offset: usize) -> usize {
         let mut last_line = 0;
 
@@ -0,0 +1,196 @@
+//! Problems found in source code, kept as data so callers decide how to show them
+
+/// A range of bytes in the source
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    /// The one-based line and column, in characters, of the start of the span
+    pub fn location(&self, source: &str) -> (usize, usize) {
+        let start = self.start.min(source.len());
+        let before = &source[..start];
+        let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+        let line = before.matches('\n').count() + 1;
+        let column = before[line_start..].chars().count() + 1;
+        (line, column)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A span of source with an optional explanation of why it matters
+#[derive(Clone, Debug, PartialEq)]
+pub struct Label {
+    pub span: Span,
+    pub message: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// The code the problem is in, which is empty for errors at the end of the source and in
+    /// malformed tokens
+    pub primary: Label,
+    /// Other code that helps explain the problem
+    pub secondary: Vec<Label>,
+    pub notes: Vec<String>,
+    /// The one-based line of the primary span
+    pub line: usize,
+    /// The one-based column, in characters, of the primary span
+    pub column: usize,
+}
+
+impl Diagnostic {
+    /// An error at `span`, which starts at `line` and `column`
+    pub fn error(message: impl Into<String>, span: Span, line: usize, column: usize) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.into(),
+            primary: Label {
+                span,
+                message: None,
+            },
+            secondary: vec![],
+            notes: vec![],
+            line,
+            column,
+        }
+    }
+
+    pub fn with_label(mut self, message: impl Into<String>) -> Diagnostic {
+        self.primary.message = Some(message.into());
+        self
+    }
+
+    pub fn with_secondary(mut self, span: Span, message: impl Into<String>) -> Diagnostic {
+        self.secondary.push(Label {
+            span,
+            message: Some(message.into()),
+        });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Diagnostic {
+        self.notes.push(note.into());
+        self
+    }
+
+    /// Renders the diagnostic with the source lines it points at, underlining the primary span
+    /// with `^` and secondary spans with `-`
+    ///
+    /// ```text
+    /// error: Expect `;` after value.
+    ///  --> line 1, column 8
+    ///   |
+    /// 1 | print 1
+    ///   |        ^
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        use std::fmt::Write;
+
+        let mut labels: Vec<(usize, usize, &Label, char)> = std::iter::once((&self.primary, '^'))
+            .chain(self.secondary.iter().map(|label| (label, '-')))
+            .map(|(label, underline)| {
+                let (line, column) = label.span.location(source);
+                (line, column, label, underline)
+            })
+            .collect();
+        labels.sort_by_key(|(line, ..)| *line);
+
+        let gutter = labels
+            .iter()
+            .map(|(line, ..)| line.to_string().len())
+            .max()
+            .unwrap_or(1);
+
+        let mut out = String::new();
+        writeln!(out, "{}: {}", self.severity, self.message).unwrap();
+        writeln!(
+            out,
+            "{:gutter$}--> line {}, column {}",
+            "", self.line, self.column
+        )
+        .unwrap();
+        writeln!(out, "{:gutter$} |", "").unwrap();
+
+        let mut last_line = None;
+        for (line, column, label, underline) in labels {
+            let text = source
+                .lines()
+                .nth(line - 1)
+                .unwrap_or("")
+                .replace('\t', " ");
+
+            if last_line != Some(line) {
+                writeln!(out, "{:>gutter$} | {}", line, text).unwrap();
+                last_line = Some(line);
+            }
+
+            // spans running past the end of the line are cut off there
+            let width = source
+                [label.span.start.min(source.len())..label.span.end.min(source.len())]
+                .lines()
+                .next()
+                .map_or(0, |first| first.chars().count())
+                .max(1);
+            write!(
+                out,
+                "{:gutter$} | {:indent$}{}",
+                "",
+                "",
+                underline.to_string().repeat(width),
+                indent = column - 1
+            )
+            .unwrap();
+            match &label.message {
+                Some(message) => writeln!(out, " {}", message).unwrap(),
+                None => writeln!(out).unwrap(),
+            }
+        }
+
+        for note in &self.notes {
+            writeln!(out, "{:gutter$} = note: {}", "", note).unwrap();
+        }
+
+        out
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "[line {}, column {}] {}: {}",
+            self.line, self.column, self.severity, self.message
+        )
+    }
+}
+
+/// Renders every diagnostic, one after the other
+pub fn render_all(diagnostics: &[Diagnostic], source: &str) -> String {
+    diagnostics
+        .iter()
+        .map(|diagnostic| diagnostic.render(source))
+        .collect()
+}
@@ -0,0 +1,775 @@
+//! Runs programs through both the `Vm` and the reference interpreter, looking for places they
+//! disagree
+//!
+//! Programs come from script files or from a generator of random programs that always
+//! terminate. When the interpreters disagree, the program is shrunk a line at a time to a small
+//! reproducer.
+
+use crate::reference;
+use crate::vm::InterpretError;
+use crate::vm::Limits;
+use crate::vm::OutputBuffer;
+use crate::vm::Vm;
+use crate::vm::VmOptions;
+
+/// How many instructions a program may run before it is skipped as too slow to compare
+const INSTRUCTIONS_MAX: u64 = 1_000_000;
+
+/// How many bytes of objects a program may allocate before it is skipped
+const MEMORY_MAX: usize = 16 * 1024 * 1024;
+
+/// How many bytes of stack the reference interpreter runs with
+const REFERENCE_STACK: usize = 64 * 1024 * 1024;
+
+/// What a program did when it was run
+#[derive(Debug, PartialEq)]
+pub struct Behaviour {
+    pub output: String,
+    pub ending: Ending,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Ending {
+    Finished,
+    /// Only whether a program compiles is compared, as the reference stops at its first error
+    CompileError,
+    RuntimeError(String),
+}
+
+impl std::fmt::Display for Ending {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Ending::Finished => write!(f, "finished"),
+            Ending::CompileError => write!(f, "failed to compile"),
+            Ending::RuntimeError(message) => write!(f, "runtime error: {}", message),
+        }
+    }
+}
+
+/// A program the interpreters disagree about
+#[derive(Debug)]
+pub struct Mismatch {
+    /// The smallest version of the program found that they still disagree about
+    pub reproducer: String,
+    pub vm: Behaviour,
+    pub reference: Behaviour,
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.vm.output != self.reference.output {
+            let vm: Vec<&str> = self.vm.output.lines().collect();
+            let reference: Vec<&str> = self.reference.output.lines().collect();
+            let diff = crate::conformance::diff(&vm, &reference);
+            writeln!(f, "output differs (- vm, + reference):\n{}", diff)?;
+        }
+        if self.vm.ending != self.reference.ending {
+            writeln!(f, "vm {}", self.vm.ending)?;
+            writeln!(f, "reference {}", self.reference.ending)?;
+        }
+        writeln!(f, "reproducer:")?;
+        for line in self.reproducer.lines() {
+            writeln!(f, "    {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs a program through both interpreters, returning a minimized reproducer if they disagree
+pub fn check(source: &str) -> Option<Mismatch> {
+    disagreement(source)?;
+
+    let reproducer = minimize(source, |candidate| disagreement(candidate).is_some());
+    let (vm, reference) = disagreement(&reproducer)?;
+    Some(Mismatch {
+        reproducer,
+        vm,
+        reference,
+    })
+}
+
+/// What each interpreter did with a program, if they differ and both were able to run it
+fn disagreement(source: &str) -> Option<(Behaviour, Behaviour)> {
+    let vm = run_vm(source)?;
+    let reference = run_reference(source)?;
+    (vm != reference).then_some((vm, reference))
+}
+
+fn run_vm(source: &str) -> Option<Behaviour> {
+    let output = OutputBuffer::default();
+    let limits = Limits::default()
+        .instructions(INSTRUCTIONS_MAX)
+        .memory(MEMORY_MAX);
+    let options = VmOptions::default().limits(limits);
+    let mut vm = Vm::with_options(options)
+        .with_output(output.clone())
+        .with_input(std::io::empty());
+
+    let ending = match vm.interpret(source) {
+        Ok(()) => Ending::Finished,
+        Err(InterpretError::CompileError(_)) => Ending::CompileError,
+        Err(InterpretError::RuntimeError(error)) => Ending::RuntimeError(error.message),
+        Err(_) => return None,
+    };
+
+    Some(Behaviour {
+        output: output.contents(),
+        ending,
+    })
+}
+
+fn run_reference(source: &str) -> Option<Behaviour> {
+    let mut output = String::new();
+    // the reference recurses as deeply as scripts nest, which takes more stack than a test
+    // thread has when they nest nearly as deeply as the compiler allows
+    let result = std::thread::scope(|scope| {
+        std::thread::Builder::new()
+            .stack_size(REFERENCE_STACK)
+            .spawn_scoped(scope, || reference::interpret(source, &mut output))
+            .expect("failed to start the reference interpreter")
+            .join()
+            .unwrap()
+    });
+    let ending = match result {
+        Ok(()) => Ending::Finished,
+        Err(reference::Error::Compile(_)) => Ending::CompileError,
+        Err(reference::Error::Runtime(message)) => Ending::RuntimeError(message),
+        Err(reference::Error::Limit(_)) => return None,
+    };
+
+    Some(Behaviour { output, ending })
+}
+
+/// Removes as many lines from `source` as it can while `still_fails` holds
+///
+/// Whole blocks are tried first, then chunks of lines from large to small, until nothing more
+/// can be removed.
+pub fn minimize(source: &str, still_fails: impl Fn(&str) -> bool) -> String {
+    let mut lines: Vec<&str> = source.lines().collect();
+    loop {
+        let before = lines.len();
+        lines = remove_blocks(lines, &still_fails);
+        lines = remove_chunks(lines, &still_fails);
+        if lines.len() == before {
+            return join(&lines);
+        }
+    }
+}
+
+fn join(lines: &[&str]) -> String {
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+/// Tries removing each braced block, and failing that just the lines that open and close it
+fn remove_blocks(mut lines: Vec<&str>, still_fails: impl Fn(&str) -> bool) -> Vec<&str> {
+    let mut start = 0;
+    while start < lines.len() {
+        if let Some(end) = closing_line(&lines, start) {
+            let removed = [&lines[..start], &lines[end + 1..]].concat();
+            if still_fails(&join(&removed)) {
+                lines = removed;
+                continue;
+            }
+            let unwrapped = [&lines[..start], &lines[start + 1..end], &lines[end + 1..]].concat();
+            if still_fails(&join(&unwrapped)) {
+                lines = unwrapped;
+                continue;
+            }
+        }
+        start += 1;
+    }
+    lines
+}
+
+/// The line that closes the block opened on line `start`, if it opens one
+fn closing_line(lines: &[&str], start: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (index, line) in lines.iter().enumerate().skip(start) {
+        for c in line.chars() {
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => (),
+            }
+        }
+        if depth <= 0 {
+            return (index > start).then_some(index);
+        }
+    }
+    None
+}
+
+/// Tries removing chunks of lines, halving their size each time round
+fn remove_chunks(mut lines: Vec<&str>, still_fails: impl Fn(&str) -> bool) -> Vec<&str> {
+    let mut chunk = (lines.len() / 2).max(1);
+    loop {
+        let mut start = 0;
+        while start < lines.len() {
+            let end = (start + chunk).min(lines.len());
+            let removed = [&lines[..start], &lines[end..]].concat();
+            if still_fails(&join(&removed)) {
+                lines = removed;
+            } else {
+                start = end;
+            }
+        }
+
+        if chunk == 1 {
+            return lines;
+        }
+        chunk /= 2;
+    }
+}
+
+/// Writes a random program, the same one each time for the same seed
+///
+/// The program always terminates, as its loops count down variables their bodies can't assign,
+/// functions and methods only call those declared before them, and variables holding functions
+/// or instances are never reassigned.
+pub fn generate(seed: u64) -> String {
+    let mut generator = Generator {
+        rng: Rng(seed),
+        lines: vec![],
+        scopes: vec![vec![]],
+        hidden: None,
+        names: 0,
+        sloppy: false,
+    };
+    generator.sloppy = generator.rng.chance(20);
+
+    let statements = 10 + generator.rng.below(30);
+    for _ in 0..statements {
+        generator.statement(0);
+    }
+    generator.lines.join("\n") + "\n"
+}
+
+/// SplitMix64, which is enough to vary programs
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number below `n`
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// Whether an event that happens `percent` times in a hundred happens this time
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Type {
+    Number,
+    Bool,
+    String,
+    /// A function taking this many numbers and returning a number
+    Function(usize),
+    /// A class whose initializer takes a number
+    Class,
+    /// An instance with a number in its field `x` and a method `get` returning a number
+    Instance,
+}
+
+#[derive(Clone)]
+struct Variable {
+    name: String,
+    ty: Type,
+    assignable: bool,
+}
+
+// the precedence of each kind of expression, matching the compiler's
+const OR: u8 = 1;
+const AND: u8 = 2;
+const EQUALITY: u8 = 3;
+const COMPARISON: u8 = 4;
+const TERM: u8 = 5;
+const FACTOR: u8 = 6;
+const UNARY: u8 = 7;
+const PRIMARY: u8 = 8;
+
+/// How deeply statements and expressions nest
+const DEPTH_MAX: usize = 3;
+
+struct Generator {
+    rng: Rng,
+    lines: Vec<String>,
+    /// The variables declared in each scope, globals first
+    scopes: Vec<Vec<Variable>>,
+    /// A variable that can't be used because its initializer is being written
+    hidden: Option<String>,
+    names: usize,
+    /// Whether operands are sometimes of the wrong type, to exercise runtime errors
+    sloppy: bool,
+}
+
+impl Generator {
+    fn line(&mut self, text: String) {
+        let indent = "    ".repeat(self.scopes.len() - 1);
+        self.lines.push(indent + &text);
+    }
+
+    fn fresh(&mut self, prefix: &str) -> String {
+        self.names += 1;
+        format!("{}{}", prefix, self.names)
+    }
+
+    fn declare(&mut self, name: String, ty: Type, assignable: bool) {
+        let variable = Variable {
+            name,
+            ty,
+            assignable,
+        };
+        self.scopes.last_mut().unwrap().push(variable);
+    }
+
+    /// Picks one of the variables in scope that `accept` accepts
+    fn variable(&mut self, accept: impl Fn(&Variable) -> bool) -> Option<Variable> {
+        let mut visible: Vec<&Variable> = vec![];
+        for variable in self.scopes.iter().rev().flatten() {
+            if visible.iter().all(|seen| seen.name != variable.name) {
+                visible.push(variable);
+            }
+        }
+        let hidden = self.hidden.as_deref();
+        let candidates: Vec<Variable> = visible
+            .into_iter()
+            .filter(|variable| Some(variable.name.as_str()) != hidden && accept(variable))
+            .cloned()
+            .collect();
+
+        if candidates.is_empty() {
+            return None;
+        }
+        Some(self.rng.pick(&candidates).clone())
+    }
+
+    /// Writes statements in a new scope holding `scope`, followed by `last`, between braces the
+    /// caller writes
+    fn block(&mut self, depth: usize, scope: Vec<Variable>, last: Option<String>) {
+        self.scopes.push(scope);
+        for _ in 0..1 + self.rng.below(3) {
+            self.statement(depth + 1);
+        }
+        if let Some(last) = last {
+            self.line(last);
+        }
+        self.scopes.pop();
+    }
+
+    fn statement(&mut self, depth: usize) {
+        let kinds = if depth < DEPTH_MAX { 13 } else { 5 };
+        match self.rng.below(kinds) {
+            0 | 1 => {
+                let value = self.any_expression(0);
+                self.line(format!("print {};", value));
+            }
+            2 => self.var_declaration(),
+            3 => self.assignment(),
+            4 => self.expression_statement(),
+            5 => {
+                self.line("{".to_string());
+                self.block(depth, vec![], None);
+                self.line("}".to_string());
+            }
+            6 => self.if_statement(depth),
+            7 => self.for_statement(depth),
+            8 => self.while_statement(depth),
+            9 | 10 => self.function_declaration(depth),
+            11 => self.counter(),
+            _ => self.class_declaration(),
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let ty = *self
+            .rng
+            .pick(&[Type::Number, Type::Number, Type::Bool, Type::String]);
+
+        // sometimes shadow a variable from an enclosing scope
+        let local = self.scopes.len() > 1;
+        let in_scope = |variable: &Variable| {
+            self.scopes
+                .last()
+                .unwrap()
+                .iter()
+                .any(|v| v.name == variable.name)
+        };
+        let shadowable: Vec<String> = self
+            .scopes
+            .iter()
+            .flatten()
+            .filter(|variable| variable.assignable && !in_scope(variable))
+            .map(|variable| variable.name.clone())
+            .collect();
+        let name = match local && !shadowable.is_empty() && self.rng.chance(30) {
+            true => self.rng.pick(&shadowable).clone(),
+            false => self.fresh("v"),
+        };
+
+        self.hidden = Some(name.clone());
+        let value = self.expression(ty, 0).0;
+        self.hidden = None;
+
+        self.line(format!("var {} = {};", name, value));
+        self.declare(name, ty, true);
+    }
+
+    fn assignment(&mut self) {
+        let assignable = |variable: &Variable| variable.assignable;
+        let Some(variable) = self.variable(assignable) else {
+            return self.var_declaration();
+        };
+
+        // a string that can be appended to itself grows exponentially in loops
+        if variable.ty == Type::String {
+            self.hidden = Some(variable.name.clone());
+        }
+        let value = self.expression(variable.ty, 0).0;
+        self.hidden = None;
+        self.line(format!("{} = {};", variable.name, value));
+    }
+
+    fn expression_statement(&mut self) {
+        let object = |variable: &Variable| variable.ty == Type::Instance;
+        match self.variable(object) {
+            Some(object) if self.rng.chance(50) => {
+                let value = self.expression(Type::Number, 1).0;
+                self.line(format!("{}.x = {};", object.name, value));
+            }
+            _ => {
+                let call = self.expression(Type::Number, 0).0;
+                self.line(format!("{};", call));
+            }
+        }
+    }
+
+    fn if_statement(&mut self, depth: usize) {
+        let condition = self.expression(Type::Bool, 0).0;
+        self.line(format!("if ({}) {{", condition));
+        self.block(depth, vec![], None);
+        if self.rng.chance(40) {
+            self.line("} else {".to_string());
+            self.block(depth, vec![], None);
+        }
+        self.line("}".to_string());
+    }
+
+    fn for_statement(&mut self, depth: usize) {
+        let counter = self.fresh("i");
+        let count = self.rng.below(5);
+        self.line(format!(
+            "for (var {0} = 0; {0} < {1}; {0} = {0} + 1) {{",
+            counter, count
+        ));
+        let scope = vec![Variable {
+            name: counter,
+            ty: Type::Number,
+            assignable: false,
+        }];
+        self.block(depth, scope, None);
+        self.line("}".to_string());
+    }
+
+    fn while_statement(&mut self, depth: usize) {
+        let counter = self.fresh("w");
+        let count = self.rng.below(5);
+        self.line(format!("var {} = {};", counter, count));
+        self.declare(counter.clone(), Type::Number, false);
+
+        self.line(format!("while ({} > 0) {{", counter));
+        self.block(depth, vec![], Some(format!("{0} = {0} - 1;", counter)));
+        self.line("}".to_string());
+    }
+
+    fn function_declaration(&mut self, depth: usize) {
+        let name = self.fresh("f");
+        let params: Vec<String> = (0..self.rng.below(3)).map(|_| self.fresh("p")).collect();
+        self.line(format!("fun {}({}) {{", name, params.join(", ")));
+
+        let scope = params
+            .iter()
+            .map(|param| Variable {
+                name: param.clone(),
+                ty: Type::Number,
+                assignable: true,
+            })
+            .collect();
+        self.scopes.push(scope);
+        for _ in 0..self.rng.below(3) {
+            self.statement(depth + 1);
+        }
+        let value = self.expression(Type::Number, 0).0;
+        self.line(format!("return {};", value));
+        self.scopes.pop();
+
+        self.line("}".to_string());
+        // declared only now, so the function never calls itself
+        self.declare(name, Type::Function(params.len()), false);
+    }
+
+    /// Writes a function returning a closure that counts up, and calls it
+    fn counter(&mut self) {
+        let maker = self.fresh("make");
+        let count = self.fresh("count");
+        let step = self.fresh("step");
+        let counter = self.fresh("c");
+
+        self.line(format!("fun {}() {{", maker));
+        self.scopes.push(vec![]);
+        let start = self.expression(Type::Number, 1).0;
+        self.line(format!("var {} = {};", count, start));
+        self.declare(count.clone(), Type::Number, true);
+
+        self.line(format!("fun {}() {{", step));
+        self.scopes.push(vec![]);
+        let increment = self.expression(Type::Number, 1).0;
+        self.line(format!("{0} = {0} + {1};", count, increment));
+        self.line(format!("return {};", count));
+        self.scopes.pop();
+        self.line("}".to_string());
+
+        self.line(format!("return {};", step));
+        self.scopes.pop();
+        self.line("}".to_string());
+
+        self.line(format!("var {} = {}();", counter, maker));
+        self.declare(counter, Type::Function(0), false);
+    }
+
+    /// Writes a class, perhaps inheriting from another, and makes an instance of it
+    fn class_declaration(&mut self) {
+        let name = self.fresh("C");
+        let class = |variable: &Variable| variable.ty == Type::Class;
+        let superclass = match self.rng.chance(50) {
+            true => self.variable(class),
+            false => None,
+        };
+
+        match &superclass {
+            Some(superclass) => self.line(format!("class {} < {} {{", name, superclass.name)),
+            None => self.line(format!("class {} {{", name)),
+        }
+        self.scopes.push(vec![]);
+
+        if superclass.is_none() || self.rng.chance(50) {
+            let param = self.fresh("p");
+            self.line(format!("init({}) {{", param));
+            self.scopes.push(vec![Variable {
+                name: param.clone(),
+                ty: Type::Number,
+                assignable: true,
+            }]);
+            if superclass.is_some() {
+                let value = self.expression(Type::Number, 1).0;
+                self.line(format!("super.init({});", value));
+            } else {
+                self.line(format!("this.x = {};", param));
+            }
+            self.scopes.pop();
+            self.line("}".to_string());
+        }
+
+        self.line("get() {".to_string());
+        self.scopes.push(vec![]);
+        let value = self.operand(Type::Number, 1, TERM + 1);
+        match superclass {
+            Some(_) if self.rng.chance(70) => self.line(format!("return super.get() * {};", value)),
+            _ => self.line(format!("return this.x + {};", value)),
+        }
+        self.scopes.pop();
+        self.line("}".to_string());
+
+        self.scopes.pop();
+        self.line("}".to_string());
+        self.declare(name.clone(), Type::Class, false);
+
+        let object = self.fresh("o");
+        let argument = self.expression(Type::Number, 1).0;
+        self.line(format!("var {} = {}({});", object, name, argument));
+        self.declare(object, Type::Instance, false);
+    }
+
+    /// An expression of any type, including ones only worth printing
+    fn any_expression(&mut self, depth: usize) -> String {
+        let printable = |variable: &Variable| {
+            matches!(
+                variable.ty,
+                Type::Function(_) | Type::Class | Type::Instance
+            )
+        };
+        match self.rng.below(10) {
+            0 => "nil".to_string(),
+            1 => match self.variable(printable) {
+                Some(variable) => variable.name,
+                None => "nil".to_string(),
+            },
+            2 => match self.variable(|variable| variable.ty == Type::Instance) {
+                Some(object) => format!("{}.get", object.name),
+                None => "clock".to_string(),
+            },
+            _ => {
+                let ty = *self.rng.pick(&[Type::Number, Type::Bool, Type::String]);
+                self.expression(ty, depth).0
+            }
+        }
+    }
+
+    /// Writes an expression to use as an operand, parenthesized if its precedence is below
+    /// `precedence`
+    fn operand(&mut self, ty: Type, depth: usize, precedence: u8) -> String {
+        let ty = match self.sloppy && self.rng.chance(3) {
+            true => *self.rng.pick(&[Type::Number, Type::Bool, Type::String]),
+            false => ty,
+        };
+        let (text, own) = self.expression(ty, depth + 1);
+        if own < precedence || self.rng.chance(5) {
+            format!("({})", text)
+        } else {
+            text
+        }
+    }
+
+    /// Writes an expression of the given type, returning it with its precedence
+    fn expression(&mut self, ty: Type, depth: usize) -> (String, u8) {
+        let leaf = depth >= DEPTH_MAX || self.rng.chance(30);
+        match ty {
+            Type::Number if leaf => (self.number(), PRIMARY),
+            Type::Number => self.number_expression(depth),
+            Type::Bool if leaf => {
+                let boolean = |variable: &Variable| variable.ty == Type::Bool;
+                match self.variable(boolean) {
+                    Some(variable) if self.rng.chance(50) => (variable.name, PRIMARY),
+                    _ => (self.rng.pick(&["true", "false"]).to_string(), PRIMARY),
+                }
+            }
+            Type::Bool => self.bool_expression(depth),
+            Type::String if leaf || self.rng.chance(50) => {
+                let string = |variable: &Variable| variable.ty == Type::String;
+                match self.variable(string) {
+                    Some(variable) if self.rng.chance(50) => (variable.name, PRIMARY),
+                    _ => {
+                        let text = self.rng.pick(&["", "a", "lox", "the end"]);
+                        (format!("\"{}\"", text), PRIMARY)
+                    }
+                }
+            }
+            Type::String => {
+                let left = self.operand(Type::String, depth, TERM);
+                let right = self.operand(Type::String, depth, TERM + 1);
+                (format!("{} + {}", left, right), TERM)
+            }
+            Type::Function(_) | Type::Class | Type::Instance => {
+                unreachable!("only numbers, booleans and strings are generated")
+            }
+        }
+    }
+
+    fn number(&mut self) -> String {
+        let number = |variable: &Variable| variable.ty == Type::Number;
+        match self.variable(number) {
+            Some(variable) if self.rng.chance(50) => variable.name,
+            _ if self.rng.chance(20) => format!("{}.5", self.rng.below(10)),
+            _ => self.rng.below(10).to_string(),
+        }
+    }
+
+    fn number_expression(&mut self, depth: usize) -> (String, u8) {
+        match self.rng.below(8) {
+            0 => (
+                format!("-{}", self.operand(Type::Number, depth, UNARY)),
+                UNARY,
+            ),
+            1 | 2 => {
+                let operator = self.rng.pick(&["+", "-"]);
+                let left = self.operand(Type::Number, depth, TERM);
+                let right = self.operand(Type::Number, depth, TERM + 1);
+                (format!("{} {} {}", left, operator, right), TERM)
+            }
+            3 | 4 => {
+                let operator = self.rng.pick(&["*", "/", "%"]);
+                let left = self.operand(Type::Number, depth, FACTOR);
+                let right = self.operand(Type::Number, depth, FACTOR + 1);
+                (format!("{} {} {}", left, operator, right), FACTOR)
+            }
+            5 => {
+                let function = |variable: &Variable| matches!(variable.ty, Type::Function(_));
+                let Some(function) = self.variable(function) else {
+                    return (self.number(), PRIMARY);
+                };
+                let Type::Function(arity) = function.ty else {
+                    unreachable!();
+                };
+                let arguments: Vec<String> = (0..arity)
+                    .map(|_| self.expression(Type::Number, depth + 1).0)
+                    .collect();
+                (
+                    format!("{}({})", function.name, arguments.join(", ")),
+                    PRIMARY,
+                )
+            }
+            6 => {
+                let object = |variable: &Variable| variable.ty == Type::Instance;
+                match self.variable(object) {
+                    Some(object) if self.rng.chance(50) => (format!("{}.x", object.name), PRIMARY),
+                    Some(object) => (format!("{}.get()", object.name), PRIMARY),
+                    None => (self.number(), PRIMARY),
+                }
+            }
+            _ => {
+                // numbers are always truthy, so this is a number either way
+                let condition = self.operand(Type::Bool, depth, AND);
+                let then = self.operand(Type::Number, depth, EQUALITY);
+                let otherwise = self.operand(Type::Number, depth, AND);
+                (format!("{} and {} or {}", condition, then, otherwise), OR)
+            }
+        }
+    }
+
+    fn bool_expression(&mut self, depth: usize) -> (String, u8) {
+        match self.rng.below(4) {
+            0 => {
+                let ty = *self.rng.pick(&[Type::Number, Type::Bool, Type::String]);
+                (format!("!{}", self.operand(ty, depth, UNARY)), UNARY)
+            }
+            1 => {
+                let operator = self.rng.pick(&["<", "<=", ">", ">="]);
+                let left = self.operand(Type::Number, depth, COMPARISON);
+                let right = self.operand(Type::Number, depth, COMPARISON + 1);
+                (format!("{} {} {}", left, operator, right), COMPARISON)
+            }
+            2 => {
+                let ty = *self.rng.pick(&[Type::Number, Type::Bool, Type::String]);
+                let operator = self.rng.pick(&["==", "!="]);
+                let left = self.operand(ty, depth, EQUALITY);
+                let right = self.operand(ty, depth, EQUALITY + 1);
+                (format!("{} {} {}", left, operator, right), EQUALITY)
+            }
+            _ => match self.rng.chance(50) {
+                true => {
+                    let left = self.operand(Type::Bool, depth, AND);
+                    let right = self.operand(Type::Bool, depth, AND + 1);
+                    (format!("{} and {}", left, right), AND)
+                }
+                false => {
+                    let left = self.operand(Type::Bool, depth, OR);
+                    let right = self.operand(Type::Bool, depth, OR + 1);
+                    (format!("{} or {}", left, right), OR)
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,437 @@
+//! Entry points for fuzzing the scanner, compiler and `Vm`
+//!
+//! Each target takes arbitrary bytes and panics only when it finds a bug. They are driven by the
+//! cargo-fuzz crate in `fuzz/`, with `cargo fuzz run <target> fuzz/corpus/<target>`, and
+//! `tests/fuzz.rs` replays that corpus through them so they are checked without a fuzzer too.
+//! Inputs that find bugs belong in the corpus once they are fixed.
+
+use crate::bytecode;
+use crate::chunk::Chunk;
+use crate::diagnostic;
+use crate::differential;
+use crate::scanner::Scanner;
+use crate::scanner::TokenKind;
+use crate::vm::InterpretError;
+use crate::vm::Limits;
+use crate::vm::Vm;
+use crate::vm::VmOptions;
+
+/// How many instructions a fuzzed program may run, low enough that loops don't slow fuzzing
+const INSTRUCTIONS_MAX: u64 = 100_000;
+
+/// How many bytes of objects a fuzzed program may allocate
+const MEMORY_MAX: usize = 1024 * 1024;
+
+/// How deeply generated expressions and statements nest
+const DEPTH_MAX: usize = 4;
+
+/// Scans text to the end, checking that the tokens cover it in order
+pub fn scan(data: &[u8]) {
+    let Ok(source) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    let mut scanner = Scanner::new(source);
+    let mut end = 0;
+    let mut line = 1;
+    loop {
+        let token = scanner.scan_token();
+        assert!(
+            token.offset >= end,
+            "token at {} overlaps the one before",
+            token.offset
+        );
+        assert_eq!(
+            &source[token.offset..token.offset + token.span.len()],
+            token.span
+        );
+        assert!(
+            token.line >= line,
+            "token at {} goes back a line",
+            token.offset
+        );
+        end = token.offset + token.span.len();
+        line = token.line;
+
+        if token.kind == TokenKind::Eof {
+            assert_eq!(end, source.len(), "stopped before the end of the source");
+            return;
+        }
+        // otherwise the scanner could loop forever
+        assert!(!token.span.is_empty(), "empty token at {}", token.offset);
+    }
+}
+
+/// Compiles text, checking that errors come with diagnostics that can be shown and that whatever
+/// compiles is accepted by the loader and verifier
+pub fn compile(data: &[u8]) {
+    let Ok(source) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    match Vm::new().compile_bytecode(source) {
+        Ok(bytes) => check_run(limited_vm().interpret_bytecode(&bytes)),
+        Err(InterpretError::CompileError(diagnostics)) => {
+            assert!(!diagnostics.is_empty(), "compile error with no diagnostics");
+            diagnostic::render_all(&diagnostics, source);
+        }
+        Err(error) => panic!("unexpected error compiling: {}", error),
+    }
+}
+
+/// Runs bytecode, either a compiled `.lxc` file or the code of a bare chunk, checking that
+/// anything the loader and verifier accept runs without panicking
+pub fn execute(data: &[u8]) {
+    let mut vm = limited_vm();
+    let result = if bytecode::is_bytecode(data) {
+        vm.interpret_bytecode(data)
+    } else {
+        let chunk = bare_chunk(&mut vm, data);
+        vm.interpret_chunk(chunk)
+    };
+
+    match result {
+        Err(InterpretError::LoadError(_) | InterpretError::VerifyError(_)) => (),
+        // the verifier doesn't track the types on the stack, so instructions that need a class
+        // or closure check for themselves
+        Err(InterpretError::Ice(_)) => (),
+        result => check_run(result),
+    }
+}
+
+/// Generates a program from the bytes, checks it as `compile` does, then checks that the `Vm` and
+/// the reference interpreter agree on it
+pub fn grammar(data: &[u8]) {
+    let source = generate(data);
+    compile(source.as_bytes());
+
+    if let Some(mismatch) = differential::check(&source) {
+        panic!("the interpreters disagree\n{}", mismatch);
+    }
+}
+
+/// A `Vm` that stops long-running programs and doesn't touch stdin or stdout
+fn limited_vm() -> Vm {
+    let limits = Limits::default()
+        .instructions(INSTRUCTIONS_MAX)
+        .memory(MEMORY_MAX);
+    Vm::with_options(VmOptions::default().limits(limits))
+        .with_output(std::io::sink())
+        .with_input(std::io::empty())
+}
+
+/// Panics if running a program that was compiled or verified failed for any reason a script
+/// could cause
+fn check_run(result: Result<(), InterpretError>) {
+    match result {
+        Ok(())
+        | Err(InterpretError::RuntimeError(_))
+        | Err(InterpretError::LimitError(_))
+        | Err(InterpretError::Interrupted) => (),
+        Err(error) => panic!("unexpected error running: {}", error),
+    }
+}
+
+/// A chunk with `code` and a few constants of each kind for it to load
+fn bare_chunk(vm: &mut Vm, code: &[u8]) -> Chunk {
+    let mut chunk = Chunk::default();
+    for number in [0.0, 1.0, -2.5, f64::NAN] {
+        chunk.add_constant(number.into());
+    }
+    for text in ["", "a", "init"] {
+        let string = vm.new_string(text).value();
+        chunk.add_constant(string);
+    }
+    for byte in code {
+        chunk.write(*byte, 1);
+    }
+    chunk
+}
+
+/// Generates a syntactically valid program, making each choice with the next of `data`
+///
+/// `return`, `this` and `super` only appear where they are allowed, but unlike
+/// `differential::generate` nothing else stops the program from misusing its values or failing to
+/// compile, so it reaches the errors that one avoids. Once the bytes run out every choice is the
+/// first, which always ends the program.
+pub fn generate(data: &[u8]) -> String {
+    let mut generator = Grammar {
+        data,
+        source: String::new(),
+        depth: 0,
+        nesting: 0,
+        function: false,
+        class: None,
+    };
+    while !generator.data.is_empty() {
+        generator.declaration();
+    }
+    generator.source
+}
+
+const VARIABLES: &[&str] = &["a", "b", "c", "f", "A", "B"];
+const PROPERTIES: &[&str] = &["x", "y", "init", "m"];
+const NUMBERS: &[&str] = &["0", "1", "2", "10", "1.5"];
+const STRINGS: &[&str] = &["\"\"", "\"a\"", "\"b\\n\""];
+
+/// The operators of each binary precedence level, loosest first
+const BINARY: &[&[&str]] = &[
+    &["or"],
+    &["and"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+/// The precedence of assignments, the loosest kind of expression
+const ASSIGNMENT: usize = 0;
+/// The precedence of unary operators, just after the binary levels
+const UNARY: usize = BINARY.len() + 1;
+/// The precedence of calls and property accesses
+const CALL: usize = UNARY + 1;
+
+struct Grammar<'a> {
+    data: &'a [u8],
+    source: String,
+    /// How deeply the current statement is nested in blocks
+    depth: usize,
+    /// How deeply the current expression is nested in others
+    nesting: usize,
+    /// Whether the current statement is in a function, where it can return
+    function: bool,
+    /// Whether the class the current method is in has a superclass, `None` outside methods
+    class: Option<bool>,
+}
+
+impl Grammar<'_> {
+    /// Picks a number below `n` using the next byte, or 0 if there are none left
+    fn choose(&mut self, n: usize) -> usize {
+        match self.data.split_first() {
+            Some((byte, rest)) => {
+                self.data = rest;
+                *byte as usize % n
+            }
+            None => 0,
+        }
+    }
+
+    fn pick(&mut self, options: &[&'static str]) -> &'static str {
+        options[self.choose(options.len())]
+    }
+
+    /// Whether a nested statement should be generated, rather than only the simplest kind
+    fn can_nest(&self) -> bool {
+        self.depth < DEPTH_MAX && !self.data.is_empty()
+    }
+
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.depth {
+            self.source.push_str("    ");
+        }
+        self.source.push_str(text);
+        self.source.push('\n');
+    }
+
+    fn declaration(&mut self) {
+        match self.choose(8) {
+            0 if self.can_nest() => {
+                let name = self.pick(VARIABLES);
+                let params = self.params();
+                self.line(&format!("fun {}({}) {{", name, params));
+                let function = std::mem::replace(&mut self.function, true);
+                self.body();
+                self.function = function;
+            }
+            1 if self.can_nest() => self.class(),
+            2 => {
+                let name = self.pick(VARIABLES);
+                if self.choose(4) == 0 {
+                    self.line(&format!("var {};", name));
+                } else {
+                    let value = self.expression(ASSIGNMENT);
+                    self.line(&format!("var {} = {};", name, value));
+                }
+            }
+            _ => self.statement(),
+        }
+    }
+
+    fn class(&mut self) {
+        let name = self.pick(VARIABLES);
+        let has_superclass = self.choose(2) == 1;
+        if has_superclass {
+            let superclass = self.pick(VARIABLES);
+            self.line(&format!("class {} < {} {{", name, superclass));
+        } else {
+            self.line(&format!("class {} {{", name));
+        }
+
+        let function = std::mem::replace(&mut self.function, true);
+        let class = self.class.replace(has_superclass);
+        self.depth += 1;
+        for _ in 0..self.choose(4) {
+            let name = self.pick(PROPERTIES);
+            let params = self.params();
+            self.line(&format!("{}({}) {{", name, params));
+            self.body();
+        }
+        self.depth -= 1;
+        self.line("}");
+        self.function = function;
+        self.class = class;
+    }
+
+    fn params(&mut self) -> String {
+        let count = self.choose(4);
+        ["x", "y", "z"][..count].join(", ")
+    }
+
+    /// The declarations of a block or function after its opening line, and its closing brace
+    fn body(&mut self) {
+        self.depth += 1;
+        for _ in 0..self.choose(4) {
+            self.declaration();
+        }
+        self.depth -= 1;
+        self.line("}");
+    }
+
+    fn statement(&mut self) {
+        if !self.can_nest() {
+            let value = self.expression(ASSIGNMENT);
+            self.line(&format!("print {};", value));
+            return;
+        }
+
+        match self.choose(8) {
+            0 => {
+                let value = self.expression(ASSIGNMENT);
+                self.line(&format!("print {};", value));
+            }
+            1 => {
+                let value = self.expression(ASSIGNMENT);
+                self.line(&format!("{};", value));
+            }
+            2 => {
+                self.line("{");
+                self.body();
+            }
+            3 => {
+                let condition = self.expression(ASSIGNMENT);
+                self.line(&format!("if ({}) {{", condition));
+                self.body();
+                if self.choose(2) == 1 {
+                    self.line("else {");
+                    self.body();
+                }
+            }
+            4 => {
+                let condition = self.expression(ASSIGNMENT);
+                self.line(&format!("while ({}) {{", condition));
+                self.body();
+            }
+            5 => {
+                let initializer = match self.choose(3) {
+                    0 => String::new(),
+                    1 => format!(
+                        "var {} = {}",
+                        self.pick(VARIABLES),
+                        self.expression(ASSIGNMENT)
+                    ),
+                    _ => self.expression(ASSIGNMENT),
+                };
+                let condition = self.optional_expression();
+                let increment = self.optional_expression();
+                self.line(&format!(
+                    "for ({}; {}; {}) {{",
+                    initializer, condition, increment
+                ));
+                self.body();
+            }
+            6 if self.function => match self.optional_expression() {
+                value if value.is_empty() => self.line("return;"),
+                value => self.line(&format!("return {};", value)),
+            },
+            _ => {
+                let value = self.expression(ASSIGNMENT);
+                self.line(&format!("print {};", value));
+            }
+        }
+    }
+
+    fn optional_expression(&mut self) -> String {
+        match self.choose(3) {
+            0 => String::new(),
+            _ => self.expression(ASSIGNMENT),
+        }
+    }
+
+    /// An expression that binds at least as tightly as `precedence`, bracketed if it needs to be
+    fn expression(&mut self, precedence: usize) -> String {
+        if self.nesting >= DEPTH_MAX || self.data.is_empty() {
+            return self.primary();
+        }
+
+        self.nesting += 1;
+        let (text, own) = match self.choose(10) {
+            0 | 1 => (self.primary(), CALL),
+            2 => {
+                let operator = self.pick(&["-", "!"]);
+                (format!("{}{}", operator, self.expression(UNARY)), UNARY)
+            }
+            3 | 4 => {
+                let level = self.choose(BINARY.len());
+                let operator = self.pick(BINARY[level]);
+                let left = self.expression(level + 1);
+                let right = self.expression(level + 2);
+                (format!("{} {} {}", left, operator, right), level + 1)
+            }
+            5 => {
+                let callee = self.expression(CALL);
+                let arguments = (0..self.choose(3))
+                    .map(|_| self.expression(ASSIGNMENT))
+                    .collect::<Vec<_>>();
+                (format!("{}({})", callee, arguments.join(", ")), CALL)
+            }
+            6 => {
+                let object = self.expression(CALL);
+                (format!("{}.{}", object, self.pick(PROPERTIES)), CALL)
+            }
+            7 => {
+                let name = self.pick(VARIABLES);
+                (
+                    format!("{} = {}", name, self.expression(ASSIGNMENT)),
+                    ASSIGNMENT,
+                )
+            }
+            8 => {
+                let object = self.expression(CALL);
+                let name = self.pick(PROPERTIES);
+                let value = self.expression(ASSIGNMENT);
+                (format!("{}.{} = {}", object, name, value), ASSIGNMENT)
+            }
+            9 if self.class == Some(true) && self.choose(2) == 1 => {
+                (format!("super.{}", self.pick(PROPERTIES)), CALL)
+            }
+            9 if self.class.is_some() => ("this".to_string(), CALL),
+            _ => (self.primary(), CALL),
+        };
+        self.nesting -= 1;
+
+        if own < precedence {
+            format!("({})", text)
+        } else {
+            text
+        }
+    }
+
+    fn primary(&mut self) -> String {
+        match self.choose(5) {
+            0 => self.pick(NUMBERS).to_string(),
+            1 => self.pick(STRINGS).to_string(),
+            2 => self.pick(&["true", "false", "nil"]).to_string(),
+            _ => self.pick(VARIABLES).to_string(),
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use crate::table::Interner;
+use crate::table::Symbol;
+use crate::value::InternedString;
+use crate::value::Object;
+use crate::value::ObjectKind;
+use crate::value::Upvalue;
+use crate::value::Value;
+
+use std::collections::HashMap;
+use std::ptr::NonNull;
+
+/// How much the heap may grow after a collection before the next one
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+/// The number of bytes allocated before the first collection
+const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+
+/// A handle to an object owned by a `Heap`
+///
+/// Handles are only valid as long as the object is reachable from the roots of the `Vm` that
+/// owns the heap, and nothing ties a handle to that, so reading the object through one is
+/// unsafe. Equality compares object identity.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ObjRef(NonNull<Object>);
+
+impl ObjRef {
+    pub fn as_ptr(&self) -> *const Object {
+        self.0.as_ptr()
+    }
+
+    /// Rebuilds a handle from a pointer previously taken with `as_ptr`
+    ///
+    /// # Safety
+    ///
+    /// The pointer must have come from a handle to an object that is still on the heap.
+    #[cfg_attr(not(feature = "nan-boxing"), allow(dead_code))]
+    pub(crate) unsafe fn from_ptr(pointer: *const Object) -> ObjRef {
+        ObjRef(NonNull::new_unchecked(pointer as *mut Object))
+    }
+
+    /// The object, borrowed for as long as the caller needs it rather than as long as the handle
+    ///
+    /// # Safety
+    ///
+    /// The object must stay on the heap for as long as the reference is used: it must be
+    /// reachable from the roots of the `Vm` that owns it, or the `Vm` must not collect garbage,
+    /// until then.
+    pub(crate) unsafe fn get<'a>(self) -> &'a Object {
+        self.0.as_ref()
+    }
+}
+
+impl std::fmt::Pointer for ObjRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:p}", self.0)
+    }
+}
+
+/// Owns every object allocated by the compiler and the `Vm`, and frees them with a tracing
+/// mark-and-sweep collector
+pub struct Heap {
+    objects: Vec<ObjRef>,
+    interner: Interner,
+    /// The string object for each live symbol
+    ///
+    /// Strings are held weakly: they are removed when swept, so anything keyed by a symbol must
+    /// mark it to keep it alive.
+    strings: HashMap<Symbol, ObjRef>,
+    /// Marked objects whose references have not been traced yet
+    gray: Vec<ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    /// Collect on every allocation, to shake out objects that aren't properly rooted
+    stress: bool,
+}
+
+impl Default for Heap {
+    fn default() -> Heap {
+        Heap {
+            objects: vec![],
+            interner: Interner::default(),
+            strings: HashMap::new(),
+            gray: vec![],
+            bytes_allocated: 0,
+            next_gc: GC_INITIAL_THRESHOLD,
+            stress: false,
+        }
+    }
+}
+
+impl Heap {
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn objects(&self) -> impl Iterator<Item = ObjRef> + '_ {
+        self.objects.iter().copied()
+    }
+
+    /// Whether the next allocation should be preceded by a collection
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    /// Accounts for an object on the heap having grown since it was allocated
+    pub fn grow(&mut self, bytes: usize) {
+        self.bytes_allocated += bytes;
+    }
+
+    /// Moves an object onto the heap
+    ///
+    /// This never collects, so callers that may need a collection first must check
+    /// `should_collect` with their roots at hand.
+    pub fn alloc<T: Into<Object>>(&mut self, object: T) -> ObjRef {
+        let object = object.into();
+        self.bytes_allocated += object.size();
+
+        let pointer = NonNull::from(Box::leak(Box::new(object)));
+        let object = ObjRef(pointer);
+        self.objects.push(object);
+        object
+    }
+
+    /// Returns the string object with the given text, allocating it if there isn't one yet
+    ///
+    /// Like `alloc`, this never collects.
+    pub fn intern(&mut self, text: &str) -> ObjRef {
+        let symbol = self.intern_symbol(text);
+        self.strings[&symbol]
+    }
+
+    /// Interns a string as `intern` does, returning its symbol rather than the string object
+    pub fn intern_symbol(&mut self, text: &str) -> Symbol {
+        if let Some(symbol) = self.interner.lookup(text) {
+            return symbol;
+        }
+
+        let symbol = self.interner.intern_string(text);
+        let text = self.interner.resolve(symbol).unwrap().clone();
+        let string = self.alloc(InternedString::new(symbol, text));
+        self.strings.insert(symbol, string);
+        symbol
+    }
+
+    /// The text of a live string
+    pub fn resolve(&self, symbol: Symbol) -> Option<&str> {
+        self.interner.resolve(symbol).map(|text| &**text)
+    }
+
+    pub fn mark_symbol(&mut self, symbol: Symbol) {
+        if let Some(string) = self.strings.get(&symbol) {
+            self.mark_object(*string);
+        }
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Some(object) = value.as_object() {
+            self.mark_object(object);
+        }
+    }
+
+    pub fn mark_object(&mut self, object: ObjRef) {
+        // SAFETY: only objects reachable from the roots are marked, and those haven't been freed
+        if unsafe { object.get() }.marked.replace(true) {
+            return;
+        }
+
+        self.gray.push(object);
+    }
+
+    /// Marks everything reachable from the objects marked so far
+    pub fn trace_references(&mut self) {
+        while let Some(object) = self.gray.pop() {
+            self.blacken(object);
+        }
+    }
+
+    fn blacken(&mut self, object: ObjRef) {
+        // SAFETY: gray objects have been marked, so they are still on the heap
+        match &unsafe { object.get() }.kind {
+            ObjectKind::String(_) | ObjectKind::Native(_) => (),
+            ObjectKind::Function(function) => {
+                for constant in function.chunk.constants() {
+                    self.mark_value(*constant);
+                }
+            }
+            ObjectKind::Closure(closure) => {
+                self.mark_object(closure.function);
+                for upvalue in closure.upvalues.iter() {
+                    self.mark_object(*upvalue);
+                }
+            }
+            ObjectKind::Upvalue(upvalue) => {
+                // open upvalues point into the stack, which is a root
+                if let Upvalue::Closed(value) = &*upvalue.borrow() {
+                    self.mark_value(*value);
+                }
+            }
+            ObjectKind::Class(class) => {
+                self.mark_object(class.name);
+                for (name, method) in class.methods.borrow().iter() {
+                    self.mark_symbol(*name);
+                    self.mark_object(*method);
+                }
+            }
+            ObjectKind::Instance(instance) => {
+                self.mark_object(instance.class);
+                for (name, value) in instance.fields.borrow().iter() {
+                    self.mark_symbol(*name);
+                    self.mark_value(*value);
+                }
+            }
+            ObjectKind::BoundMethod(bound) => {
+                self.mark_value(bound.receiver);
+                self.mark_object(bound.method);
+            }
+        }
+    }
+
+    /// Frees every unmarked object and clears the marks of the rest
+    pub fn sweep(&mut self) {
+        let mut freed = 0;
+
+        self.objects.retain(|handle| {
+            // SAFETY: every object in the list is on the heap until it is freed below
+            let object = unsafe { handle.get() };
+            if object.marked.replace(false) {
+                return true;
+            }
+
+            freed += object.size();
+
+            if let Some(string) = object.as_string() {
+                self.interner.remove(string.symbol);
+                self.strings.remove(&string.symbol);
+            }
+
+            // SAFETY: the object is unreachable, so no handle to it will be dereferenced again
+            drop(unsafe { Box::from_raw(handle.0.as_ptr()) });
+            false
+        });
+
+        self.bytes_allocated -= freed;
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(GC_INITIAL_THRESHOLD);
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for object in self.objects.drain(..) {
+            // SAFETY: the heap is going away, along with the `Vm` that could reach its objects
+            drop(unsafe { Box::from_raw(object.0.as_ptr()) });
+        }
+    }
+}
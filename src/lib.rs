@@ -1,45 +1,194 @@
 mod compiler;
 mod debug;
+mod heap;
+mod native;
+mod optimize;
+mod parser;
 mod scanner;
-//~ mod table;
+mod table;
 mod value;
 
 pub mod arg;
+pub mod asm;
+pub mod ast;
+pub mod bytecode;
 pub mod chunk;
+pub mod conformance;
+pub mod diagnostic;
+pub mod differential;
+pub mod fuzz;
+pub mod reference;
+pub mod repl;
+pub mod verify;
 pub mod vm;
 
-pub use compiler::compile;
+pub use optimize::OptLevel;
+pub use parser::parse;
+pub use value::NativeFn;
+pub use value::Value;
+pub use value::ValueRef;
 
 use chunk::Chunk;
 use chunk::OpCode;
 
-pub fn run_file(path: std::path::PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+pub fn run_file(
+    path: std::path::PathBuf,
+    options: vm::VmOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let bytes = std::fs::read(path)?;
+    let mut vm = vm::Vm::with_options(options);
+
+    if bytecode::is_bytecode(&bytes) {
+        return Ok(vm.interpret_bytecode(&bytes)?);
+    }
+
+    let source = String::from_utf8(bytes)?;
+    vm.interpret(&source)
+        .inspect_err(|error| report_diagnostics(error, &source))?;
+    Ok(())
+}
+
+/// Prints any diagnostics in `error` to stderr, with the parts of `source` they point at
+pub fn report_diagnostics(error: &vm::InterpretError, source: &str) {
+    if let vm::InterpretError::CompileError(diagnostics) = error {
+        eprint!("{}", diagnostic::render_all(diagnostics, source));
+    }
+}
+
+/// Assembles a listing and runs it, or writes it out as bytecode if there is an `output` path
+pub fn assemble_file(
+    path: &std::path::Path,
+    output: Option<&std::path::Path>,
+    options: vm::VmOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let listing = std::fs::read_to_string(path)?;
+    let mut vm = vm::Vm::with_options(options);
+
+    match output {
+        Some(output) => std::fs::write(output, vm.assemble_bytecode(&listing)?)?,
+        None => vm.interpret_assembly(&listing)?,
+    }
+
+    Ok(())
+}
+
+/// Compiles a script to bytecode that `run_file` can run
+pub fn compile_file(
+    path: &std::path::Path,
+    output: &std::path::Path,
+    options: vm::VmOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let source = std::fs::read_to_string(path)?;
+    let mut vm = vm::Vm::with_options(options);
+    let bytes = vm
+        .compile_bytecode(&source)
+        .inspect_err(|error| report_diagnostics(error, &source))?;
+    std::fs::write(output, bytes)?;
+    Ok(())
+}
+
+/// Parses a script, printing its syntax tree if `dump_ast` is set
+pub fn parse_file(
+    path: &std::path::Path,
+    dump_ast: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
     let source = std::fs::read_to_string(path)?;
-    Ok(run_string(&source)?)
+    let program = parse(&source).inspect_err(|error| report_diagnostics(error, &source))?;
+    if dump_ast {
+        print!("{}", program);
+    }
+    Ok(())
+}
+
+/// Runs the conformance tests under `dir` on `jobs` threads, printing every failure, and fails
+/// if any test did
+pub fn test_dir(
+    dir: &std::path::Path,
+    filter: Option<&str>,
+    jobs: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tests = conformance::find_tests(dir, filter)?;
+    let outcomes = conformance::run_all(&tests, jobs);
+
+    let failed: Vec<_> = outcomes.iter().filter(|outcome| !outcome.passed()).collect();
+    for outcome in &failed {
+        println!("FAIL {}", outcome.path.display());
+        for failure in &outcome.failures {
+            for line in failure.lines() {
+                println!("    {}", line);
+            }
+        }
+    }
+    println!("{} passed, {} failed", tests.len() - failed.len(), failed.len());
+
+    if !failed.is_empty() {
+        return Err(format!("{} of {} tests failed", failed.len(), tests.len()).into());
+    }
+    Ok(())
+}
+
+/// Runs the `.lox` scripts under `paths`, then `random` generated programs starting from
+/// `seed`, through both the `Vm` and the reference interpreter, printing a reproducer for every
+/// program they disagree about
+pub fn compare_interpreters(
+    paths: &[std::path::PathBuf],
+    random: usize,
+    seed: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut programs = vec![];
+    for path in paths {
+        let scripts = match path.is_dir() {
+            true => conformance::find_tests(path, None)?,
+            false => vec![path.clone()],
+        };
+        for script in scripts {
+            let source = std::fs::read_to_string(&script)?;
+            programs.push((script.display().to_string(), source));
+        }
+    }
+    for seed in (0..random as u64).map(|i| seed.wrapping_add(i)) {
+        let name = format!("random program (--seed {})", seed);
+        programs.push((name, differential::generate(seed)));
+    }
+
+    let mut mismatched = 0;
+    for (name, source) in &programs {
+        if let Some(mismatch) = differential::check(source) {
+            mismatched += 1;
+            println!("MISMATCH {}", name);
+            for line in mismatch.to_string().lines() {
+                println!("    {}", line);
+            }
+        }
+    }
+    println!("{} agreed, {} mismatched", programs.len() - mismatched, mismatched);
+
+    if mismatched > 0 {
+        return Err(format!("{} of {} programs mismatched", mismatched, programs.len()).into());
+    }
+    Ok(())
 }
 
 pub fn run_string(source: &str) -> Result<(), vm::InterpretError> {
-    let mut vm = vm::Vm::new(Box::default());
+    let mut vm = vm::Vm::new();
 
     vm.interpret(source)
 }
 
+/// Runs a script, returning what it printed along with how it finished
+///
+/// The output is returned even if the script fails partway through.
+pub fn run_string_captured(source: &str) -> (String, Result<(), vm::InterpretError>) {
+    let output = vm::OutputBuffer::default();
+    let mut vm = vm::Vm::new().with_output(output.clone());
+
+    let result = vm.interpret(source);
+    (output.contents(), result)
+}
+
 pub fn run_test() {
     let mut chunk = Chunk::default();
 
-    /*
-    let line = 123;
-    for i in 0..=256 {
-        chunk.write_constant((i * 1000 + 100).into(), line + i as usize);
-        chunk.write(OpCode::Negate as u8, line + i as usize);
-        chunk.write(OpCode::Return as u8, line + i as usize);
-    }
-    chunk.write(OpCode::Return as u8, line);
-    chunk.write(OpCode::Return as u8, line + 3);
-    chunk.write(OpCode::Return as u8, line + 4);
-    chunk.disassemble("test chunk");
-    */
-
     chunk.write_constant(1.2.into(), 1);
     chunk.write_constant(3.4.into(), 1);
     chunk.write(OpCode::Add as u8, 1);
@@ -48,17 +197,846 @@ pub fn run_test() {
     chunk.write(OpCode::Negate as u8, 1);
     chunk.write(OpCode::Return as u8, 1);
 
-    let mut vm = vm::Vm::new(Box::new(chunk));
+    let mut vm = vm::Vm::new();
 
-    vm.run().unwrap();
+    vm.interpret_chunk(chunk).unwrap();
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Runs a script, failing the test if the script fails, and returns what it printed
+    fn printed(source: &str) -> String {
+        let (output, result) = run_string_captured(source);
+        if let Err(error) = result {
+            panic!("{} after printing {:?}", error, output);
+        }
+        output
+    }
+
+    /// A `Vm` whose output is kept rather than printed
+    fn captured_vm(options: vm::VmOptions) -> (vm::Vm, vm::OutputBuffer) {
+        let output = vm::OutputBuffer::default();
+        let vm = vm::Vm::with_options(options).with_output(output.clone());
+        (vm, output)
+    }
+
     #[test]
     fn test_run() {
         run_test();
     }
+
+    #[test]
+    fn test_block_scope() {
+        let source = "var a = 1; { var c = a + 1; print c; { var b = c; c = b * 2; } print c; }";
+        assert_eq!(printed(source), "2\n4\n");
+        assert!(run_string("{ var a = 1; var a = 2; }").is_err());
+        assert!(run_string("{ var a = a; }").is_err());
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let source = "for (var i = 0; i < 3; i = i + 1) { if (i != 1 and true) print i; else print nil or i; }";
+        assert_eq!(printed(source), "0\n1\n2\n");
+        run_string("var i = 3; while (i > 0) i = i - 1; for (;;) { if (i == 0) i = nil; i = -i; }").unwrap_err();
+    }
+
+    #[test]
+    fn test_functions() {
+        let source =
+            "fun fib(n) { if (n < 2) return n; return fib(n - 2) + fib(n - 1); } print fib(10);";
+        assert_eq!(printed(source), "55\n");
+        assert!(run_string("fun f(a, b) {} f(1);").is_err());
+        assert!(run_string("fun f() { f(); } f();").is_err());
+        assert!(run_string("var x = 1; x();").is_err());
+        assert!(run_string("return 1;").is_err());
+    }
+
+    #[test]
+    fn test_closures() {
+        let source =
+            "fun counter() { var i = 0; fun count() { i = i + 1; return i; } return count; }
+            var c = counter(); c(); print c();";
+        assert_eq!(printed(source), "2\n");
+        let source =
+            "var get; { var a = 1; fun set() { a = 2; } fun g() { return a; } set(); get = g; }
+            print get();";
+        assert_eq!(printed(source), "2\n");
+    }
+
+    #[test]
+    fn test_classes() {
+        let source = "class Pair { init(a, b) { this.a = a; this.b = b; } sum() { return this.a + this.b; } }
+            var p = Pair(1, 2); var sum = p.sum; p.b = 3;
+            print sum(); print p.init(5, 5).sum();";
+        assert_eq!(printed(source), "4\n10\n");
+        assert!(run_string("print this;").is_err());
+        assert!(run_string("class A { init() { return 1; } }").is_err());
+        assert!(run_string("class A {} A().missing;").is_err());
+        assert!(run_string("var a = 1; a.field = 2;").is_err());
+    }
+
+    #[test]
+    fn test_inheritance() {
+        let source = "class A { init(n) { this.n = n; } get() { return this.n; } }
+            class B < A { get() { return super.get() + 1; } bound() { return super.get; } }
+            var b = B(1);
+            print b.get(); print b.bound()();";
+        assert_eq!(printed(source), "2\n1\n");
+        assert!(run_string("class A < A {}").is_err());
+        assert!(run_string("var A = 1; class B < A {}").is_err());
+        assert!(run_string("class A { f() { super.f(); } }").is_err());
+        assert!(run_string("super.f();").is_err());
+    }
+
+    #[test]
+    fn test_interned_strings() {
+        let source = "var a = \"ab\"; var b = \"a\" + \"b\";
+            class C {} var c = C(); c.ab = 1;
+            print a == b; print c.ab;";
+        assert_eq!(printed(source), "true\n1\n");
+        let source = "var s = \"\"; for (var i = 0; i < 10; i = i + 1) s = s + \"x\"; print s == \"xxxxxxxxxx\";";
+        assert_eq!(printed_stressed(source), "true\n");
+    }
+
+    #[test]
+    fn test_global_slots() {
+        let (mut vm, output) = captured_vm(vm::VmOptions::default());
+        assert!(vm.interpret("print later;").is_err());
+        assert!(vm.interpret("later = 1;").is_err());
+        vm.interpret("var later = 1; fun f() { return later; }").unwrap();
+        vm.interpret("later = later + f(); print later;").unwrap();
+        assert_eq!(output.contents(), "2\n");
+    }
+
+    /// Runs a script with a collection before every allocation, returning what it printed
+    fn printed_stressed(source: &str) -> String {
+        let (mut vm, output) = captured_vm(vm::VmOptions::default());
+        vm.set_gc_stress(true);
+        if let Err(error) = vm.interpret(source) {
+            panic!("{} after printing {:?}", error, output.contents());
+        }
+        output.contents()
+    }
+
+    #[test]
+    fn test_gc_stress() {
+        let source = "class A { init(n) { this.n = n; } get() { return this.n; } }
+            class B < A { get() { return super.get() + \"!\"; } }
+            fun counter() { var i = 0; fun count() { i = i + 1; return i; } return count; }
+            var c = counter();
+            var s = \"\";
+            for (var i = 0; i < 20; i = i + 1) { s = s + \"x\"; c(); }
+            print B(s).get() == s + \"!\"; print c();";
+        assert_eq!(printed_stressed(source), "true\n21\n");
+    }
+
+    #[test]
+    fn test_gc_collects_cycles() {
+        let mut vm = vm::Vm::new();
+        vm.interpret(
+            "class Node {}
+            for (var i = 0; i < 100; i = i + 1) { var a = Node(); var b = Node(); a.b = b; b.a = a; }",
+        )
+        .unwrap();
+
+        let before = vm.object_count();
+        vm.collect_garbage();
+        assert!(vm.object_count() < before - 100);
+    }
+
+    #[test]
+    fn test_value_representation() {
+        use value::Value;
+
+        let mut heap = heap::Heap::default();
+        let string: Value = heap.intern("text").into();
+
+        assert!(Value::NIL.is_nil() && !Value::NIL.truthiness());
+        assert_eq!(Value::from(false).as_bool(), Some(false));
+        assert!(Value::from(true).truthiness() && Value::from(0.0).truthiness());
+        assert_eq!(Value::from(-0.0).as_number(), Some(-0.0));
+        assert_eq!(Value::from(f64::INFINITY).as_number(), Some(f64::INFINITY));
+        assert!(Value::from(f64::NAN).as_number().unwrap().is_nan());
+        assert!(Value::from(f64::NAN) != Value::from(f64::NAN));
+        assert_eq!(string.as_string(), Some("text"));
+        assert!(string == heap.intern("text").into() && string != Value::NIL);
+        assert_eq!(Value::from(1.5).to_string(), "1.5");
+        assert_eq!(String::try_from(string).unwrap(), "text");
+        assert!(f64::try_from(string).is_err());
+    }
+
+    #[test]
+    fn test_debug_options() {
+        let options = vm::VmOptions::default()
+            .trace(true)
+            .dump_stack(true)
+            .dump_heap(true)
+            .disassemble(true);
+        let trace = vm::OutputBuffer::default();
+        let (vm, output) = captured_vm(options);
+        let mut vm = vm.with_debug_output(trace.clone());
+        vm.interpret("fun f(a) { return a * 2; } print f(21);").unwrap();
+        assert_eq!(output.contents(), "42\n");
+        assert!(trace.contents().contains("OP_MULTIPLY"));
+    }
+
+    #[test]
+    fn test_bytecode_round_trip() {
+        let source = "var a = 1; var b = 2;
+            class A { init(n) { this.n = n; } get() { return this.n + a; } }
+            fun adder(x) { fun add(y) { return x + y; } return add; }
+            print A(b).get(); print adder(40)(b); print \"s\" + \"t\";";
+        let bytes = vm::Vm::new().compile_bytecode(source).unwrap();
+
+        // global slots are renumbered to the loading vm's
+        let (mut vm, output) = captured_vm(vm::VmOptions::default());
+        vm.interpret("var c = 5; var b = 100;").unwrap();
+        vm.interpret_bytecode(&bytes).unwrap();
+        vm.interpret("print a; print b; print c;").unwrap();
+        assert_eq!(output.contents(), "3\n42\nst\n1\n2\n5\n");
+    }
+
+    #[test]
+    fn test_bytecode_rejects_corruption() {
+        let bytes = vm::Vm::new()
+            .compile_bytecode("var a = \"x\"; fun f(n) { return n + a; } print f(\"y\");")
+            .unwrap();
+
+        for len in 0..bytes.len() {
+            assert!(vm::Vm::new().interpret_bytecode(&bytes[..len]).is_err());
+        }
+
+        // loading corrupted files may fail, but must not panic
+        for i in 0..bytes.len() {
+            for byte in [0, 1, 0x7f, 0xff] {
+                let mut corrupted = bytes.clone();
+                corrupted[i] = byte;
+                let mut heap = heap::Heap::default();
+                let mut globals = table::Globals::default();
+                let _ = bytecode::read(&corrupted, &mut heap, &mut globals);
+            }
+        }
+
+        let mut chunk = Chunk::default();
+        chunk.write(OpCode::Constant as u8, 1);
+        chunk.write(3, 1);
+        chunk.write(OpCode::Return as u8, 1);
+        let function = value::Function {
+            arity: 0,
+            upvalue_count: 0,
+            chunk,
+            name: None,
+        };
+        let heap = heap::Heap::default();
+        let bytes = bytecode::write(&function, &table::Globals::default(), &heap);
+        let error = vm::Vm::new().interpret_bytecode(&bytes).unwrap_err();
+        assert!(error.to_string().contains("constant 3 is out of range"));
+
+        // functions are loaded and verified recursively, so how deeply they nest is limited
+        let mut heap = heap::Heap::default();
+        let mut function = value::Function {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::default(),
+            name: None,
+        };
+        for _ in 0..verify::NESTING_MAX {
+            let mut chunk = Chunk::default();
+            chunk.add_constant(heap.alloc(function).into());
+            chunk.write(OpCode::Nil as u8, 1);
+            chunk.write(OpCode::Return as u8, 1);
+            function = value::Function {
+                arity: 0,
+                upvalue_count: 0,
+                chunk,
+                name: None,
+            };
+        }
+        let error = verify::verify(&function, 0).unwrap_err();
+        assert!(error.message.contains("nested too deeply"));
+        let bytes = bytecode::write(&function, &table::Globals::default(), &heap);
+        let error = vm::Vm::new().interpret_bytecode(&bytes).unwrap_err();
+        assert!(error.to_string().contains("nested too deeply"));
+    }
+
+    #[test]
+    fn test_verifier() {
+        fn verify(code: &[u8]) -> Result<(), String> {
+            let mut chunk = Chunk::default();
+            chunk.add_constant(1.0.into());
+            for byte in code {
+                chunk.write(*byte, 1);
+            }
+            vm::Vm::new()
+                .interpret_chunk(chunk)
+                .map_err(|e| e.to_string())
+        }
+
+        use OpCode::*;
+        let constant = Constant as u8;
+        let nil = Nil as u8;
+        let ret = Return as u8;
+
+        verify(&[constant, 0, constant, 0, Add as u8, ret]).unwrap();
+        let rejected = [
+            (vec![0xff], "unknown opcode"),
+            (vec![constant], "cut off"),
+            (vec![constant, 1, ret], "constant 1 is out of range"),
+            (vec![GetGlobal as u8, 9, ret], "global slot 9 is out of range"),
+            (vec![GetLocal as u8, 1, ret], "local slot 1 is out of range"),
+            (vec![constant, 0, Add as u8, ret], "pops more values"),
+            (vec![nil, Pop as u8], "runs off the end"),
+            (vec![Jump as u8, 0, 1, constant, 0, ret], "not an instruction"),
+            (vec![nil, JumpIfFalse as u8, 0, 2, nil, nil, ret], "stack depth is"),
+        ];
+        for (code, message) in rejected {
+            let error = verify(&code).unwrap_err();
+            assert!(error.contains(message), "{:?}: {}", code, error);
+        }
+    }
+
+    #[test]
+    fn test_assembler_round_trip() {
+        let source = "var greeting = \"tab\\t\\\"quoted\\\"\";
+            class A { init(n) { this.n = n; } get() { return this.n; } }
+            class B < A { get() { return super.get() * 2; } }
+            fun make() { var x = 0; fun add(y) { x = x + y; return x; } return add; }
+            var f = make();
+            for (var i = 0; i < 3; i = i + 1) f(B(i).get());
+            print f(0); print greeting;";
+
+        let listing = vm::Vm::new().disassemble(source).unwrap();
+        let compiled = vm::Vm::new().compile_bytecode(source).unwrap();
+        let assembled = vm::Vm::new().assemble_bytecode(&listing).unwrap();
+        assert!(compiled == assembled, "{}", listing);
+
+        let (mut vm, output) = captured_vm(vm::VmOptions::default());
+        vm.interpret_assembly(&listing).unwrap();
+        assert_eq!(output.contents(), "6\ntab\t\"quoted\"\n");
+    }
+
+    #[test]
+    fn test_optimize() {
+        let source = "var x = 2;\nprint -(1 + 2) * x;\n\"unused\";\nprint x >= 2 and x != 0/0;
+            print x <=\n  -1.5;";
+        let run = |opt_level| {
+            let output = vm::OutputBuffer::default();
+            let options = vm::VmOptions::default().opt_level(opt_level);
+            let mut vm = vm::Vm::with_options(options).with_output(output.clone());
+            let listing = vm.disassemble(source).unwrap();
+            vm.interpret(source).unwrap();
+            (listing, output.contents())
+        };
+
+        let (unoptimized, expected) = run(OptLevel::O0);
+        let (listing, output) = run(OptLevel::O1);
+        assert_eq!(output, expected);
+        assert!(unoptimized.contains("OP_NOT\n") && unoptimized.contains("unused"));
+        assert!(!listing.contains("OP_NOT\n"), "{}", listing);
+        assert!(!listing.contains("unused"), "{}", listing);
+        for fused in ["OP_NOT_EQUAL", "OP_GREATER_EQUAL", "OP_LESS_EQUAL"] {
+            assert!(listing.contains(fused), "{}", listing);
+        }
+
+        // folded constants are attributed to their last token, and equal ones share a slot
+        for line in [
+            "0004 0002 OP_CONSTANT      1 -3",
+            "0012    | OP_CONSTANT      0 2",
+            "0027 0006 OP_CONSTANT      3 -1.5",
+        ] {
+            assert!(listing.contains(line), "{}", listing);
+        }
+
+        let options = vm::VmOptions::default().opt_level(OptLevel::O1);
+        let error = vm::Vm::with_options(options).interpret("print \"a\" >=\n  1;");
+        match error {
+            Err(vm::InterpretError::RuntimeError(e)) => assert_eq!(e.line(), Some(2)),
+            result => panic!("expected a runtime error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_hand_written_assembly() {
+        let listing = "
+            ; counts down from 3
+                OP_CONSTANT 3
+                OP_DEFINE_GLOBAL count
+            loop:
+                OP_GET_GLOBAL count
+                OP_PRINT
+            2   OP_GET_GLOBAL count
+                OP_CONSTANT 1
+                OP_SUBTRACT
+                OP_SET_GLOBAL count
+                OP_CONSTANT 0
+                OP_GREATER
+                OP_JUMP_IF_FALSE -> done
+                OP_POP
+                OP_LOOP -> loop
+            done:
+                OP_POP
+                OP_NIL
+                OP_RETURN";
+        let (mut vm, output) = captured_vm(vm::VmOptions::default());
+        vm.interpret_assembly(listing).unwrap();
+        assert_eq!(output.contents(), "3\n2\n1\n");
+
+        let error = |listing: &str| match vm::Vm::new().interpret_assembly(listing) {
+            Err(vm::InterpretError::AsmError(e)) => (e.line, e.message),
+            result => panic!("expected an assembly error, got {:?}", result),
+        };
+        assert_eq!(error("OP_NIL\nOP_JUMP -> nowhere").0, 2);
+        assert!(error("OP_FROB").1.contains("unknown instruction"));
+        assert!(error("OP_CONSTANT 0 1\nOP_CONSTANT 0 2").1.contains("already defined"));
+        assert!(error("OP_CLOSURE <fn f>\nOP_RETURN").1.contains("missing the section"));
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let diagnostics = |source: &str| match run_string(source) {
+            Err(vm::InterpretError::CompileError(diagnostics)) => diagnostics,
+            result => panic!("expected a compile error, got {:?}", result),
+        };
+
+        let source = "var s = \"é\";\n{\n  var a = 1; var a = 2;\n}\nprint 1 +;";
+        let found = diagnostics(source);
+        assert_eq!(found.len(), 2);
+        assert_eq!((found[0].line, found[0].column), (3, 18));
+        assert_eq!(&source[found[0].primary.span.start..found[0].primary.span.end], "a");
+        assert_eq!(found[0].secondary[0].span.location(source), (3, 7));
+        assert_eq!((found[1].line, found[1].column), (5, 10));
+
+        let rendered = found[1].render(source);
+        assert!(rendered.starts_with("error: Expect expression.\n"));
+        assert!(rendered.contains("5 | print 1 +;\n  |          ^\n"));
+
+        // columns count characters rather than bytes
+        let found = diagnostics("print \"héllo\" $;");
+        assert_eq!(found[0].column, 15);
+    }
+
+    #[test]
+    fn test_parse() {
+        use ast::ExprKind;
+        use ast::StmtKind;
+
+        let source = "print a.b(1) + -2;";
+        let program = parse(source).unwrap();
+        let StmtKind::Print(expr) = &program.statements[0].kind else {
+            panic!("expected a print statement, got {:?}", program.statements[0]);
+        };
+        let ExprKind::Binary { left, operator, .. } = &expr.kind else {
+            panic!("expected a binary expression, got {:?}", expr);
+        };
+        assert_eq!(operator.kind, ast::BinaryOp::Add);
+        assert_eq!(&source[left.span.start..left.span.end], "a.b(1)");
+        assert!(matches!(&left.kind, ExprKind::Call { callee, .. }
+            if matches!(&callee.kind, ExprKind::Get { name, .. } if name.name == "b")));
+
+        let dump = parse("var x = (1);\nwhile (x) x = nil;")
+            .unwrap()
+            .to_string();
+        let expected = "Var x 0..12
+  Grouping 8..11
+    Literal 1 9..10
+While 13..31
+  condition: Variable x 20..21
+  body: Expression 23..31
+    Assign x 23..30
+      Literal nil 27..30
+";
+        assert_eq!(dump, expected);
+
+        // only syntax errors are found by parsing
+        assert!(parse("print this;").is_ok());
+        assert!(matches!(
+            run_string("print this;"),
+            Err(vm::InterpretError::CompileError(_))
+        ));
+        match parse("print (1;\nprint 2 +;") {
+            Err(vm::InterpretError::CompileError(diagnostics)) => assert_eq!(diagnostics.len(), 2),
+            result => panic!("expected a compile error, got {:?}", result),
+        }
+
+        // an operator's code is on the line of its last operand, or where a string operand starts
+        for (source, line) in [("print 1 +\n  nil;", 2), ("print 1 +\n  \"a\nb\";", 2)] {
+            match run_string(source) {
+                Err(vm::InterpretError::RuntimeError(e)) => assert_eq!(e.line(), Some(line)),
+                result => panic!("expected a runtime error, got {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let nested = |open: &str, close: &str, depth: usize, inner: &str| {
+            format!("{}{}{}", open.repeat(depth), inner, close.repeat(depth))
+        };
+        let error = |source: &str| match run_string(source) {
+            Err(vm::InterpretError::CompileError(diagnostics)) => diagnostics[0].message.clone(),
+            result => panic!("expected a compile error, got {:?}", result),
+        };
+
+        // the innermost print statement and its operand take a level each
+        let source = format!("print {};", nested("(", ")", 254, "1"));
+        assert_eq!(printed(&source), "1\n");
+        let source = format!("print {};", nested("-", "", 254, "1"));
+        assert_eq!(printed(&source), "1\n");
+        let source = nested("{", "}", 254, "print 1;");
+        assert_eq!(printed(&source), "1\n");
+        let source = nested("fun f() {", "}", 128, "print 1;");
+        assert_eq!(printed(&source), "");
+
+        for source in [
+            format!("print {};", nested("(", ")", 5000, "1")),
+            format!("print {};", nested("-", "", 100_000, "1")),
+            format!("var a; {};", nested("a = ", "", 5000, "1")),
+            format!("print {};", nested("1 + (", ")", 5000, "1")),
+        ] {
+            assert_eq!(error(&source), "Expression nests too deeply.");
+        }
+        for source in [
+            nested("{", "}", 20_000, ""),
+            nested("fun f() {", "}", 5000, ""),
+        ] {
+            assert_eq!(error(&source), "Statement nests too deeply.");
+        }
+        // the condition is an expression one level inside the `if`, so it reaches the limit first
+        let source = nested("if (true) ", "", 20_000, "print 1;");
+        assert_eq!(error(&source), "Expression nests too deeply.");
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let error = |source: &str| match run_string(source) {
+            Err(vm::InterpretError::RuntimeError(e)) => e,
+            result => panic!("expected a runtime error, got {:?}", result),
+        };
+
+        let e = error("fun f(x) {\n  return x + nil;\n}\nfun g() { return f(1); }\n\ng();");
+        assert_eq!(e.message, "Operands must be numbers, not a number and nil.");
+        assert_eq!(e.line(), Some(2));
+        let trace: Vec<_> = e.trace.iter().map(ToString::to_string).collect();
+        assert_eq!(trace, ["[line 2] in f()", "[line 4] in g()", "[line 6] in script"]);
+
+        assert_eq!(error("print foo;").message, "Undefined variable 'foo'.");
+        assert_eq!(error("class A {} A().bar;").message, "Undefined property 'bar'.");
+        assert_eq!(error("fun f(a) {} f();").message, "Expected 1 arguments but got 0.");
+
+        // however a script fails, the next one starts from a clean stack
+        let (mut vm, output) = captured_vm(vm::VmOptions::default());
+        let failures = [
+            "var a = 1; fun f() { a + nil; } f();",
+            "fun f() { f(); } f();",
+        ];
+        for source in failures {
+            assert!(vm.interpret(source).is_err());
+            vm.interpret("print a;").unwrap();
+        }
+        let listing = "OP_CLASS \"A\"\nOP_CONSTANT \"s\"\nOP_METHOD \"init\"\nOP_NIL\nOP_RETURN";
+        assert!(matches!(
+            vm.interpret_assembly(listing),
+            Err(vm::InterpretError::Ice(_))
+        ));
+        vm.interpret("print a;").unwrap();
+        assert_eq!(output.contents(), "1\n1\n1\n");
+    }
+
+    #[test]
+    fn test_natives() {
+        fn add<'vm>(
+            _vm: &'vm mut vm::Vm,
+            args: &[ValueRef<'vm>],
+        ) -> Result<ValueRef<'vm>, String> {
+            match (args[0].as_number(), args[1].as_number()) {
+                (Some(a), Some(b)) => Ok((a + b).into()),
+                _ => Err("add() takes two numbers.".to_string()),
+            }
+        }
+        fn greet<'vm>(
+            vm: &'vm mut vm::Vm,
+            args: &[ValueRef<'vm>],
+        ) -> Result<ValueRef<'vm>, String> {
+            Ok(vm.new_string(&format!("hello {}", args[0])))
+        }
+        fn first<'vm>(
+            vm: &'vm mut vm::Vm,
+            args: &[ValueRef<'vm>],
+        ) -> Result<ValueRef<'vm>, String> {
+            // the arguments stay alive through a collection
+            vm.collect_garbage();
+            Ok(args[0])
+        }
+        fn nested<'vm>(
+            vm: &'vm mut vm::Vm,
+            _args: &[ValueRef<'vm>],
+        ) -> Result<ValueRef<'vm>, String> {
+            match vm.interpret("print 1;") {
+                Err(vm::InterpretError::Reentrant) => Ok(true.into()),
+                result => Err(format!("expected a re-entrancy error, got {:?}", result)),
+            }
+        }
+
+        let (mut vm, output) = captured_vm(vm::VmOptions::default());
+        vm.define_native("add", 2, add);
+        vm.define_native("greet", 1, greet);
+        vm.define_native("first", 1, first);
+        vm.define_native("nested", 0, nested);
+        vm.interpret("print add(1, 2);").unwrap();
+        vm.interpret("print greet(\"you\");").unwrap();
+        vm.interpret("print first(\"a\" + \"b\");").unwrap();
+        vm.interpret("print nested();").unwrap();
+        let globals: Vec<_> = vm.globals().map(|(name, _)| name.to_string()).collect();
+        assert!(globals.contains(&"greet".to_string()));
+        vm.interpret("var t = clock(); print clock() >= t; print clock;").unwrap();
+        let printed = "3\nhello you\nab\ntrue\ntrue\n<native fn clock>\n";
+        assert_eq!(output.contents(), printed);
+
+        match vm.interpret("fun f() {\n  return add(1, nil);\n}\nf();") {
+            Err(vm::InterpretError::RuntimeError(e)) => {
+                assert_eq!(e.message, "add() takes two numbers.");
+                assert_eq!(e.line(), Some(2));
+                assert_eq!(e.trace.len(), 2);
+            }
+            result => panic!("expected a runtime error, got {:?}", result),
+        }
+        assert!(vm.interpret("add(1);").is_err());
+    }
+
+    #[test]
+    fn test_redirected_streams() {
+        let (output, result) = run_string_captured("print 1; print \"two\"; nil(); print 3;");
+        assert_eq!(output, "1\ntwo\n");
+        assert!(result.is_err());
+
+        let output = vm::OutputBuffer::default();
+        let trace = vm::OutputBuffer::default();
+        let input: &[u8] = b"first\r\nsecond";
+        let mut vm = vm::Vm::with_options(vm::VmOptions::default().trace(true))
+            .with_output(output.clone())
+            .with_debug_output(trace.clone())
+            .with_input(input);
+        vm.interpret("print readLine(); print readLine(); print readLine();")
+            .unwrap();
+        assert_eq!(output.contents(), "first\nsecond\nnil\n");
+        assert!(trace.contents().contains("OP_PRINT"));
+        assert!(!trace.contents().contains("first"));
+    }
+
+    #[test]
+    fn test_limits() {
+        let run = |limits: vm::Limits, source: &str| {
+            vm::Vm::with_options(vm::VmOptions::default().limits(limits)).interpret(source)
+        };
+        let limit = |limits: vm::Limits, source: &str| match run(limits, source) {
+            Err(vm::InterpretError::LimitError(e)) => e,
+            result => panic!("expected a limit error, got {:?}", result),
+        };
+        let limits = vm::Limits::default();
+
+        assert_eq!(
+            limit(limits.instructions(1000), "while (true) {}"),
+            vm::LimitError::Instructions(1000)
+        );
+        run(limits.instructions(1000), "var a = 1 + 2;").unwrap();
+
+        let deep = "fun f(n) { if (n > 0) return 1 + f(n - 1); return 0; } f(50);";
+        assert_eq!(limit(limits.stack(64), deep), vm::LimitError::Stack(64));
+        run(limits.stack(1000), deep).unwrap();
+        // natives are calls too, made here with the script, 64 locals and `clock` on the stack
+        let locals: String = (0..64).map(|i| format!("var a{} = {};", i, i)).collect();
+        let native = format!("{{ {} clock(); }}", locals);
+        assert_eq!(limit(limits.stack(65), &native), vm::LimitError::Stack(65));
+        run(limits.stack(66), &native).unwrap();
+
+        let node = "class Node { init(next) { this.next = next; } }";
+        let hoard = format!("{} var all = nil; while (true) all = Node(all);", node);
+        assert_eq!(limit(limits.memory(100_000), &hoard), vm::LimitError::Memory(100_000));
+        // garbage is collected rather than counted
+        let churn = format!("{} for (var i = 0; i < 10000; i = i + 1) Node(nil);", node);
+        run(limits.memory(100_000), &churn).unwrap();
+        // the fields of instances count too
+        let mut fields = String::from("this.next = next;");
+        for i in 0..32 {
+            fields += &format!(" this.f{} = {};", i, i);
+        }
+        let wide = format!("class Node {{ init(next) {{ {} }} }}", fields);
+        let keep = "var all = nil; for (var i = 0; i < 100; i = i + 1) all = Node(all);";
+        run(limits.memory(100_000), &format!("{} {}", node, keep)).unwrap();
+        let error = limit(limits.memory(100_000), &format!("{} {}", wide, keep));
+        assert_eq!(error, vm::LimitError::Memory(100_000));
+
+        let (mut vm, output) = captured_vm(vm::VmOptions::default());
+        let interrupt = vm.interrupt_handle();
+        let stopper = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            interrupt.store(true, std::sync::atomic::Ordering::Relaxed);
+        });
+        let result = vm.interpret("var i = 0; while (true) { i = i + 1; }");
+        stopper.join().unwrap();
+        assert!(matches!(result, Err(vm::InterpretError::Interrupted)));
+        // a stopped vm can carry on
+        vm.interpret("print i > 0;").unwrap();
+        assert_eq!(output.contents(), "true\n");
+        // and raising the flag between scripts doesn't stop the next one
+        vm.interrupt_handle()
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        vm.interpret("for (var j = 0; j < 1000; j = j + 1) i = i + 1;")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_repl() {
+        let printed = vm::OutputBuffer::default();
+        let vm_output = printed.clone();
+        let mut repl = repl::Repl::new(move || vm::Vm::new().with_output(vm_output.clone()));
+        let mut shown = vec![];
+        let mut enter = |repl: &mut repl::Repl, line: &str| repl.line(line, &mut shown).unwrap();
+
+        enter(&mut repl, "var a = 1;");
+        enter(&mut repl, "nil();");
+        enter(&mut repl, "fun add(b) {");
+        assert_eq!(repl.prompt(), "... ");
+        enter(&mut repl, "  return a + b;");
+        enter(&mut repl, "}");
+        assert_eq!(repl.prompt(), "> ");
+        enter(&mut repl, "add(2)");
+        enter(&mut repl, "var s = \"two");
+        enter(&mut repl, "lines\";");
+        enter(&mut repl, "print 1 +;");
+        enter(&mut repl, ":globals");
+        enter(&mut repl, ":reset");
+        enter(&mut repl, "a");
+        assert!(!enter(&mut repl, ":quit"));
+
+        assert_eq!(printed.contents(), "3\n");
+        let shown = String::from_utf8(shown).unwrap();
+        assert!(shown.contains("Runtime Error: Can only call functions and classes."));
+        assert!(shown.contains("error: Expect expression."));
+        assert!(shown.contains("a = 1\nadd = <fn add>\ns = \"two\\nlines\"\n"));
+        assert!(shown.ends_with("Runtime Error: Undefined variable 'a'.\n[line 1] in script\n"));
+    }
+
+    #[test]
+    fn test_differential() {
+        let source = "class A { init(n) { this.n = n; } }\nprint -A(2).n + 3;\nprint nil + 1;\n";
+        let mut output = String::new();
+        let result = reference::interpret(source, &mut output);
+        assert_eq!(output, "1\n");
+        let message = "Operands must be numbers, not nil and a number.";
+        assert_eq!(result, Err(reference::Error::Runtime(message.to_string())));
+        assert!(differential::check(source).is_none());
+
+        // the reference refuses chains longer than the compiler lets anything nest, which the
+        // comparison skips
+        let source = format!("print 0{};", " + 1".repeat(20_000));
+        let result = reference::interpret(&source, &mut String::new());
+        assert!(matches!(result, Err(reference::Error::Limit(_))));
+        assert!(differential::check(&source).is_none());
+
+        let source = "var a = 1;\n{\n    print a;\n    print \"bad\";\n}\nprint a;\n";
+        let minimized = differential::minimize(source, |candidate| candidate.contains("bad"));
+        assert_eq!(minimized.trim(), "print \"bad\";");
+
+        assert_eq!(differential::generate(7), differential::generate(7));
+    }
+
+    #[test]
+    fn test_fuzz_regressions() {
+        // the compiler rejects a class inheriting from itself, but bytecode can still do it
+        let listing = "
+                OP_CLASS \"A\"
+                OP_DEFINE_GLOBAL A
+                OP_GET_GLOBAL A
+                OP_GET_GLOBAL A
+                OP_INHERIT
+                OP_POP
+                OP_NIL
+                OP_RETURN";
+        vm::Vm::new().interpret_assembly(listing).unwrap();
+
+        // `f` pops the variable `g` captured without closing it, then calls `g`
+        let listing = "
+                OP_CLOSURE <fn f>
+                OP_CALL 0
+                OP_POP
+                OP_NIL
+                OP_RETURN
+            === f ===
+                OP_NIL
+                OP_NIL
+                OP_NIL
+                OP_CONSTANT 1
+                OP_CLOSURE <fn g>
+                    local 4
+                OP_SET_LOCAL 1
+                OP_POP
+                OP_POP
+                OP_POP
+                OP_POP
+                OP_GET_LOCAL 1
+                OP_CALL 0
+                OP_RETURN
+            === g ===
+                OP_GET_UPVALUE 0
+                OP_RETURN";
+        let error = vm::Vm::new().interpret_assembly(listing).unwrap_err();
+        assert!(matches!(error, vm::InterpretError::Ice(_)), "{}", error);
+
+        let mut chunk = Chunk::default();
+        chunk.write(OpCode::GetUpvalue as u8, 1);
+        chunk.write(0, 1);
+        chunk.write(OpCode::Return as u8, 1);
+        let script = value::Function {
+            arity: 0,
+            upvalue_count: 1,
+            chunk,
+            name: None,
+        };
+        let bytes = bytecode::write(&script, &table::Globals::default(), &heap::Heap::default());
+        let error = vm::Vm::new().interpret_bytecode(&bytes).unwrap_err();
+        assert!(error.to_string().contains("can't capture"), "{}", error);
+
+        // long lines are scanned in linear time, and their columns are still right
+        let source = format!("print nil{}é;", ".a".repeat(100_000));
+        match run_string(&source) {
+            Err(vm::InterpretError::CompileError(diagnostics)) => {
+                assert_eq!(diagnostics[0].message, "unexpected character");
+                assert_eq!(diagnostics[0].column, 200_010);
+            }
+            result => panic!("expected a compile error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_long_chains() {
+        // the parser builds infix chains in a loop, so however long they are they never count
+        // against its nesting limit, and nothing walking the tree may recurse along them
+        let source = format!("print 1{};", " + 1".repeat(2000));
+        assert_eq!(printed(&source), "2001\n");
+
+        let tree = parse(&source).unwrap().to_string();
+        assert_eq!(tree.lines().count(), 1 + 2000 + 2001);
+        assert!(tree.ends_with("\n    Literal 1 8006..8007\n"), "{}", tree);
+
+        let source = format!("print nil{};", ".a(1)".repeat(1000));
+        let tree = parse(&source).unwrap().to_string();
+        assert!(tree.ends_with("\n    Literal 1 5007..5008\n"), "{}", tree);
+    }
+
+    #[test]
+    #[ignore = "compiles 2^24 constants, which takes a while"]
+    fn test_constant_limit() {
+        // equal constants are only shared when optimizing
+        let source = format!("nil{};", ".a".repeat(chunk::CONSTANTS_MAX + 1));
+        let options = vm::VmOptions::default().opt_level(OptLevel::O0);
+        match vm::Vm::with_options(options).interpret(&source) {
+            Err(vm::InterpretError::CompileError(diagnostics)) => {
+                assert_eq!(diagnostics[0].message, "Too many constants in one chunk.");
+            }
+            result => panic!("expected a compile error, got {:?}", result),
+        }
+    }
 }
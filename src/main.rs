@@ -1,29 +1,89 @@
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// The exit status when a script can't be compiled or loaded, following sysexits.h
+const EXIT_COMPILE_ERROR: u8 = 65;
+/// The exit status when a script fails while running
+const EXIT_RUNTIME_ERROR: u8 = 70;
+
+fn main() -> std::process::ExitCode {
+    use bylox::vm::InterpretError;
+
+    let Err(error) = run() else {
+        return std::process::ExitCode::SUCCESS;
+    };
+
+    let status = match error.downcast_ref() {
+        // compile errors have already been shown alongside their source
+        Some(InterpretError::CompileError(_)) => EXIT_COMPILE_ERROR,
+        Some(InterpretError::RuntimeError(e)) => {
+            eprintln!("Runtime Error: {}", e);
+            EXIT_RUNTIME_ERROR
+        }
+        Some(e @ (InterpretError::LimitError(_) | InterpretError::Interrupted)) => {
+            eprintln!("{}", e);
+            EXIT_RUNTIME_ERROR
+        }
+        Some(
+            e @ (InterpretError::AsmError(_)
+            | InterpretError::LoadError(_)
+            | InterpretError::VerifyError(_)),
+        ) => {
+            eprintln!("{}", e);
+            EXIT_COMPILE_ERROR
+        }
+        _ => {
+            eprintln!("Error: {}", error);
+            1
+        }
+    };
+    std::process::ExitCode::from(status)
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
     let args = {
         use clap::Parser;
         bylox::arg::ArgStruct::parse()
     };
 
-    match args.script {
-        Some(file) => bylox::run_file(file)?,
-        None => {
-            use std::io::BufRead;
-            use std::io::Write;
-
-            let mut stdout = std::io::BufWriter::new(std::io::stdout());
-            let mut stdin = std::io::BufReader::new(std::io::stdin());
-
-            let mut vm = bylox::vm::Vm::new(Box::default());
+    let options = args.vm_options();
 
-            loop {
-                write!(stdout, "> ")?;
-                stdout.flush()?;
-                let mut input = String::new();
-                stdin.read_line(&mut input)?;
+    if let Some(command) = args.command {
+        use bylox::arg::Command;
 
-                vm.interpret(&input)?;
+        match command {
+            Command::Compile { script, output } => {
+                let output = output.unwrap_or_else(|| script.with_extension("lxc"));
+                bylox::compile_file(&script, &output, options)?;
+            }
+            Command::Parse { script, dump_ast } => {
+                bylox::parse_file(&script, dump_ast)?;
+            }
+            Command::Asm { listing, output } => {
+                bylox::assemble_file(&listing, output.as_deref(), options)?;
+            }
+            Command::Test { dir, filter, jobs } => {
+                let jobs = jobs.unwrap_or_else(|| {
+                    std::thread::available_parallelism().map_or(1, |jobs| jobs.get())
+                });
+                bylox::test_dir(&dir, filter.as_deref(), jobs)?;
+            }
+            Command::Diff {
+                paths,
+                random,
+                seed,
+            } => {
+                let seed = seed.unwrap_or_else(|| {
+                    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+                    now.map_or(0, |now| now.as_nanos() as u64)
+                });
+                bylox::compare_interpreters(&paths, random, seed)?;
             }
         }
+
+        return Ok(());
+    }
+
+    match args.script {
+        Some(file) => bylox::run_file(file, options)?,
+        None => bylox::repl::run(options)?,
     }
 
     Ok(())
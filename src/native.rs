@@ -0,0 +1,25 @@
+//! Native functions every `Vm` starts with
+
+use crate::value::NativeFn;
+use crate::value::ValueRef;
+use crate::vm::Vm;
+
+/// The name, arity and implementation of each standard native
+pub const STANDARD: &[(&str, usize, NativeFn)] = &[("clock", 0, clock), ("readLine", 0, read_line)];
+
+/// The number of seconds since the Unix epoch
+fn clock<'vm>(_vm: &'vm mut Vm, _args: &[ValueRef<'vm>]) -> Result<ValueRef<'vm>, String> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_err(|e| e.to_string())?;
+    Ok(now.as_secs_f64().into())
+}
+
+/// The next line of input, or `nil` at the end of the input
+fn read_line<'vm>(vm: &'vm mut Vm, _args: &[ValueRef<'vm>]) -> Result<ValueRef<'vm>, String> {
+    match vm.read_line() {
+        Ok(Some(line)) => Ok(vm.new_string(&line)),
+        Ok(None) => Ok(ValueRef::NIL),
+        Err(e) => Err(format!("Could not read input: {}.", e)),
+    }
+}
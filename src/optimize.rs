@@ -0,0 +1,232 @@
+//! Constant folding, the part of optimization done on the syntax tree before it is compiled
+//!
+//! An operator whose operands are all literals is replaced by the literal it evaluates to, as
+//! long as evaluating it can't fail, so `-(1 + 2) * 4` is compiled as `-12`. The code generator
+//! does the rest of the work of `OptLevel::O1` as it emits instructions.
+
+use crate::ast::BinaryOp;
+use crate::ast::Expr;
+use crate::ast::ExprKind;
+use crate::ast::Literal;
+use crate::ast::LogicalOp;
+use crate::ast::Program;
+use crate::ast::Stmt;
+use crate::ast::StmtKind;
+use crate::ast::UnaryOp;
+use crate::compiler::last_token;
+
+/// How much work the compiler does to make scripts run faster
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    /// Compile each expression just as it is written
+    O0,
+    /// Fold constant expressions, fuse comparisons with the `Not` that follows them, leave out
+    /// constants that would only be popped, and share equal constants within a chunk
+    #[default]
+    O1,
+}
+
+pub(crate) fn fold_program(program: &mut Program) {
+    for statement in &mut program.statements {
+        fold_statement(statement);
+    }
+}
+
+fn fold_statement(stmt: &mut Stmt) {
+    match &mut stmt.kind {
+        StmtKind::Expression(expr) | StmtKind::Print(expr) => fold(expr),
+        StmtKind::Var { initializer, .. } => initializer.iter_mut().for_each(fold),
+        StmtKind::Block(statements) => statements.iter_mut().for_each(fold_statement),
+        StmtKind::If {
+            condition,
+            then_branch,
+            else_branch,
+            ..
+        } => {
+            fold(condition);
+            fold_statement(then_branch);
+            else_branch.iter_mut().for_each(|stmt| fold_statement(stmt));
+        }
+        StmtKind::While {
+            condition, body, ..
+        } => {
+            fold(condition);
+            fold_statement(body);
+        }
+        StmtKind::For {
+            initializer,
+            condition,
+            increment,
+            body,
+            ..
+        } => {
+            initializer.iter_mut().for_each(|stmt| fold_statement(stmt));
+            condition.iter_mut().for_each(fold);
+            increment.iter_mut().for_each(fold);
+            fold_statement(body);
+        }
+        StmtKind::Function(function) => function.body.iter_mut().for_each(fold_statement),
+        StmtKind::Class(class) => {
+            for method in &mut class.methods {
+                method.body.iter_mut().for_each(fold_statement);
+            }
+        }
+        StmtKind::Return(value) => value.iter_mut().for_each(fold),
+    }
+}
+
+fn fold(expr: &mut Expr) {
+    // infix expressions nest to the left, so a long chain such as `1 + 2 + 3` is taken apart
+    // down to its innermost operand and folded from there out rather than recursively
+    let mut chain = vec![];
+    let mut innermost = take(expr);
+    while let Some(operand) = left_operand(&mut innermost) {
+        let operand = take(operand);
+        chain.push(innermost);
+        innermost = operand;
+    }
+
+    fold_node(&mut innermost);
+    for mut outer in chain.into_iter().rev() {
+        *left_operand(&mut outer).unwrap() = innermost;
+        fold_node(&mut outer);
+        innermost = outer;
+    }
+
+    *expr = innermost;
+}
+
+/// Moves an expression out, leaving a `nil` behind
+fn take(expr: &mut Expr) -> Expr {
+    let empty = Expr {
+        kind: ExprKind::Literal(Literal::Nil),
+        span: expr.span,
+    };
+    std::mem::replace(expr, empty)
+}
+
+fn left_operand(expr: &mut Expr) -> Option<&mut Expr> {
+    match &mut expr.kind {
+        ExprKind::Binary { left, .. } | ExprKind::Logical { left, .. } => Some(left),
+        ExprKind::Get { object, .. } | ExprKind::Set { object, .. } => Some(object),
+        ExprKind::Call { callee, .. } => Some(callee),
+        _ => None,
+    }
+}
+
+/// Folds the operands of an expression other than its left one, which is already folded, then
+/// the expression itself
+fn fold_node(expr: &mut Expr) {
+    let folded = match &mut expr.kind {
+        ExprKind::Grouping(inner) => {
+            fold(inner);
+            literal(inner).cloned()
+        }
+        ExprKind::Assign { value, .. } | ExprKind::Set { value, .. } => {
+            fold(value);
+            None
+        }
+        ExprKind::Call { arguments, .. } => {
+            arguments.iter_mut().for_each(fold);
+            None
+        }
+        ExprKind::Unary { operator, operand } => {
+            fold(operand);
+            match (operator.kind, literal(operand)) {
+                (UnaryOp::Negate, Some(Literal::Number(n))) => Some(Literal::Number(-n)),
+                (UnaryOp::Not, Some(operand)) => Some(Literal::Bool(!is_truthy(operand))),
+                _ => None,
+            }
+        }
+        ExprKind::Binary {
+            left,
+            operator,
+            right,
+        } => {
+            fold(right);
+            match (&mut left.kind, literal(right)) {
+                (ExprKind::Literal(left), Some(right)) => binary(operator.kind, left, right),
+                _ => None,
+            }
+        }
+        ExprKind::Logical {
+            left,
+            operator,
+            right,
+        } => {
+            fold(right);
+            let Some(left) = literal(left) else {
+                return;
+            };
+
+            let short_circuits = match operator.kind {
+                LogicalOp::And => !is_truthy(left),
+                LogicalOp::Or => is_truthy(left),
+            };
+            if !short_circuits {
+                *expr = take(right);
+                return;
+            }
+            // the right operand is only dropped if it is a literal, as it could have errors in it
+            literal(right).map(|_| left.clone())
+        }
+        _ => None,
+    };
+
+    if let Some(folded) = folded {
+        // the code after an expression is attributed to its last token, so the literal takes that
+        // span to leave the lines of what follows it unchanged
+        *expr = Expr {
+            span: last_token(expr),
+            kind: ExprKind::Literal(folded),
+        };
+    }
+}
+
+fn literal(expr: &Expr) -> Option<&Literal> {
+    match &expr.kind {
+        ExprKind::Literal(literal) => Some(literal),
+        _ => None,
+    }
+}
+
+fn is_truthy(literal: &Literal) -> bool {
+    !matches!(literal, Literal::Nil | Literal::Bool(false))
+}
+
+/// The result of a binary operator, unless it would be a runtime error
+///
+/// The left operand is about to be replaced, so a string on the left is reused for the result of
+/// a concatenation.
+#[allow(clippy::neg_cmp_op_on_partial_ord)]
+fn binary(operator: BinaryOp, left: &mut Literal, right: &Literal) -> Option<Literal> {
+    use BinaryOp::*;
+
+    let result = match (operator, left, right) {
+        (Equal, left, right) => Literal::Bool(*left == *right),
+        (NotEqual, left, right) => Literal::Bool(*left != *right),
+        (Add, Literal::String(a), Literal::String(b)) => {
+            let mut string = std::mem::take(a);
+            string.push_str(b);
+            Literal::String(string)
+        }
+        (_, Literal::Number(a), Literal::Number(b)) => {
+            let (a, b) = (*a, *b);
+            match operator {
+                Greater => Literal::Bool(a > b),
+                // as `!(a < b)`, which the unfused instructions compute
+                GreaterEqual => Literal::Bool(!(a < b)),
+                Less => Literal::Bool(a < b),
+                LessEqual => Literal::Bool(!(a > b)),
+                Add => Literal::Number(a + b),
+                Subtract => Literal::Number(a - b),
+                Multiply => Literal::Number(a * b),
+                Divide => Literal::Number(a / b),
+                Remainder => Literal::Number(a % b),
+                Equal | NotEqual => unreachable!("equality is folded for any literals"),
+            }
+        }
+        _ => return None,
+    };
+    Some(result)
+}
@@ -0,0 +1,874 @@
+use crate::ast::BinaryOp;
+use crate::ast::Class;
+use crate::ast::Expr;
+use crate::ast::ExprKind;
+use crate::ast::Function;
+use crate::ast::Identifier;
+use crate::ast::Literal;
+use crate::ast::LogicalOp;
+use crate::ast::Operator;
+use crate::ast::Program;
+use crate::ast::Stmt;
+use crate::ast::StmtKind;
+use crate::ast::UnaryOp;
+use crate::diagnostic::Diagnostic;
+use crate::diagnostic::Span;
+use crate::scanner::Scanner;
+use crate::scanner::Token;
+use crate::scanner::TokenKind;
+use crate::vm::InterpretError;
+
+use derive_try_from_primitive::TryFromPrimitive;
+
+/// The maximum number of parameters or arguments, limited by the one-byte `Call` operand
+const ARGS_MAX: usize = 255;
+
+/// How deeply expressions and statements may nest, as parsing and compiling them recurses
+pub(crate) const DEPTH_MAX: usize = 256;
+
+struct Parser<'a> {
+    scanner: Scanner<'a>,
+    current: Option<Token<'a>>,
+    previous: Option<Token<'a>>,
+    /// Every error found so far, in source order
+    diagnostics: Vec<Diagnostic>,
+    panic_mode: bool,
+    /// How many expressions and statements enclose the one being parsed
+    depth: usize,
+}
+
+type PrefixFn = fn(&mut Parser<'_>, bool) -> Expr;
+type InfixFn = fn(&mut Parser<'_>, Expr, bool) -> Expr;
+
+#[repr(u8)]
+#[derive(TryFromPrimitive, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Precedence {
+    None,
+    Assignment, // =
+    Or,         // or
+    And,        // and
+    Equality,   // == !=
+    Comparison, // < > <= >=
+    Term,       // + -
+    Factor,     // * /
+    Unary,      // ! -
+    Call,       // . ()
+    Primary,
+}
+
+impl<'a> Parser<'a> {
+    fn new(scanner: Scanner<'a>) -> Parser<'a> {
+        Parser {
+            scanner,
+            current: None,
+            previous: None,
+            diagnostics: vec![],
+            panic_mode: false,
+            depth: 0,
+        }
+    }
+
+    fn advance(&mut self) {
+        self.previous = self.current.clone();
+
+        loop {
+            let token = self.scanner.scan_token();
+            let kind = token.kind;
+            self.current = Some(token);
+
+            match kind {
+                TokenKind::UnterminatedString => {
+                    self.report_error_at_current("unterminated string")
+                }
+                TokenKind::UnexpectedCharacter => {
+                    self.report_error_at_current("unexpected character")
+                }
+                _ => break,
+            }
+        }
+    }
+
+    // match() in book
+    fn check_advance(&mut self, kind: TokenKind) -> bool {
+        if !self.check(kind) {
+            false
+        } else {
+            self.advance();
+            true
+        }
+    }
+
+    fn check(&mut self, kind: TokenKind) -> bool {
+        match &self.current {
+            Some(t) => t.kind == kind,
+            None => false,
+        }
+    }
+
+    // errorAtCurrent() in book
+    fn report_error_at_current(&mut self, message: &str) {
+        self.report_error_at(self.current.clone().unwrap(), message)
+    }
+
+    // error() in book
+    fn report_error_at_previous(&mut self, message: &str) {
+        self.report_error_at(self.previous.clone().unwrap(), message)
+    }
+
+    // errorAt() in book
+    fn report_error_at(&mut self, token: Token<'_>, message: &str) {
+        let span = match token.kind {
+            // a malformed token has no meaningful text, so point at where it starts
+            TokenKind::UnterminatedString | TokenKind::UnexpectedCharacter => {
+                Span::new(token.offset, token.offset)
+            }
+            _ => token.range(),
+        };
+        let diagnostic = Diagnostic::error(message, span, token.line, token.column);
+        self.report(diagnostic)
+    }
+
+    /// Records a diagnostic, unless the parser is still recovering from an earlier error
+    fn report(&mut self, diagnostic: Diagnostic) {
+        if self.panic_mode {
+            return;
+        }
+
+        self.panic_mode = true;
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn consume(&mut self, kind: TokenKind, message: &str) {
+        if self.current.as_ref().unwrap().kind == kind {
+            self.advance();
+            return;
+        }
+
+        self.report_error_at_current(message);
+    }
+
+    fn previous_span(&self) -> Span {
+        self.previous.as_ref().unwrap().range()
+    }
+
+    /// The span from `start` to the end of the token just consumed
+    fn span_from(&self, start: usize) -> Span {
+        Span::new(start, self.previous_span().end)
+    }
+
+    fn current_offset(&self) -> usize {
+        self.current.as_ref().unwrap().offset
+    }
+
+    /// The token just consumed as an identifier, which is only meaningful if it was one
+    fn identifier(&self) -> Identifier {
+        let token = self.previous.as_ref().unwrap();
+        Identifier {
+            name: token.span.to_string(),
+            span: token.range(),
+        }
+    }
+
+    /// Goes one level deeper into the tree, or reports `message` if that is too deep
+    fn enter(&mut self, message: &str) -> bool {
+        if self.depth == DEPTH_MAX {
+            self.report_error_at_current(message);
+            return false;
+        }
+        self.depth += 1;
+        true
+    }
+
+    /// An expression standing in for one that couldn't be parsed
+    ///
+    /// It is only seen by the compiler if recovery from the error ended inside the statement
+    /// holding it, and the compiler's output is thrown away then anyway.
+    fn missing_expression(&self) -> Expr {
+        Expr {
+            kind: ExprKind::Literal(Literal::Nil),
+            span: self.previous_span(),
+        }
+    }
+}
+
+// parsing rules
+impl<'a> Parser<'a> {
+    fn parse_precedence(&mut self, precedence: Precedence) -> Expr {
+        if !self.enter("Expression nests too deeply.") {
+            return self.missing_expression();
+        }
+        let expr = self.parse_precedence_unchecked(precedence);
+        self.depth -= 1;
+        expr
+    }
+
+    fn parse_precedence_unchecked(&mut self, precedence: Precedence) -> Expr {
+        self.advance();
+
+        let prefix_rule = get_rule_prefix(&self.previous.as_ref().unwrap().kind);
+
+        let (mut expr, can_assign) = if let Some(rule) = prefix_rule {
+            let can_assign = precedence <= Precedence::Assignment;
+            (rule(self, can_assign), can_assign)
+        } else {
+            self.report_error_at_previous("Expect expression.");
+            return self.missing_expression();
+        };
+
+        while precedence as u8 <= get_rule_precedence(&self.current.as_ref().unwrap().kind) as u8 {
+            self.advance();
+            let infix_rule = get_rule_infix(&self.previous.as_ref().unwrap().kind);
+            expr = infix_rule.unwrap()(self, expr, can_assign);
+        }
+
+        if can_assign && self.check_advance(TokenKind::Equal) {
+            self.report_error_at_previous("Invalid assignment target.");
+        }
+
+        expr
+    }
+
+    fn expression(&mut self) -> Expr {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    /// Parses a declaration, or `None` if it has an error, skipping to where parsing can carry on
+    fn declaration(&mut self) -> Option<Stmt> {
+        if !self.enter("Statement nests too deeply.") {
+            // skip a token so the enclosing block doesn't try this one again
+            self.advance();
+            self.synchronize();
+            return None;
+        }
+        let stmt = self.declaration_unchecked();
+        self.depth -= 1;
+        stmt
+    }
+
+    fn declaration_unchecked(&mut self) -> Option<Stmt> {
+        let start = self.current_offset();
+
+        let kind = if self.check_advance(TokenKind::Class) {
+            self.class_declaration()
+        } else if self.check_advance(TokenKind::Fun) {
+            self.function_declaration()
+        } else if self.check_advance(TokenKind::Var) {
+            self.variable_declaration()
+        } else {
+            self.statement_kind()
+        };
+
+        let stmt = Stmt {
+            kind,
+            span: self.span_from(start),
+        };
+
+        if self.panic_mode {
+            self.synchronize();
+            return None;
+        }
+
+        Some(stmt)
+    }
+
+    fn class_declaration(&mut self) -> StmtKind {
+        self.consume(TokenKind::Identifier, "Expect class name.");
+        let name = self.identifier();
+
+        let superclass = if self.check_advance(TokenKind::Less) {
+            self.consume(TokenKind::Identifier, "Expect superclass name.");
+            Some(self.identifier())
+        } else {
+            None
+        };
+
+        let mut methods = vec![];
+        self.consume(TokenKind::LeftBrace, "Expect `{` before class body.");
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            methods.push(self.method());
+        }
+        self.consume(TokenKind::RightBrace, "Expect `}` after class body.");
+
+        StmtKind::Class(Class {
+            name,
+            superclass,
+            methods,
+        })
+    }
+
+    fn method(&mut self) -> Function {
+        self.consume(TokenKind::Identifier, "Expect method name.");
+        self.function()
+    }
+
+    fn function_declaration(&mut self) -> StmtKind {
+        self.consume(TokenKind::Identifier, "Expect function name.");
+        StmtKind::Function(self.function())
+    }
+
+    /// Parses the rest of a function whose name was just consumed
+    fn function(&mut self) -> Function {
+        let name = self.identifier();
+        let mut params = vec![];
+
+        self.consume(TokenKind::LeftParen, "Expect `(` after function name.");
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                if params.len() == ARGS_MAX {
+                    self.report_error_at_current("Can't have more than 255 parameters.");
+                }
+
+                self.consume(TokenKind::Identifier, "Expect parameter name.");
+                params.push(self.identifier());
+
+                if !self.check_advance(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenKind::RightParen, "Expect `)` after parameters.");
+        self.consume(TokenKind::LeftBrace, "Expect `{` before function body.");
+        let body = self.block();
+
+        Function {
+            span: self.span_from(name.span.start),
+            name,
+            params,
+            body,
+        }
+    }
+
+    fn variable_declaration(&mut self) -> StmtKind {
+        self.consume(TokenKind::Identifier, "Expect variable name.");
+        let name = self.identifier();
+
+        let initializer = if self.check_advance(TokenKind::Equal) {
+            Some(self.expression())
+        } else {
+            None
+        };
+
+        self.consume(TokenKind::Semicolon, "Expect `;`.");
+
+        StmtKind::Var { name, initializer }
+    }
+
+    fn statement(&mut self) -> Stmt {
+        let start = self.current_offset();
+        let kind = if self.enter("Statement nests too deeply.") {
+            let kind = self.statement_kind();
+            self.depth -= 1;
+            kind
+        } else {
+            StmtKind::Expression(self.missing_expression())
+        };
+
+        Stmt {
+            kind,
+            span: self.span_from(start),
+        }
+    }
+
+    fn statement_kind(&mut self) -> StmtKind {
+        if self.check_advance(TokenKind::Print) {
+            self.print_statement()
+        } else if self.check_advance(TokenKind::If) {
+            self.if_statement()
+        } else if self.check_advance(TokenKind::Return) {
+            self.return_statement()
+        } else if self.check_advance(TokenKind::While) {
+            self.while_statement()
+        } else if self.check_advance(TokenKind::For) {
+            self.for_statement()
+        } else if self.check_advance(TokenKind::LeftBrace) {
+            StmtKind::Block(self.block())
+        } else {
+            self.expression_statement()
+        }
+    }
+
+    fn block(&mut self) -> Vec<Stmt> {
+        let mut statements = vec![];
+
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            statements.extend(self.declaration());
+        }
+
+        self.consume(TokenKind::RightBrace, "Expect `}` after block.");
+
+        statements
+    }
+
+    fn expression_statement(&mut self) -> StmtKind {
+        let expr = self.expression();
+        self.consume(TokenKind::Semicolon, "Expect `;`.");
+        StmtKind::Expression(expr)
+    }
+
+    fn print_statement(&mut self) -> StmtKind {
+        let expr = self.expression();
+        self.consume(TokenKind::Semicolon, "Expect `;`.");
+        StmtKind::Print(expr)
+    }
+
+    fn return_statement(&mut self) -> StmtKind {
+        if self.check_advance(TokenKind::Semicolon) {
+            return StmtKind::Return(None);
+        }
+
+        let value = self.expression();
+        self.consume(TokenKind::Semicolon, "Expect `;` after return value.");
+        StmtKind::Return(Some(value))
+    }
+
+    fn if_statement(&mut self) -> StmtKind {
+        self.consume(TokenKind::LeftParen, "Expect `(` after `if`.");
+        let condition = self.expression();
+        self.consume(TokenKind::RightParen, "Expect `)` after condition.");
+        let right_paren = self.previous_span();
+
+        let then_branch = Box::new(self.statement());
+        let else_branch = if self.check_advance(TokenKind::Else) {
+            Some(Box::new(self.statement()))
+        } else {
+            None
+        };
+
+        StmtKind::If {
+            condition,
+            right_paren,
+            then_branch,
+            else_branch,
+        }
+    }
+
+    fn while_statement(&mut self) -> StmtKind {
+        self.consume(TokenKind::LeftParen, "Expect `(` after `while`.");
+        let condition = self.expression();
+        self.consume(TokenKind::RightParen, "Expect `)` after condition.");
+        let right_paren = self.previous_span();
+
+        StmtKind::While {
+            condition,
+            right_paren,
+            body: Box::new(self.statement()),
+        }
+    }
+
+    fn for_statement(&mut self) -> StmtKind {
+        self.consume(TokenKind::LeftParen, "Expect `(` after `for`.");
+
+        let start = self.current_offset();
+        let initializer = if self.check_advance(TokenKind::Semicolon) {
+            None
+        } else {
+            let kind = if self.check_advance(TokenKind::Var) {
+                self.variable_declaration()
+            } else {
+                self.expression_statement()
+            };
+            Some(Box::new(Stmt {
+                kind,
+                span: self.span_from(start),
+            }))
+        };
+
+        let condition = if self.check_advance(TokenKind::Semicolon) {
+            None
+        } else {
+            let condition = self.expression();
+            self.consume(TokenKind::Semicolon, "Expect `;` after loop condition.");
+            Some(condition)
+        };
+        let semicolon = self.previous_span();
+
+        let increment = if self.check_advance(TokenKind::RightParen) {
+            None
+        } else {
+            let increment = self.expression();
+            self.consume(TokenKind::RightParen, "Expect `)` after for clauses.");
+            Some(increment)
+        };
+        let right_paren = self.previous_span();
+
+        StmtKind::For {
+            initializer,
+            condition,
+            semicolon,
+            increment,
+            right_paren,
+            body: Box::new(self.statement()),
+        }
+    }
+
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        use TokenKind::*;
+
+        while let Some(t) = &self.current {
+            if let Some(TokenKind::Semicolon) = self.previous.as_ref().map(|t| t.kind) {
+                break;
+            }
+
+            match t.kind {
+                Class | Fun | Var | For | If | While | Print | Return | Eof => break,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn grouping(&mut self, _can_assign: bool) -> Expr {
+        let start = self.previous_span().start;
+        let inner = self.expression();
+        self.consume(TokenKind::RightParen, "Expect `)`.");
+
+        Expr {
+            kind: ExprKind::Grouping(Box::new(inner)),
+            span: self.span_from(start),
+        }
+    }
+
+    fn number(&mut self, _can_assign: bool) -> Expr {
+        let string = self.previous.as_ref().unwrap().span;
+        let number = string.parse::<f64>().unwrap();
+
+        Expr {
+            kind: ExprKind::Literal(Literal::Number(number)),
+            span: self.previous_span(),
+        }
+    }
+
+    fn unary(&mut self, _can_assign: bool) -> Expr {
+        let operator = Operator {
+            kind: match self.previous.as_ref().unwrap().kind {
+                TokenKind::Bang => UnaryOp::Not,
+                TokenKind::Minus => UnaryOp::Negate,
+                _ => unreachable!(),
+            },
+            span: self.previous_span(),
+        };
+
+        let operand = self.parse_precedence(Precedence::Unary);
+
+        Expr {
+            kind: ExprKind::Unary {
+                operator,
+                operand: Box::new(operand),
+            },
+            span: self.span_from(operator.span.start),
+        }
+    }
+
+    fn binary(&mut self, left: Expr, _can_assign: bool) -> Expr {
+        let operator_kind = self.previous.as_ref().unwrap().kind;
+        let operator = Operator {
+            kind: match operator_kind {
+                TokenKind::BangEqual => BinaryOp::NotEqual,
+                TokenKind::EqualEqual => BinaryOp::Equal,
+                TokenKind::Greater => BinaryOp::Greater,
+                TokenKind::GreaterEqual => BinaryOp::GreaterEqual,
+                TokenKind::Less => BinaryOp::Less,
+                TokenKind::LessEqual => BinaryOp::LessEqual,
+                TokenKind::Plus => BinaryOp::Add,
+                TokenKind::Minus => BinaryOp::Subtract,
+                TokenKind::Star => BinaryOp::Multiply,
+                TokenKind::Slash => BinaryOp::Divide,
+                TokenKind::Percent => BinaryOp::Remainder,
+                _ => unreachable!(),
+            },
+            span: self.previous_span(),
+        };
+
+        let precedence = get_rule_precedence(&operator_kind);
+
+        let right = self.parse_precedence((precedence as u8 + 1).try_into().unwrap());
+
+        Expr {
+            span: self.span_from(left.span.start),
+            kind: ExprKind::Binary {
+                left: Box::new(left),
+                operator,
+                right: Box::new(right),
+            },
+        }
+    }
+
+    fn call(&mut self, callee: Expr, _can_assign: bool) -> Expr {
+        let arguments = self.argument_list();
+
+        Expr {
+            span: self.span_from(callee.span.start),
+            kind: ExprKind::Call {
+                callee: Box::new(callee),
+                arguments,
+            },
+        }
+    }
+
+    fn argument_list(&mut self) -> Vec<Expr> {
+        let mut arguments = vec![];
+
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                arguments.push(self.expression());
+
+                if arguments.len() > ARGS_MAX {
+                    self.report_error_at_previous("Can't have more than 255 arguments.");
+                }
+
+                if !self.check_advance(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenKind::RightParen, "Expect `)` after arguments.");
+
+        arguments
+    }
+
+    fn dot(&mut self, object: Expr, can_assign: bool) -> Expr {
+        self.consume(TokenKind::Identifier, "Expect property name after `.`.");
+        let name = self.identifier();
+        let start = object.span.start;
+        let object = Box::new(object);
+
+        let kind = if can_assign && self.check_advance(TokenKind::Equal) {
+            let value = Box::new(self.expression());
+            ExprKind::Set {
+                object,
+                name,
+                value,
+            }
+        } else {
+            ExprKind::Get { object, name }
+        };
+
+        Expr {
+            span: self.span_from(start),
+            kind,
+        }
+    }
+
+    fn this(&mut self, _can_assign: bool) -> Expr {
+        Expr {
+            kind: ExprKind::This,
+            span: self.previous_span(),
+        }
+    }
+
+    fn super_(&mut self, _can_assign: bool) -> Expr {
+        let start = self.previous_span().start;
+
+        self.consume(TokenKind::Dot, "Expect `.` after `super`.");
+        self.consume(TokenKind::Identifier, "Expect superclass method name.");
+
+        Expr {
+            kind: ExprKind::Super {
+                method: self.identifier(),
+            },
+            span: self.span_from(start),
+        }
+    }
+
+    fn and(&mut self, left: Expr, _can_assign: bool) -> Expr {
+        self.logical(left, LogicalOp::And, Precedence::And)
+    }
+
+    fn or(&mut self, left: Expr, _can_assign: bool) -> Expr {
+        self.logical(left, LogicalOp::Or, Precedence::Or)
+    }
+
+    fn logical(&mut self, left: Expr, kind: LogicalOp, precedence: Precedence) -> Expr {
+        let operator = Operator {
+            kind,
+            span: self.previous_span(),
+        };
+
+        let right = self.parse_precedence(precedence);
+
+        Expr {
+            span: self.span_from(left.span.start),
+            kind: ExprKind::Logical {
+                left: Box::new(left),
+                operator,
+                right: Box::new(right),
+            },
+        }
+    }
+
+    fn literal(&mut self, _can_assign: bool) -> Expr {
+        let literal = match self.previous.as_ref().unwrap().kind {
+            TokenKind::Nil => Literal::Nil,
+            TokenKind::True => Literal::Bool(true),
+            TokenKind::False => Literal::Bool(false),
+            _ => unreachable!(),
+        };
+
+        Expr {
+            kind: ExprKind::Literal(literal),
+            span: self.previous_span(),
+        }
+    }
+
+    fn string(&mut self, _can_assign: bool) -> Expr {
+        let token = self.previous.as_ref().unwrap();
+
+        match parse_string(&token.span[1..(token.span.len() - 1)]) {
+            Ok(s) => Expr {
+                kind: ExprKind::Literal(Literal::String(s)),
+                span: self.previous_span(),
+            },
+            Err(s) => {
+                self.report_error_at_previous(s);
+                self.missing_expression()
+            }
+        }
+    }
+
+    fn variable(&mut self, can_assign: bool) -> Expr {
+        let name = self.identifier();
+
+        if can_assign && self.check_advance(TokenKind::Equal) {
+            let value = self.expression();
+            Expr {
+                span: self.span_from(name.span.start),
+                kind: ExprKind::Assign {
+                    name,
+                    value: Box::new(value),
+                },
+            }
+        } else {
+            Expr {
+                span: name.span,
+                kind: ExprKind::Variable(name),
+            }
+        }
+    }
+}
+
+fn get_rule_prefix(token: &TokenKind) -> Option<PrefixFn> {
+    let literal = |self_: &mut Parser<'_>, can_assign: bool| Parser::literal(self_, can_assign);
+    Some(match token {
+        TokenKind::LeftParen => |self_: &mut Parser<'_>, can_assign: bool| Parser::grouping(self_, can_assign),
+        TokenKind::Minus => |self_: &mut Parser<'_>, can_assign: bool| Parser::unary(self_, can_assign),
+        TokenKind::Bang => |self_: &mut Parser<'_>, can_assign: bool| Parser::unary(self_, can_assign),
+        TokenKind::Number => |self_: &mut Parser<'_>, can_assign: bool| Parser::number(self_, can_assign),
+        TokenKind::Nil => literal,
+        TokenKind::False => literal,
+        TokenKind::True => literal,
+        TokenKind::String => |self_: &mut Parser<'_>, can_assign: bool| Parser::string(self_, can_assign),
+        TokenKind::Identifier => |self_: &mut Parser<'_>, can_assign: bool| Parser::variable(self_, can_assign),
+        TokenKind::This => |self_: &mut Parser<'_>, can_assign: bool| Parser::this(self_, can_assign),
+        TokenKind::Super => |self_: &mut Parser<'_>, can_assign: bool| Parser::super_(self_, can_assign),
+        _ => return None,
+    })
+}
+
+fn get_rule_infix(token: &TokenKind) -> Option<InfixFn> {
+    let binary = |self_: &mut Parser<'_>, left: Expr, can_assign: bool| Parser::binary(self_, left, can_assign);
+    Some(match token {
+        TokenKind::LeftParen => |self_: &mut Parser<'_>, left: Expr, can_assign: bool| Parser::call(self_, left, can_assign),
+        TokenKind::Dot => |self_: &mut Parser<'_>, left: Expr, can_assign: bool| Parser::dot(self_, left, can_assign),
+        TokenKind::Plus => binary,
+        TokenKind::Minus => binary,
+        TokenKind::Star => binary,
+        TokenKind::Slash => binary,
+        TokenKind::Percent => binary,
+        TokenKind::BangEqual => binary,
+        TokenKind::EqualEqual => binary,
+        TokenKind::Greater => binary,
+        TokenKind::GreaterEqual => binary,
+        TokenKind::Less => binary,
+        TokenKind::LessEqual => binary,
+        TokenKind::And => |self_: &mut Parser<'_>, left: Expr, can_assign: bool| Parser::and(self_, left, can_assign),
+        TokenKind::Or => |self_: &mut Parser<'_>, left: Expr, can_assign: bool| Parser::or(self_, left, can_assign),
+        _ => return None,
+    })
+}
+
+fn get_rule_precedence(token: &TokenKind) -> Precedence {
+    match token {
+        TokenKind::LeftParen => Precedence::Call,
+        TokenKind::Dot => Precedence::Call,
+        TokenKind::Plus => Precedence::Term,
+        TokenKind::Minus => Precedence::Term,
+        TokenKind::Star => Precedence::Factor,
+        TokenKind::Slash => Precedence::Factor,
+        TokenKind::Percent => Precedence::Factor,
+        TokenKind::BangEqual => Precedence::Equality,
+        TokenKind::EqualEqual => Precedence::Equality,
+        TokenKind::Greater => Precedence::Comparison,
+        TokenKind::GreaterEqual => Precedence::Comparison,
+        TokenKind::Less => Precedence::Comparison,
+        TokenKind::LessEqual => Precedence::Comparison,
+        TokenKind::And => Precedence::And,
+        TokenKind::Or => Precedence::Or,
+        _ => Precedence::None,
+    }
+}
+
+pub(crate) fn parse_string(escaped_string: &str) -> Result<String, &'static str> {
+    let mut final_string = String::with_capacity(escaped_string.len());
+    let mut iter = escaped_string.chars();
+
+    while let Some(ch) = iter.next() {
+        final_string.push(match ch {
+            '\\' => match iter.next() {
+                Some(esc) => match esc {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    '\\' => '\\',
+                    '0' => '\0',
+                    '\'' => '\'',
+                    '\"' => '\"',
+                    'x' => return Err("unimplemented string escape `\\x`"),
+                    'u' => return Err("unimplemented string escape `\\u`"),
+                    _ => return Err("unrecognized string escape"),
+                },
+                None => return Err("unexpected end of string"),
+            },
+            _ => ch,
+        });
+    }
+
+    Ok(final_string)
+}
+
+/// Parses a script, returning the tree for every declaration that parsed along with the errors
+/// in those that didn't
+pub(crate) fn parse_with_errors(source: &str) -> (Program, Vec<Diagnostic>) {
+    let mut parser = Parser::new(Scanner::new(source));
+
+    parser.advance();
+
+    let mut statements = vec![];
+    while let Some(t) = &parser.current {
+        if t.kind == TokenKind::Eof {
+            break;
+        } else {
+            statements.extend(parser.declaration());
+        }
+    }
+
+    parser.consume(TokenKind::Eof, "Expect end of expression.");
+
+    (Program { statements }, parser.diagnostics)
+}
+
+/// Parses a script into its syntax tree
+///
+/// This only finds syntax errors. Errors such as using `this` outside a class or redeclaring a
+/// local variable are found when the tree is compiled. If the script has syntax errors, every one
+/// found is returned in an `InterpretError::CompileError`.
+pub fn parse(source: &str) -> Result<Program, InterpretError> {
+    let (program, diagnostics) = parse_with_errors(source);
+
+    if !diagnostics.is_empty() {
+        return Err(InterpretError::CompileError(diagnostics));
+    }
+
+    Ok(program)
+}
@@ -0,0 +1,1213 @@
+//! A deliberately simple tree-walking interpreter, to check the compiler and `Vm` against
+//!
+//! It shares only the scanner with them. Scripts are parsed into a tree, variables are found by
+//! name in a chain of environments, and memory is left to reference counting, which leaks
+//! cycles. It doesn't model the bytecode's limits on constants, locals, upvalues and jump
+//! lengths, but should otherwise behave exactly like the `Vm`. Parsing and evaluating recurse,
+//! so scripts nesting more deeply than the compiler allows, counting each link of a chain such
+//! as `a + b + c`, are refused with `Error::Limit` rather than run.
+
+use crate::parser::parse_string;
+use crate::parser::DEPTH_MAX;
+use crate::scanner::Scanner;
+use crate::scanner::Token;
+use crate::scanner::TokenKind;
+use crate::vm::FRAMES_MAX;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Why a script stopped early
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The first error the compiler would report, though not necessarily in the same words
+    Compile(String),
+    Runtime(String),
+    /// The script nests too deeply to be run, though the `Vm` may still run it
+    Limit(String),
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Compile(message) => write!(f, "Compile Error: {}", message),
+            Error::Runtime(message) => write!(f, "Runtime Error: {}", message),
+            Error::Limit(message) => write!(f, "Limit Error: {}", message),
+        }
+    }
+}
+
+/// Runs a script, appending what it prints to `output`
+///
+/// There is no input, so `readLine()` always returns `nil`.
+pub fn interpret(source: &str, output: &mut String) -> Result<(), Error> {
+    let mut parser = Parser::new(source);
+    let script = parser.script().map_err(|message| match parser.too_deep {
+        true => Error::Limit(message),
+        false => Error::Compile(message),
+    })?;
+
+    let mut interpreter = Interpreter::new(output);
+    for statement in &script {
+        match interpreter.execute(statement) {
+            Ok(()) => (),
+            Err(Unwind::Error(message)) => return Err(Error::Runtime(message)),
+            Err(Unwind::Return(_)) => unreachable!("returned from top-level code"),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone)]
+enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(Rc<str>),
+    Function(Rc<Function>),
+    Native(Rc<Native>),
+    Class(Rc<Class>),
+    Instance(Rc<Instance>),
+}
+
+struct Function {
+    declaration: Rc<FunctionDeclaration>,
+    /// The environment the function was declared in, `None` at the top level
+    closure: Option<Rc<Environment>>,
+    /// The receiver of a bound method
+    this: Option<Value>,
+}
+
+struct Native {
+    name: &'static str,
+    arity: usize,
+    function: fn() -> Value,
+}
+
+struct Class {
+    name: Rc<str>,
+    /// Including those inherited from the superclass, which are copied down like the `Vm` does
+    methods: HashMap<Rc<str>, Rc<Function>>,
+}
+
+struct Instance {
+    class: Rc<Class>,
+    fields: RefCell<HashMap<Rc<str>, Value>>,
+}
+
+impl Value {
+    fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    /// The kind of value, named the same way as in the `Vm`'s error messages
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "a boolean",
+            Value::Number(_) => "a number",
+            Value::String(_) => "a string",
+            Value::Function(_) | Value::Native(_) => "a function",
+            Value::Class(_) => "a class",
+            Value::Instance(_) => "an instance",
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::Native(a), Value::Native(b)) => Rc::ptr_eq(a, b),
+            (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Function(function) => write!(f, "<fn {}>", function.declaration.name),
+            Value::Native(native) => write!(f, "<native fn {}>", native.name),
+            Value::Class(class) => write!(f, "{}", class.name),
+            Value::Instance(instance) => write!(f, "{} instance", instance.class.name),
+        }
+    }
+}
+
+/// A variable as it appears in an expression, resolved while parsing
+struct Variable {
+    name: Rc<str>,
+    /// How many environments out from the current one the variable is in, or `None` for a global
+    depth: Option<usize>,
+}
+
+enum Expr {
+    Literal(Value),
+    Grouping(Box<Expr>),
+    Variable(Variable),
+    This(Variable),
+    Assign(Variable, Box<Expr>),
+    Unary(TokenKind, Box<Expr>),
+    Binary(Box<Expr>, TokenKind, Box<Expr>),
+    Logical(Box<Expr>, TokenKind, Box<Expr>),
+    Call(Box<Expr>, Vec<Expr>),
+    Get(Box<Expr>, Rc<str>),
+    Set(Box<Expr>, Rc<str>, Box<Expr>),
+    /// A method call, which the `Vm` evaluates in a different order than a `Get` then a `Call`
+    Invoke(Box<Expr>, Rc<str>, Vec<Expr>),
+    /// `super.name`, with the variables holding `this` and the superclass
+    Super(Variable, Variable, Rc<str>),
+    SuperInvoke(Variable, Variable, Rc<str>, Vec<Expr>),
+}
+
+enum Stmt {
+    Expression(Expr),
+    Print(Expr),
+    Var(Rc<str>, Option<Expr>),
+    Block(Vec<Stmt>),
+    If(Expr, Box<Stmt>, Option<Box<Stmt>>),
+    /// A `while` loop, or a `for` loop with its increment
+    While(Expr, Box<Stmt>, Option<Expr>),
+    Function(Rc<FunctionDeclaration>),
+    Class(Rc<str>, Option<Variable>, Vec<Rc<FunctionDeclaration>>),
+    Return(Option<Expr>),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionKind {
+    Function,
+    Initializer,
+    Method,
+}
+
+struct FunctionDeclaration {
+    name: Rc<str>,
+    kind: FunctionKind,
+    params: Vec<Rc<str>>,
+    body: Vec<Stmt>,
+}
+
+type ParseResult<T> = Result<T, String>;
+
+struct Parser<'a> {
+    scanner: Scanner<'a>,
+    current: Token<'a>,
+    previous: Token<'a>,
+    /// The names declared in each enclosing scope, innermost last, and whether each has been
+    /// initialized yet
+    scopes: Vec<Vec<(&'a str, bool)>>,
+    /// The kinds of the enclosing functions
+    functions: Vec<FunctionKind>,
+    /// Whether each enclosing class has a superclass
+    classes: Vec<bool>,
+    /// How many expressions and statements enclose the one being parsed, counting each link of
+    /// the chains enclosing it
+    depth: usize,
+    /// Whether parsing stopped because the script nests too deeply
+    too_deep: bool,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Parser<'a> {
+        let mut scanner = Scanner::new(source);
+        let current = scanner.scan_token();
+        Parser {
+            scanner,
+            previous: current.clone(),
+            current,
+            scopes: vec![],
+            functions: vec![],
+            classes: vec![],
+            depth: 0,
+            too_deep: false,
+        }
+    }
+
+    fn script(&mut self) -> ParseResult<Vec<Stmt>> {
+        self.check_token()?;
+
+        let mut statements = vec![];
+        while !self.check(TokenKind::Eof) {
+            statements.push(self.declaration()?);
+        }
+        Ok(statements)
+    }
+
+    fn error(&self, token: &Token, message: &str) -> String {
+        format!("[line {}] at '{}': {}", token.line, token.span, message)
+    }
+
+    /// Fails on the scanner's error tokens, which the compiler reports as soon as it sees them
+    fn check_token(&self) -> ParseResult<()> {
+        match self.current.kind {
+            TokenKind::UnterminatedString => Err(self.error(&self.current, "unterminated string")),
+            TokenKind::UnexpectedCharacter => {
+                Err(self.error(&self.current, "unexpected character"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Goes one level deeper into the tree, failing if that is deeper than the compiler goes
+    fn deeper(&mut self) -> ParseResult<()> {
+        if self.depth == DEPTH_MAX {
+            self.too_deep = true;
+            return Err(self.error(&self.current, "Too deeply nested."));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// Parses with `parse` one level deeper into the tree
+    fn nested<T>(&mut self, parse: fn(&mut Parser<'a>) -> ParseResult<T>) -> ParseResult<T> {
+        self.deeper()?;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn advance(&mut self) -> ParseResult<()> {
+        self.previous = std::mem::replace(&mut self.current, self.scanner.scan_token());
+        self.check_token()
+    }
+
+    fn check(&self, kind: TokenKind) -> bool {
+        self.current.kind == kind
+    }
+
+    fn matches(&mut self, kind: TokenKind) -> ParseResult<bool> {
+        if !self.check(kind) {
+            return Ok(false);
+        }
+        self.advance()?;
+        Ok(true)
+    }
+
+    fn consume(&mut self, kind: TokenKind, message: &str) -> ParseResult<()> {
+        if !self.check(kind) {
+            return Err(self.error(&self.current, message));
+        }
+        self.advance()
+    }
+
+    /// Declares the variable named by the next token in the innermost scope, if there is one
+    fn declare(&mut self, message: &str) -> ParseResult<Rc<str>> {
+        self.consume(TokenKind::Identifier, message)?;
+        let name = self.previous.span;
+
+        if let Some(scope) = self.scopes.last_mut() {
+            if scope.iter().any(|(declared, _)| *declared == name) {
+                let message = "Already a variable with this name in this scope.";
+                return Err(self.error(&self.previous, message));
+            }
+            scope.push((name, false));
+        }
+        Ok(name.into())
+    }
+
+    fn define(&mut self) {
+        if let Some((_, initialized)) = self.scopes.last_mut().and_then(|scope| scope.last_mut()) {
+            *initialized = true;
+        }
+    }
+
+    fn resolve(&self, name: &str) -> ParseResult<Variable> {
+        for (depth, scope) in self.scopes.iter().rev().enumerate() {
+            if let Some((_, initialized)) = scope.iter().find(|(declared, _)| *declared == name) {
+                if !initialized {
+                    let message = "Can't read local variable in its own initializer.";
+                    return Err(self.error(&self.previous, message));
+                }
+                return Ok(Variable {
+                    name: name.into(),
+                    depth: Some(depth),
+                });
+            }
+        }
+
+        Ok(Variable {
+            name: name.into(),
+            depth: None,
+        })
+    }
+
+    fn declaration(&mut self) -> ParseResult<Stmt> {
+        self.nested(Parser::declaration_unchecked)
+    }
+
+    fn declaration_unchecked(&mut self) -> ParseResult<Stmt> {
+        if self.matches(TokenKind::Class)? {
+            self.class_declaration()
+        } else if self.matches(TokenKind::Fun)? {
+            let name = self.declare("Expect function name.")?;
+            // a function may refer to itself
+            self.define();
+            let function = self.function(name, FunctionKind::Function)?;
+            Ok(Stmt::Function(Rc::new(function)))
+        } else if self.matches(TokenKind::Var)? {
+            self.var_declaration()
+        } else {
+            self.statement()
+        }
+    }
+
+    fn class_declaration(&mut self) -> ParseResult<Stmt> {
+        let name = self.declare("Expect class name.")?;
+        self.define();
+
+        let superclass = if self.matches(TokenKind::Less)? {
+            self.consume(TokenKind::Identifier, "Expect superclass name.")?;
+            if self.previous.span == &*name {
+                return Err(self.error(&self.previous, "A class can't inherit from itself."));
+            }
+            Some(self.resolve(self.previous.span)?)
+        } else {
+            None
+        };
+
+        if superclass.is_some() {
+            self.scopes.push(vec![("super", true)]);
+        }
+        self.classes.push(superclass.is_some());
+
+        self.consume(TokenKind::LeftBrace, "Expect `{` before class body.")?;
+        let mut methods = vec![];
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            self.consume(TokenKind::Identifier, "Expect method name.")?;
+            let name: Rc<str> = self.previous.span.into();
+            let kind = match &*name {
+                "init" => FunctionKind::Initializer,
+                _ => FunctionKind::Method,
+            };
+            methods.push(Rc::new(self.function(name, kind)?));
+        }
+        self.consume(TokenKind::RightBrace, "Expect `}` after class body.")?;
+
+        self.classes.pop();
+        if superclass.is_some() {
+            self.scopes.pop();
+        }
+
+        Ok(Stmt::Class(name, superclass, methods))
+    }
+
+    fn function(&mut self, name: Rc<str>, kind: FunctionKind) -> ParseResult<FunctionDeclaration> {
+        // the receiver shares a scope with the parameters
+        let scope = match kind {
+            FunctionKind::Function => vec![],
+            FunctionKind::Initializer | FunctionKind::Method => vec![("this", true)],
+        };
+        self.scopes.push(scope);
+        self.functions.push(kind);
+
+        self.consume(TokenKind::LeftParen, "Expect `(` after function name.")?;
+        let mut params = vec![];
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                if params.len() == 255 {
+                    let message = "Can't have more than 255 parameters.";
+                    return Err(self.error(&self.current, message));
+                }
+                params.push(self.declare("Expect parameter name.")?);
+                self.define();
+
+                if !self.matches(TokenKind::Comma)? {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenKind::RightParen, "Expect `)` after parameters.")?;
+        self.consume(TokenKind::LeftBrace, "Expect `{` before function body.")?;
+        let body = self.block()?;
+
+        self.functions.pop();
+        self.scopes.pop();
+
+        Ok(FunctionDeclaration {
+            name,
+            kind,
+            params,
+            body,
+        })
+    }
+
+    fn var_declaration(&mut self) -> ParseResult<Stmt> {
+        let name = self.declare("Expect variable name.")?;
+        let initializer = if self.matches(TokenKind::Equal)? {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.consume(TokenKind::Semicolon, "Expect `;`.")?;
+        self.define();
+
+        Ok(Stmt::Var(name, initializer))
+    }
+
+    fn statement(&mut self) -> ParseResult<Stmt> {
+        self.nested(Parser::statement_unchecked)
+    }
+
+    fn statement_unchecked(&mut self) -> ParseResult<Stmt> {
+        if self.matches(TokenKind::Print)? {
+            let value = self.expression()?;
+            self.consume(TokenKind::Semicolon, "Expect `;`.")?;
+            Ok(Stmt::Print(value))
+        } else if self.matches(TokenKind::If)? {
+            self.if_statement()
+        } else if self.matches(TokenKind::Return)? {
+            self.return_statement()
+        } else if self.matches(TokenKind::While)? {
+            self.consume(TokenKind::LeftParen, "Expect `(` after `while`.")?;
+            let condition = self.expression()?;
+            self.consume(TokenKind::RightParen, "Expect `)` after condition.")?;
+            let body = self.statement()?;
+            Ok(Stmt::While(condition, Box::new(body), None))
+        } else if self.matches(TokenKind::For)? {
+            self.scopes.push(vec![]);
+            let statement = self.for_statement();
+            self.scopes.pop();
+            statement
+        } else if self.matches(TokenKind::LeftBrace)? {
+            self.scopes.push(vec![]);
+            let block = self.block();
+            self.scopes.pop();
+            Ok(Stmt::Block(block?))
+        } else {
+            self.expression_statement()
+        }
+    }
+
+    /// Parses declarations up to the closing brace, in whatever scope the caller set up
+    fn block(&mut self) -> ParseResult<Vec<Stmt>> {
+        let mut statements = vec![];
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            statements.push(self.declaration()?);
+        }
+        self.consume(TokenKind::RightBrace, "Expect `}` after block.")?;
+        Ok(statements)
+    }
+
+    fn if_statement(&mut self) -> ParseResult<Stmt> {
+        self.consume(TokenKind::LeftParen, "Expect `(` after `if`.")?;
+        let condition = self.expression()?;
+        self.consume(TokenKind::RightParen, "Expect `)` after condition.")?;
+
+        let then_branch = self.statement()?;
+        let else_branch = if self.matches(TokenKind::Else)? {
+            Some(Box::new(self.statement()?))
+        } else {
+            None
+        };
+
+        Ok(Stmt::If(condition, Box::new(then_branch), else_branch))
+    }
+
+    fn return_statement(&mut self) -> ParseResult<Stmt> {
+        let Some(&kind) = self.functions.last() else {
+            return Err(self.error(&self.previous, "Can't return from top-level code."));
+        };
+
+        if self.matches(TokenKind::Semicolon)? {
+            return Ok(Stmt::Return(None));
+        }
+
+        if kind == FunctionKind::Initializer {
+            let message = "Can't return a value from an initializer.";
+            return Err(self.error(&self.previous, message));
+        }
+        let value = self.expression()?;
+        self.consume(TokenKind::Semicolon, "Expect `;` after return value.")?;
+        Ok(Stmt::Return(Some(value)))
+    }
+
+    /// Turns a `for` loop into a block holding its initializer and a `while` loop
+    fn for_statement(&mut self) -> ParseResult<Stmt> {
+        self.consume(TokenKind::LeftParen, "Expect `(` after `for`.")?;
+        let initializer = if self.matches(TokenKind::Semicolon)? {
+            None
+        } else if self.matches(TokenKind::Var)? {
+            Some(self.var_declaration()?)
+        } else {
+            Some(self.expression_statement()?)
+        };
+
+        let condition = if self.matches(TokenKind::Semicolon)? {
+            Expr::Literal(Value::Bool(true))
+        } else {
+            let condition = self.expression()?;
+            self.consume(TokenKind::Semicolon, "Expect `;` after loop condition.")?;
+            condition
+        };
+
+        let increment = if self.matches(TokenKind::RightParen)? {
+            None
+        } else {
+            let increment = self.expression()?;
+            self.consume(TokenKind::RightParen, "Expect `)` after for clauses.")?;
+            Some(increment)
+        };
+
+        let body = self.statement()?;
+
+        let mut statements: Vec<Stmt> = initializer.into_iter().collect();
+        statements.push(Stmt::While(condition, Box::new(body), increment));
+        Ok(Stmt::Block(statements))
+    }
+
+    fn expression_statement(&mut self) -> ParseResult<Stmt> {
+        let expression = self.expression()?;
+        self.consume(TokenKind::Semicolon, "Expect `;`.")?;
+        Ok(Stmt::Expression(expression))
+    }
+
+    fn expression(&mut self) -> ParseResult<Expr> {
+        self.nested(Parser::expression_unchecked)
+    }
+
+    fn expression_unchecked(&mut self) -> ParseResult<Expr> {
+        let target = self.or()?;
+
+        if !self.matches(TokenKind::Equal)? {
+            return Ok(target);
+        }
+
+        let equals = self.previous.clone();
+        let value = Box::new(self.expression()?);
+        match target {
+            Expr::Variable(variable) => Ok(Expr::Assign(variable, value)),
+            Expr::Get(object, name) => Ok(Expr::Set(object, name, value)),
+            _ => Err(self.error(&equals, "Invalid assignment target.")),
+        }
+    }
+
+    fn or(&mut self) -> ParseResult<Expr> {
+        let depth = self.depth;
+        let mut expression = self.and()?;
+        while self.matches(TokenKind::Or)? {
+            self.deeper()?;
+            let right = self.and()?;
+            expression = Expr::Logical(Box::new(expression), TokenKind::Or, Box::new(right));
+        }
+        self.depth = depth;
+        Ok(expression)
+    }
+
+    fn and(&mut self) -> ParseResult<Expr> {
+        let depth = self.depth;
+        let mut expression = self.equality()?;
+        while self.matches(TokenKind::And)? {
+            self.deeper()?;
+            let right = self.equality()?;
+            expression = Expr::Logical(Box::new(expression), TokenKind::And, Box::new(right));
+        }
+        self.depth = depth;
+        Ok(expression)
+    }
+
+    /// Parses a run of left-associative binary operators, with `operand` parsing each side
+    fn binary(
+        &mut self,
+        operators: &[TokenKind],
+        operand: fn(&mut Parser<'a>) -> ParseResult<Expr>,
+    ) -> ParseResult<Expr> {
+        let depth = self.depth;
+        let mut expression = operand(self)?;
+        while operators.contains(&self.current.kind) {
+            self.deeper()?;
+            self.advance()?;
+            let operator = self.previous.kind;
+            let right = operand(self)?;
+            expression = Expr::Binary(Box::new(expression), operator, Box::new(right));
+        }
+        self.depth = depth;
+        Ok(expression)
+    }
+
+    fn equality(&mut self) -> ParseResult<Expr> {
+        use TokenKind::*;
+        self.binary(&[BangEqual, EqualEqual], Parser::comparison)
+    }
+
+    fn comparison(&mut self) -> ParseResult<Expr> {
+        use TokenKind::*;
+        self.binary(&[Greater, GreaterEqual, Less, LessEqual], Parser::term)
+    }
+
+    fn term(&mut self) -> ParseResult<Expr> {
+        self.binary(&[TokenKind::Minus, TokenKind::Plus], Parser::factor)
+    }
+
+    fn factor(&mut self) -> ParseResult<Expr> {
+        use TokenKind::*;
+        self.binary(&[Slash, Star, Percent], Parser::unary)
+    }
+
+    fn unary(&mut self) -> ParseResult<Expr> {
+        if self.matches(TokenKind::Bang)? || self.matches(TokenKind::Minus)? {
+            let operator = self.previous.kind;
+            let operand = self.nested(Parser::unary)?;
+            return Ok(Expr::Unary(operator, Box::new(operand)));
+        }
+        self.call()
+    }
+
+    fn call(&mut self) -> ParseResult<Expr> {
+        let depth = self.depth;
+        let mut expression = self.primary()?;
+
+        loop {
+            if matches!(self.current.kind, TokenKind::LeftParen | TokenKind::Dot) {
+                self.deeper()?;
+            }
+            if self.matches(TokenKind::LeftParen)? {
+                let arguments = self.arguments()?;
+                expression = Expr::Call(Box::new(expression), arguments);
+            } else if self.matches(TokenKind::Dot)? {
+                self.consume(TokenKind::Identifier, "Expect property name after `.`.")?;
+                let name = self.previous.span.into();
+                expression = if self.matches(TokenKind::LeftParen)? {
+                    Expr::Invoke(Box::new(expression), name, self.arguments()?)
+                } else {
+                    Expr::Get(Box::new(expression), name)
+                };
+            } else {
+                self.depth = depth;
+                return Ok(expression);
+            }
+        }
+    }
+
+    /// Parses arguments up to the closing parenthesis
+    fn arguments(&mut self) -> ParseResult<Vec<Expr>> {
+        let mut arguments = vec![];
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                arguments.push(self.expression()?);
+                if arguments.len() > 255 {
+                    let message = "Can't have more than 255 arguments.";
+                    return Err(self.error(&self.previous, message));
+                }
+
+                if !self.matches(TokenKind::Comma)? {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenKind::RightParen, "Expect `)` after arguments.")?;
+        Ok(arguments)
+    }
+
+    fn primary(&mut self) -> ParseResult<Expr> {
+        self.advance()?;
+        let token = self.previous.clone();
+
+        let literal = match token.kind {
+            TokenKind::False => Value::Bool(false),
+            TokenKind::True => Value::Bool(true),
+            TokenKind::Nil => Value::Nil,
+            TokenKind::Number => Value::Number(token.span.parse().unwrap()),
+            TokenKind::String => match parse_string(&token.span[1..token.span.len() - 1]) {
+                Ok(string) => Value::String(string.into()),
+                Err(message) => return Err(self.error(&token, message)),
+            },
+            TokenKind::Identifier => return Ok(Expr::Variable(self.resolve(token.span)?)),
+            TokenKind::This => {
+                if self.classes.is_empty() {
+                    let message = "Can't use `this` outside of a class.";
+                    return Err(self.error(&token, message));
+                }
+                return Ok(Expr::This(self.resolve("this")?));
+            }
+            TokenKind::Super => return self.super_(),
+            TokenKind::LeftParen => {
+                let expression = self.expression()?;
+                self.consume(TokenKind::RightParen, "Expect `)`.")?;
+                return Ok(Expr::Grouping(Box::new(expression)));
+            }
+            _ => return Err(self.error(&token, "Expect expression.")),
+        };
+
+        Ok(Expr::Literal(literal))
+    }
+
+    fn super_(&mut self) -> ParseResult<Expr> {
+        match self.classes.last() {
+            None => {
+                let message = "Can't use `super` outside of a class.";
+                return Err(self.error(&self.previous, message));
+            }
+            Some(false) => {
+                let message = "Can't use `super` in a class with no superclass.";
+                return Err(self.error(&self.previous, message));
+            }
+            Some(true) => (),
+        }
+
+        self.consume(TokenKind::Dot, "Expect `.` after `super`.")?;
+        self.consume(TokenKind::Identifier, "Expect superclass method name.")?;
+        let name = self.previous.span.into();
+
+        let this = self.resolve("this")?;
+        let superclass = self.resolve("super")?;
+        if self.matches(TokenKind::LeftParen)? {
+            let arguments = self.arguments()?;
+            return Ok(Expr::SuperInvoke(this, superclass, name, arguments));
+        }
+        Ok(Expr::Super(this, superclass, name))
+    }
+}
+
+struct Environment {
+    values: RefCell<HashMap<Rc<str>, Value>>,
+    enclosing: Option<Rc<Environment>>,
+}
+
+impl Environment {
+    fn new(enclosing: Option<Rc<Environment>>) -> Environment {
+        Environment {
+            values: Default::default(),
+            enclosing,
+        }
+    }
+
+    fn define(&self, name: Rc<str>, value: Value) {
+        self.values.borrow_mut().insert(name, value);
+    }
+}
+
+/// Why a statement stopped before finishing
+enum Unwind {
+    Return(Value),
+    Error(String),
+}
+
+impl From<String> for Unwind {
+    fn from(message: String) -> Unwind {
+        Unwind::Error(message)
+    }
+}
+
+struct Interpreter<'o> {
+    output: &'o mut String,
+    globals: HashMap<Rc<str>, Value>,
+    /// The innermost environment, `None` in top-level code
+    environment: Option<Rc<Environment>>,
+    /// The calls in progress, counting the script itself
+    frames: usize,
+}
+
+impl<'o> Interpreter<'o> {
+    fn new(output: &'o mut String) -> Interpreter<'o> {
+        let natives = [
+            Native {
+                name: "clock",
+                arity: 0,
+                function: clock,
+            },
+            Native {
+                name: "readLine",
+                arity: 0,
+                function: || Value::Nil,
+            },
+        ];
+
+        Interpreter {
+            output,
+            globals: natives
+                .into_iter()
+                .map(|native| (native.name.into(), Value::Native(Rc::new(native))))
+                .collect(),
+            environment: None,
+            frames: 1,
+        }
+    }
+
+    fn execute(&mut self, statement: &Stmt) -> Result<(), Unwind> {
+        match statement {
+            Stmt::Expression(expression) => {
+                self.evaluate(expression)?;
+            }
+            Stmt::Print(expression) => {
+                let value = self.evaluate(expression)?;
+                self.output.push_str(&format!("{}\n", value));
+            }
+            Stmt::Var(name, initializer) => {
+                let value = match initializer {
+                    Some(initializer) => self.evaluate(initializer)?,
+                    None => Value::Nil,
+                };
+                self.define(name.clone(), value);
+            }
+            Stmt::Block(statements) => {
+                let environment = Environment::new(self.environment.clone());
+                self.execute_block(statements, environment)?;
+            }
+            Stmt::If(condition, then_branch, else_branch) => {
+                if self.evaluate(condition)?.is_truthy() {
+                    self.execute(then_branch)?;
+                } else if let Some(else_branch) = else_branch {
+                    self.execute(else_branch)?;
+                }
+            }
+            Stmt::While(condition, body, increment) => {
+                while self.evaluate(condition)?.is_truthy() {
+                    self.execute(body)?;
+                    if let Some(increment) = increment {
+                        self.evaluate(increment)?;
+                    }
+                }
+            }
+            Stmt::Function(declaration) => {
+                let function = Function {
+                    declaration: declaration.clone(),
+                    closure: self.environment.clone(),
+                    this: None,
+                };
+                self.define(declaration.name.clone(), Value::Function(Rc::new(function)));
+            }
+            Stmt::Class(name, superclass, methods) => {
+                self.class(name, superclass.as_ref(), methods)?;
+            }
+            Stmt::Return(value) => {
+                let value = match value {
+                    Some(value) => self.evaluate(value)?,
+                    None => Value::Nil,
+                };
+                return Err(Unwind::Return(value));
+            }
+        }
+        Ok(())
+    }
+
+    fn execute_block(
+        &mut self,
+        statements: &[Stmt],
+        environment: Environment,
+    ) -> Result<(), Unwind> {
+        let enclosing = self.environment.replace(Rc::new(environment));
+        let result = statements
+            .iter()
+            .try_for_each(|statement| self.execute(statement));
+        self.environment = enclosing;
+        result
+    }
+
+    fn class(
+        &mut self,
+        name: &Rc<str>,
+        superclass: Option<&Variable>,
+        methods: &[Rc<FunctionDeclaration>],
+    ) -> Result<(), String> {
+        let superclass = match superclass {
+            Some(superclass) => match self.get(superclass)? {
+                Value::Class(superclass) => Some(superclass),
+                _ => return Err("Superclass must be a class.".to_string()),
+            },
+            None => None,
+        };
+
+        let mut table = HashMap::new();
+        let mut closure = self.environment.clone();
+        if let Some(superclass) = superclass {
+            table.clone_from(&superclass.methods);
+            let environment = Environment::new(closure);
+            environment.define("super".into(), Value::Class(superclass));
+            closure = Some(Rc::new(environment));
+        }
+
+        for method in methods {
+            let function = Function {
+                declaration: method.clone(),
+                closure: closure.clone(),
+                this: None,
+            };
+            table.insert(method.name.clone(), Rc::new(function));
+        }
+
+        let class = Class {
+            name: name.clone(),
+            methods: table,
+        };
+        self.define(name.clone(), Value::Class(Rc::new(class)));
+        Ok(())
+    }
+
+    fn define(&mut self, name: Rc<str>, value: Value) {
+        match &self.environment {
+            Some(environment) => environment.define(name, value),
+            None => {
+                self.globals.insert(name, value);
+            }
+        }
+    }
+
+    fn ancestor(&self, depth: usize) -> Rc<Environment> {
+        let mut environment = self.environment.clone();
+        for _ in 0..depth {
+            environment = environment.and_then(|environment| environment.enclosing.clone());
+        }
+        environment.expect("a resolved local is in an environment")
+    }
+
+    fn get(&self, variable: &Variable) -> Result<Value, String> {
+        let value = match variable.depth {
+            Some(depth) => self
+                .ancestor(depth)
+                .values
+                .borrow()
+                .get(&variable.name)
+                .cloned(),
+            None => self.globals.get(&variable.name).cloned(),
+        };
+        value.ok_or_else(|| format!("Undefined variable '{}'.", variable.name))
+    }
+
+    fn assign(&mut self, variable: &Variable, value: Value) -> Result<(), String> {
+        let defined = match variable.depth {
+            Some(depth) => {
+                let environment = self.ancestor(depth);
+                let mut values = environment.values.borrow_mut();
+                values.get_mut(&variable.name).map(|slot| *slot = value)
+            }
+            None => self
+                .globals
+                .get_mut(&variable.name)
+                .map(|slot| *slot = value),
+        };
+        defined.ok_or_else(|| format!("Undefined variable '{}'.", variable.name))
+    }
+
+    fn evaluate(&mut self, expression: &Expr) -> Result<Value, String> {
+        match expression {
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::Grouping(expression) => self.evaluate(expression),
+            Expr::Variable(variable) | Expr::This(variable) => self.get(variable),
+            Expr::Assign(variable, value) => {
+                let value = self.evaluate(value)?;
+                self.assign(variable, value.clone())?;
+                Ok(value)
+            }
+            Expr::Unary(operator, operand) => {
+                let operand = self.evaluate(operand)?;
+                match (operator, operand) {
+                    (TokenKind::Bang, operand) => Ok(Value::Bool(!operand.is_truthy())),
+                    (_, Value::Number(n)) => Ok(Value::Number(-n)),
+                    _ => Err("Operand must be a number.".to_string()),
+                }
+            }
+            Expr::Binary(left, operator, right) => {
+                let left = self.evaluate(left)?;
+                let right = self.evaluate(right)?;
+                binary(*operator, left, right)
+            }
+            Expr::Logical(left, operator, right) => {
+                let left = self.evaluate(left)?;
+                if left.is_truthy() == (*operator == TokenKind::Or) {
+                    return Ok(left);
+                }
+                self.evaluate(right)
+            }
+            Expr::Call(callee, arguments) => {
+                let callee = self.evaluate(callee)?;
+                let arguments = self.evaluate_all(arguments)?;
+                self.call(callee, arguments)
+            }
+            Expr::Get(object, name) => {
+                let object = self.evaluate(object)?;
+                let Value::Instance(instance) = &object else {
+                    return Err("Only instances have properties.".to_string());
+                };
+                if let Some(value) = instance.fields.borrow().get(name) {
+                    return Ok(value.clone());
+                }
+                bind(&instance.class, name, object.clone())
+            }
+            Expr::Set(object, name, value) => {
+                let object = self.evaluate(object)?;
+                let value = self.evaluate(value)?;
+                let Value::Instance(instance) = object else {
+                    return Err("Only instances have fields.".to_string());
+                };
+                instance
+                    .fields
+                    .borrow_mut()
+                    .insert(name.clone(), value.clone());
+                Ok(value)
+            }
+            Expr::Invoke(object, name, arguments) => {
+                let object = self.evaluate(object)?;
+                let arguments = self.evaluate_all(arguments)?;
+                let Value::Instance(instance) = &object else {
+                    return Err("Only instances have methods.".to_string());
+                };
+                // a field holding a function shadows a method of the same name
+                let field = instance.fields.borrow().get(name).cloned();
+                let callee = match field {
+                    Some(field) => field,
+                    None => bind(&instance.class, name, object.clone())?,
+                };
+                self.call(callee, arguments)
+            }
+            Expr::Super(this, superclass, name) => {
+                let this = self.get(this)?;
+                let Value::Class(superclass) = self.get(superclass)? else {
+                    unreachable!("`super` always holds a class");
+                };
+                bind(&superclass, name, this)
+            }
+            Expr::SuperInvoke(this, superclass, name, arguments) => {
+                let this = self.get(this)?;
+                let arguments = self.evaluate_all(arguments)?;
+                let Value::Class(superclass) = self.get(superclass)? else {
+                    unreachable!("`super` always holds a class");
+                };
+                let method = bind(&superclass, name, this)?;
+                self.call(method, arguments)
+            }
+        }
+    }
+
+    fn evaluate_all(&mut self, expressions: &[Expr]) -> Result<Vec<Value>, String> {
+        expressions
+            .iter()
+            .map(|expression| self.evaluate(expression))
+            .collect()
+    }
+
+    fn call(&mut self, callee: Value, arguments: Vec<Value>) -> Result<Value, String> {
+        match callee {
+            Value::Function(function) => self.call_function(&function, arguments),
+            Value::Native(native) => {
+                check_arity(native.arity, arguments.len())?;
+                Ok((native.function)())
+            }
+            Value::Class(class) => {
+                let instance = Value::Instance(Rc::new(Instance {
+                    class: class.clone(),
+                    fields: Default::default(),
+                }));
+                match class.methods.get("init") {
+                    Some(initializer) => {
+                        let initializer = bound(initializer, instance);
+                        self.call_function(&initializer, arguments)
+                    }
+                    None => {
+                        check_arity(0, arguments.len())?;
+                        Ok(instance)
+                    }
+                }
+            }
+            _ => Err("Can only call functions and classes.".to_string()),
+        }
+    }
+
+    fn call_function(
+        &mut self,
+        function: &Function,
+        arguments: Vec<Value>,
+    ) -> Result<Value, String> {
+        let declaration = &function.declaration;
+        check_arity(declaration.params.len(), arguments.len())?;
+        if self.frames == FRAMES_MAX {
+            return Err("Stack overflow.".to_string());
+        }
+
+        let environment = Environment::new(function.closure.clone());
+        if let Some(this) = &function.this {
+            environment.define("this".into(), this.clone());
+        }
+        for (param, argument) in declaration.params.iter().zip(arguments) {
+            environment.define(param.clone(), argument);
+        }
+
+        self.frames += 1;
+        let result = self.execute_block(&declaration.body, environment);
+        self.frames -= 1;
+
+        match result {
+            Err(Unwind::Error(message)) => Err(message),
+            _ if declaration.kind == FunctionKind::Initializer => {
+                Ok(function.this.clone().unwrap())
+            }
+            Err(Unwind::Return(value)) => Ok(value),
+            Ok(()) => Ok(Value::Nil),
+        }
+    }
+}
+
+#[allow(clippy::neg_cmp_op_on_partial_ord)]
+fn binary(operator: TokenKind, left: Value, right: Value) -> Result<Value, String> {
+    use TokenKind::*;
+
+    match (operator, &left, &right) {
+        (EqualEqual, _, _) => Ok(Value::Bool(left == right)),
+        (BangEqual, _, _) => Ok(Value::Bool(left != right)),
+        (Plus, Value::String(a), Value::String(b)) => {
+            Ok(Value::String(format!("{}{}", a, b).into()))
+        }
+        (_, Value::Number(a), Value::Number(b)) => Ok(match operator {
+            Plus => Value::Number(a + b),
+            Minus => Value::Number(a - b),
+            Star => Value::Number(a * b),
+            Slash => Value::Number(a / b),
+            Percent => Value::Number(a % b),
+            Greater => Value::Bool(a > b),
+            Less => Value::Bool(a < b),
+            // the `Vm` negates the opposite comparison, which differs for NaN
+            GreaterEqual => Value::Bool(!(a < b)),
+            LessEqual => Value::Bool(!(a > b)),
+            _ => unreachable!(),
+        }),
+        _ => Err(format!(
+            "Operands must be numbers, not {} and {}.",
+            left.type_name(),
+            right.type_name()
+        )),
+    }
+}
+
+fn check_arity(arity: usize, argument_count: usize) -> Result<(), String> {
+    if arity != argument_count {
+        return Err(format!(
+            "Expected {} arguments but got {}.",
+            arity, argument_count
+        ));
+    }
+    Ok(())
+}
+
+fn bound(method: &Function, this: Value) -> Function {
+    Function {
+        declaration: method.declaration.clone(),
+        closure: method.closure.clone(),
+        this: Some(this),
+    }
+}
+
+/// Looks up a method of `class`, bound to `this`
+fn bind(class: &Class, name: &str, this: Value) -> Result<Value, String> {
+    match class.methods.get(name) {
+        Some(method) => Ok(Value::Function(Rc::new(bound(method, this)))),
+        None => Err(format!("Undefined property '{}'.", name)),
+    }
+}
+
+fn clock() -> Value {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+    Value::Number(now.map_or(0.0, |now| now.as_secs_f64()))
+}
@@ -0,0 +1,178 @@
+//! An interactive session that keeps its variables from one input to the next
+
+use crate::diagnostic;
+use crate::scanner::Scanner;
+use crate::scanner::TokenKind;
+use crate::vm::InterpretError;
+use crate::vm::Vm;
+use crate::vm::VmOptions;
+
+use std::io::Write;
+
+const HELP: &str = "\
+Enter declarations and statements to run them, or an expression to print its value.
+  :globals        list the global variables and their values
+  :disasm CODE    show the bytecode CODE compiles to
+  :load FILE      run a script in this session
+  :reset          forget every variable
+  :help           show this message
+  :quit           end the session, as does end of input
+";
+
+pub struct Repl {
+    /// Makes the `Vm` at the start of the session and on `:reset`
+    new_vm: Box<dyn Fn() -> Vm>,
+    vm: Vm,
+    /// The lines of a declaration or statement that isn't finished yet
+    pending: String,
+}
+
+impl Repl {
+    pub fn new(new_vm: impl Fn() -> Vm + 'static) -> Repl {
+        let vm = new_vm();
+        Repl {
+            new_vm: Box::new(new_vm),
+            vm,
+            pending: String::new(),
+        }
+    }
+
+    /// What to show before reading the next line
+    pub fn prompt(&self) -> &'static str {
+        if self.pending.is_empty() {
+            "> "
+        } else {
+            "... "
+        }
+    }
+
+    /// Handles a line of input, writing any errors and the output of commands to `output`
+    ///
+    /// Returns `false` once the session should end.
+    pub fn line(&mut self, line: &str, output: &mut dyn Write) -> std::io::Result<bool> {
+        if self.pending.is_empty() {
+            if let Some(command) = line.trim().strip_prefix(':') {
+                return self.command(command, output);
+            }
+            if line.trim().is_empty() {
+                return Ok(true);
+            }
+        }
+
+        self.pending.push_str(line);
+        if !self.pending.ends_with('\n') {
+            self.pending.push('\n');
+        }
+        if is_incomplete(&self.pending) {
+            return Ok(true);
+        }
+
+        let source = std::mem::take(&mut self.pending);
+        self.evaluate(&source, output)?;
+        Ok(true)
+    }
+
+    /// Runs a complete input, printing its value if it is a lone expression
+    fn evaluate(&mut self, source: &str, output: &mut dyn Write) -> std::io::Result<()> {
+        let trimmed = source.trim_end();
+        if !trimmed.ends_with(';') && !trimmed.ends_with('}') {
+            let print = format!("print ({});", trimmed);
+            match self.vm.interpret(&print) {
+                // not an expression, so let the original input explain what's wrong with it
+                Err(InterpretError::CompileError(_)) => (),
+                result => return report(result, &print, output),
+            }
+        }
+
+        let result = self.vm.interpret(source);
+        report(result, source, output)
+    }
+
+    fn command(&mut self, command: &str, output: &mut dyn Write) -> std::io::Result<bool> {
+        let (name, argument) = match command.split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, argument.trim()),
+            None => (command, ""),
+        };
+
+        match name {
+            "globals" => {
+                for (name, value) in self.vm.globals() {
+                    match value.as_string() {
+                        Some(string) => writeln!(output, "{} = {:?}", name, string)?,
+                        None => writeln!(output, "{} = {}", name, value)?,
+                    }
+                }
+            }
+            "disasm" => match self.vm.disassemble(argument) {
+                Ok(listing) => write!(output, "{}", listing)?,
+                Err(error) => report(Err(error), argument, output)?,
+            },
+            "load" => match std::fs::read_to_string(argument) {
+                Ok(source) => {
+                    let result = self.vm.interpret(&source);
+                    report(result, &source, output)?;
+                }
+                Err(error) => writeln!(output, "Could not read {}: {}", argument, error)?,
+            },
+            "reset" => self.vm = (self.new_vm)(),
+            "help" => write!(output, "{}", HELP)?,
+            "quit" => return Ok(false),
+            _ => writeln!(output, "Unknown command `:{}`, try `:help`", name)?,
+        }
+
+        Ok(true)
+    }
+}
+
+/// Writes an error, with compile errors shown alongside the source they point at
+fn report(
+    result: Result<(), InterpretError>,
+    source: &str,
+    output: &mut dyn Write,
+) -> std::io::Result<()> {
+    match result {
+        Ok(()) => Ok(()),
+        Err(InterpretError::CompileError(diagnostics)) => {
+            write!(output, "{}", diagnostic::render_all(&diagnostics, source))
+        }
+        Err(error) => writeln!(output, "{}", error),
+    }
+}
+
+/// Whether the source ends inside a string or unclosed brackets, so there must be more to come
+fn is_incomplete(source: &str) -> bool {
+    let mut scanner = Scanner::new(source);
+    let mut depth = 0isize;
+
+    loop {
+        match scanner.scan_token().kind {
+            TokenKind::LeftBrace | TokenKind::LeftParen => depth += 1,
+            TokenKind::RightBrace | TokenKind::RightParen => depth -= 1,
+            TokenKind::UnterminatedString => return true,
+            TokenKind::Eof => return depth > 0,
+            _ => (),
+        }
+    }
+}
+
+/// Runs a session on stdin and stdout until the input ends
+pub fn run(options: VmOptions) -> std::io::Result<()> {
+    let mut repl = Repl::new(move || Vm::with_options(options));
+    let mut stdout = std::io::stdout();
+
+    loop {
+        write!(stdout, "{}", repl.prompt())?;
+        stdout.flush()?;
+
+        // stdin's own buffer is shared with `readLine`, so neither swallows the other's input
+        let mut line = String::new();
+        if std::io::stdin().read_line(&mut line)? == 0 {
+            writeln!(stdout)?;
+            return Ok(());
+        }
+
+        if !repl.line(&line, &mut stdout)? {
+            return Ok(());
+        }
+    }
+}
@@ -1,25 +1,45 @@
+use crate::diagnostic::Span;
+
 #[derive(Default)]
 pub struct Scanner<'a> {
     source: &'a str,
     start: usize,
     current: usize,
     line: usize,
+    /// How far along the current line columns have been counted, as a byte offset
+    counted: usize,
+    /// The column at `counted`, kept so long lines aren't recounted for every token
+    column: usize,
 }
 
 #[derive(Clone, Debug)]
 pub struct Token<'a> {
     pub kind: TokenKind,
     pub span: &'a str,
+    /// The line the token starts on
     pub line: usize,
+    /// The one-based column, in characters, of the token's first character
+    pub column: usize,
+    /// The byte offset of the token in the source
+    pub offset: usize,
+}
+
+impl Token<'_> {
+    /// The bytes of the source the token covers
+    pub fn range(&self) -> Span {
+        Span::new(self.offset, self.offset + self.span.len())
+    }
 }
 
 impl<'s> Scanner<'s> {
-    pub fn new(source: &str) -> Scanner {
+    pub fn new(source: &str) -> Scanner<'_> {
         Scanner {
             source,
             start: 0,
             current: 0,
             line: 1,
+            counted: 0,
+            column: 1,
         }
     }
 
@@ -27,6 +47,8 @@ impl<'s> Scanner<'s> {
         self.skip_whitespace();
 
         self.start = self.current;
+        let line = self.line;
+        let column = self.column();
 
         let kind = if self.is_at_end() {
             TokenKind::Eof
@@ -79,17 +101,40 @@ impl<'s> Scanner<'s> {
                 b'"' => self.string(),
                 b'0'..=b'9' => self.number(),
                 b'a'..=b'z' | b'A'..=b'Z' | b'_' => self.identifier(),
-                _ => UnexpectedCharacter,
+                _ => {
+                    // take the rest of a multi-byte character, so the span is still valid UTF-8
+                    while self.peek() & 0xc0 == 0x80 {
+                        self.advance();
+                    }
+                    UnexpectedCharacter
+                }
             }
         };
 
         Token {
             kind,
             span: &self.source[self.start..self.current],
-            line: self.line,
+            line,
+            column,
+            offset: self.start,
         }
     }
 
+    /// The column of the start of the current token, counting characters rather than bytes
+    fn column(&mut self) -> usize {
+        let before = &self.source.as_bytes()[self.counted..self.start];
+        // skip UTF-8 continuation bytes
+        self.column += before.iter().filter(|byte| (*byte & 0xc0) != 0x80).count();
+        self.counted = self.start;
+        self.column
+    }
+
+    fn newline(&mut self) {
+        self.line += 1;
+        self.counted = self.current + 1;
+        self.column = 1;
+    }
+
     fn is_at_end(&self) -> bool {
         self.current >= self.source.len()
     }
@@ -138,7 +183,7 @@ impl<'s> Scanner<'s> {
                     self.advance();
                 }
                 b'\n' => {
-                    self.line += 1;
+                    self.newline();
                     self.advance();
                 }
                 b'/' => {
@@ -158,7 +203,7 @@ impl<'s> Scanner<'s> {
     fn string(&mut self) -> TokenKind {
         while self.peek() != b'"' && !self.is_at_end() {
             if self.peek() == b'\n' {
-                self.line += 1;
+                self.newline();
             }
             if self.peek() == b'\\' {
                 // advance to skip the check for double quote
@@ -167,6 +212,9 @@ impl<'s> Scanner<'s> {
                 if self.is_at_end() {
                     return TokenKind::UnterminatedString;
                 }
+                if self.peek() == b'\n' {
+                    self.newline();
+                }
             }
             self.advance();
         }
@@ -1,4 +1,7 @@
+use crate::value::Value;
+
 use std::collections::HashMap;
+use std::rc::Rc;
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct Symbol(usize);
@@ -9,20 +12,109 @@ impl std::fmt::Display for Symbol {
     }
 }
 
+/// Maps each distinct string to a unique `Symbol` and back
+///
+/// Symbols are never reused, even after their string is removed.
 #[derive(Default)]
 pub struct Interner {
-    strings: HashMap<String, Symbol>,
+    strings: HashMap<Rc<str>, Symbol>,
+    symbols: HashMap<Symbol, Rc<str>>,
     next_string_id: usize,
 }
 
 impl Interner {
-    pub fn intern_string(&mut self, string: String) -> Symbol {
+    pub fn lookup(&self, string: &str) -> Option<Symbol> {
+        self.strings.get(string).copied()
+    }
+
+    /// Returns the string's symbol, creating one if the string hasn't been interned yet
+    pub fn intern_string(&mut self, string: &str) -> Symbol {
+        if let Some(id) = self.lookup(string) {
+            return id;
+        }
+
         let id = Symbol(self.next_string_id);
 
         self.next_string_id += 1;
 
-        self.strings.insert(string, id);
+        let string: Rc<str> = string.into();
+        self.strings.insert(string.clone(), id);
+        self.symbols.insert(id, string);
 
         id
     }
+
+    /// The text of an interned string, shared with the interner
+    pub fn resolve(&self, symbol: Symbol) -> Option<&Rc<str>> {
+        self.symbols.get(&symbol)
+    }
+
+    pub fn remove(&mut self, symbol: Symbol) {
+        if let Some(string) = self.symbols.remove(&symbol) {
+            self.strings.remove(&string);
+        }
+    }
+}
+
+/// Global variables, each given a fixed slot the first time its name is compiled
+///
+/// Slots outlive any one compilation, so code compiled later in the same `Vm` sees the same
+/// variables.
+#[derive(Default)]
+pub struct Globals {
+    slots: HashMap<Symbol, usize>,
+    names: Vec<Symbol>,
+    /// `None` until the variable is defined
+    values: Vec<Option<Value>>,
+}
+
+impl Globals {
+    /// Returns the slot for a name, adding an undefined variable if it has none yet
+    pub fn resolve(&mut self, name: Symbol) -> usize {
+        if let Some(slot) = self.slots.get(&name) {
+            return *slot;
+        }
+
+        let slot = self.names.len();
+        self.slots.insert(name, slot);
+        self.names.push(name);
+        self.values.push(None);
+        slot
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn name(&self, slot: usize) -> Symbol {
+        self.names[slot]
+    }
+
+    pub fn get(&self, slot: usize) -> Option<Value> {
+        self.values[slot]
+    }
+
+    pub fn define(&mut self, slot: usize, value: Value) {
+        self.values[slot] = Some(value);
+    }
+
+    /// Assigns to an already defined variable, returning whether it was defined
+    pub fn set(&mut self, slot: usize, value: Value) -> bool {
+        match &mut self.values[slot] {
+            Some(old) => {
+                *old = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Every slot's name and value, if defined
+    pub fn iter(&self) -> impl Iterator<Item = (Symbol, Option<Value>)> + '_ {
+        self.names.iter().copied().zip(self.values.iter().copied())
+    }
 }
@@ -1,121 +1,476 @@
+use crate::chunk::Chunk;
+use crate::chunk::GlobalNames;
+use crate::heap::ObjRef;
+use crate::table::Symbol;
 use crate::vm::InterpretError;
+use crate::vm::Vm;
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::marker::PhantomData;
 use std::rc::Rc;
 
-#[derive(Clone)]
+#[cfg(feature = "nan-boxing")]
+mod nan_boxing;
+
+#[cfg(feature = "nan-boxing")]
+pub use nan_boxing::Value;
+
+/// A Lox value
+///
+/// A value holding an object is only made by the `Vm` and its compiler, which keep the object
+/// reachable for as long as the value is used, so reading through it is safe. Code outside the
+/// crate only sees such values as a `ValueRef`.
+#[cfg(not(feature = "nan-boxing"))]
+#[derive(Clone, Copy)]
 pub enum Value {
     //~ #[default]
     Nil,
     Boolean(bool),
     Number(f64),
-    Object(Rc<Object>),
+    Object(ObjRef),
 }
 
-pub struct Object {
-    pub kind: ObjectKind,
-}
+/// The primitives every representation of `Value` provides; everything else is built on these
+#[cfg(not(feature = "nan-boxing"))]
+impl Value {
+    pub const NIL: Value = Value::Nil;
 
-pub enum ObjectKind {
-    String(String),
-}
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
 
-impl Value {
-    pub fn truthiness(&self) -> bool {
+    pub fn as_bool(&self) -> Option<bool> {
         match self {
-            Value::Nil => false,
-            Value::Boolean(b) => *b,
-            _ => true,
+            Value::Boolean(b) => Some(*b),
+            _ => None,
         }
     }
 
-    pub fn is_string(&self) -> bool {
+    pub fn as_number(&self) -> Option<f64> {
         match self {
-            Value::Object(p) => match &p.kind {
-                ObjectKind::String(_) => true,
-                //~ _ => false,
-            },
-            _ => false,
+            Value::Number(n) => Some(*n),
+            _ => None,
         }
     }
 
-    pub fn as_string(&self) -> Option<&str> {
+    pub fn as_object(&self) -> Option<ObjRef> {
         match self {
-            Value::Object(p) => match &p.kind {
-                ObjectKind::String(s) => Some(s),
-                //~ _ => false,
-            },
+            Value::Object(object) => Some(*object),
             _ => None,
         }
     }
 }
 
-/*
-#[derive(Default)]
-pub struct TraceMap {
-    pub vec: Vec<(Rc<Object>, usize)>,
+#[cfg(not(feature = "nan-boxing"))]
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Number(n)
+    }
 }
 
-impl TraceMap {
-    /// adds one to the count for a node, or inserts it if it's not existent
-    /// returns the count of the node
-    fn add(&mut self, key: Rc<Object>) -> usize {
-        let index = 'index: {
-            for (i, (k, _)) in self.vec.iter().enumerate() {
-                if Rc::ptr_eq(&key, k) {
-                    break 'index Some(i);
-                }
-            }
-            None
-        };
+#[cfg(not(feature = "nan-boxing"))]
+impl From<bool> for Value {
+    fn from(n: bool) -> Self {
+        Value::Boolean(n)
+    }
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+impl From<ObjRef> for Value {
+    fn from(object: ObjRef) -> Self {
+        Value::Object(object)
+    }
+}
+
+pub struct Object {
+    pub kind: ObjectKind,
+    /// Set while the garbage collector has found the object reachable
+    pub(crate) marked: Cell<bool>,
+}
 
-        if let Some(i) = index {
-            self.vec[i].1 += 1;
-            self.vec[i].1
-        } else {
-            self.vec.push((key, 1));
-            1
+impl Object {
+    pub(crate) fn new(kind: ObjectKind) -> Object {
+        Object {
+            kind,
+            marked: Cell::new(false),
         }
     }
 
-    fn get_count(&mut self, key: Rc<Object>) -> usize {
-        let index = 'index: {
-            for (i, (k, _)) in self.vec.iter().enumerate() {
-                if Rc::ptr_eq(&key, k) {
-                    break 'index Some(i);
+    /// The number of bytes the object accounts for on the heap
+    ///
+    /// The fields of an instance and the methods of a class are counted at the capacity of
+    /// their table, so whoever grows one must tell the heap by how much (see `update_table`).
+    pub fn size(&self) -> usize {
+        std::mem::size_of::<Object>()
+            + match &self.kind {
+                ObjectKind::String(s) => s.as_str().len(),
+                ObjectKind::Function(function) => function.chunk.size(),
+                ObjectKind::Closure(closure) => {
+                    closure.upvalues.len() * std::mem::size_of::<ObjRef>()
                 }
+                ObjectKind::Class(class) => table_size(&class.methods),
+                ObjectKind::Instance(instance) => table_size(&instance.fields),
+                ObjectKind::Upvalue(_) | ObjectKind::BoundMethod(_) | ObjectKind::Native(_) => 0,
             }
-            None
-        };
+    }
 
-        if let Some(i) = index {
-            self.vec[i].1
-        } else {
-            0
+    pub fn as_string(&self) -> Option<&InternedString> {
+        match &self.kind {
+            ObjectKind::String(s) => Some(s),
+            _ => None,
         }
     }
 
-    pub fn trace(&mut self, value: &Value) {
-        match value {
-            Value::Object(obj) => {
-                if self.add(obj.clone()) == 1 {
-                    match &obj.kind {
-                        ObjectKind::String(_) => (),
-                    }
-                }
+    pub fn as_function(&self) -> Option<&Function> {
+        match &self.kind {
+            ObjectKind::Function(function) => Some(function),
+            _ => None,
+        }
+    }
+
+    pub fn as_closure(&self) -> Option<&Closure> {
+        match &self.kind {
+            ObjectKind::Closure(closure) => Some(closure),
+            _ => None,
+        }
+    }
+
+    pub fn as_upvalue(&self) -> Option<&RefCell<Upvalue>> {
+        match &self.kind {
+            ObjectKind::Upvalue(upvalue) => Some(upvalue),
+            _ => None,
+        }
+    }
+
+    pub fn as_class(&self) -> Option<&Class> {
+        match &self.kind {
+            ObjectKind::Class(class) => Some(class),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance(&self) -> Option<&Instance> {
+        match &self.kind {
+            ObjectKind::Instance(instance) => Some(instance),
+            _ => None,
+        }
+    }
+}
+
+pub enum ObjectKind {
+    String(InternedString),
+    Function(Function),
+    Closure(Closure),
+    Upvalue(RefCell<Upvalue>),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    Native(Native),
+}
+
+/// A string whose text is unique among live strings, so strings compare by symbol
+pub struct InternedString {
+    pub symbol: Symbol,
+    text: Rc<str>,
+}
+
+impl InternedString {
+    pub(crate) fn new(symbol: Symbol, text: Rc<str>) -> InternedString {
+        InternedString { symbol, text }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+}
+
+impl std::fmt::Display for InternedString {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+pub struct Function {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    /// `None` for the top-level script
+    pub name: Option<String>,
+}
+
+impl Function {
+    /// Writes the function's listing, then those of the functions nested in it in the order of
+    /// their constants, which is the order the assembler expects them in
+    pub fn write_disassembly(
+        &self,
+        f: &mut dyn std::fmt::Write,
+        globals: &GlobalNames,
+    ) -> std::fmt::Result {
+        let header = match &self.name {
+            Some(name) if self.arity > 0 => format!("{} arity {}", name, self.arity),
+            Some(name) => name.clone(),
+            None => "<script>".to_string(),
+        };
+        self.chunk.write_disassembly(f, &header, globals)?;
+
+        for constant in self.chunk.constants() {
+            if let Some(function) = constant.as_function() {
+                function.write_disassembly(f, globals)?;
             }
-            _ => (),
         }
+
+        Ok(())
+    }
+}
+
+/// A function together with the variables it captured from enclosing scopes
+pub struct Closure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+impl Closure {
+    pub fn function(&self) -> &Function {
+        // SAFETY: the closure keeps its function reachable, and is itself reachable while in use
+        unsafe { self.function.get() }.as_function().unwrap()
+    }
+}
+
+/// A captured variable
+pub enum Upvalue {
+    /// The variable is still live in the given stack slot
+    Open(usize),
+    /// The variable has left the stack and is owned by the upvalue
+    Closed(Value),
+}
+
+/// The bytes a table of fields or methods accounts for on the heap
+fn table_size<V>(table: &RefCell<HashMap<Symbol, V>>) -> usize {
+    table.borrow().capacity() * std::mem::size_of::<(Symbol, V)>()
+}
+
+/// Changes a table of fields or methods, returning how many bytes it grew by
+pub(crate) fn update_table<V>(
+    table: &RefCell<HashMap<Symbol, V>>,
+    update: impl FnOnce(&mut HashMap<Symbol, V>),
+) -> usize {
+    let before = table_size(table);
+    update(&mut table.borrow_mut());
+    // tables only ever have entries added, so they never shrink
+    table_size(table) - before
+}
+
+pub struct Class {
+    /// The class's name, a string object
+    pub name: ObjRef,
+    /// Maps method names to closures
+    pub methods: RefCell<HashMap<Symbol, ObjRef>>,
+}
+
+impl Class {
+    pub fn name(&self) -> &str {
+        // SAFETY: the class keeps its name reachable
+        unsafe { self.name.get() }.as_string().unwrap().as_str()
+    }
+}
+
+pub struct Instance {
+    pub class: ObjRef,
+    pub fields: RefCell<HashMap<Symbol, Value>>,
+}
+
+impl Instance {
+    pub fn class(&self) -> &Class {
+        // SAFETY: the instance keeps its class reachable
+        unsafe { self.class.get() }.as_class().unwrap()
+    }
+}
+
+/// A method closure paired with the instance it was accessed on
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+impl BoundMethod {
+    /// The method's function, which the `Vm` only binds once it has checked it is a closure
+    pub fn function(&self) -> &Function {
+        // SAFETY: the bound method keeps its method reachable
+        unsafe { self.method.get() }
+            .as_closure()
+            .expect("bound method is not a closure")
+            .function()
+    }
+}
+
+/// A Rust function scripts can call, given the `Vm` and the arguments
+///
+/// An `Err` message is raised in the script as a runtime error.
+pub type NativeFn = for<'vm> fn(&'vm mut Vm, &[ValueRef<'vm>]) -> Result<ValueRef<'vm>, String>;
+
+/// A value lent out by a `Vm`, which keeps any object it refers to alive for `'vm`
+///
+/// Natives get their arguments as these, and `Vm::new_string` and `Vm::globals` hand them out
+/// borrowing the `Vm`, so a value can't be held across a collection that might free its object.
+#[derive(Clone, Copy, PartialEq)]
+pub struct ValueRef<'vm> {
+    value: Value,
+    vm: PhantomData<&'vm Vm>,
+}
+
+impl<'vm> ValueRef<'vm> {
+    pub const NIL: Self = ValueRef {
+        value: Value::NIL,
+        vm: PhantomData,
+    };
+
+    /// Lends out a value, which the caller must keep reachable for `'vm`
+    pub(crate) fn new(value: Value) -> ValueRef<'vm> {
+        ValueRef {
+            value,
+            vm: PhantomData,
+        }
+    }
+
+    pub(crate) fn value(self) -> Value {
+        self.value
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.value.is_nil()
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        self.value.as_bool()
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        self.value.as_number()
+    }
+
+    pub fn as_string(&self) -> Option<&'vm str> {
+        let object = self.value.as_object()?;
+        // SAFETY: the `Vm` keeps the object alive for `'vm`
+        let string = unsafe { object.get() }.as_string()?;
+        Some(string.as_str())
+    }
+
+    pub fn truthiness(&self) -> bool {
+        self.value.truthiness()
+    }
+
+    /// The kind of value, as named in error messages
+    pub fn type_name(&self) -> &'static str {
+        self.value.type_name()
+    }
+}
+
+impl From<f64> for ValueRef<'_> {
+    fn from(n: f64) -> Self {
+        ValueRef::new(n.into())
+    }
+}
+
+impl From<bool> for ValueRef<'_> {
+    fn from(b: bool) -> Self {
+        ValueRef::new(b.into())
+    }
+}
+
+impl std::fmt::Display for ValueRef<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.value)
+    }
+}
+
+impl std::fmt::Debug for ValueRef<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self.value)
+    }
+}
+
+/// A function provided by the host rather than written in Lox
+pub struct Native {
+    pub name: String,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+impl std::fmt::Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+impl Value {
+    /// The object the value refers to, if it is one
+    pub(crate) fn object(&self) -> Option<&Object> {
+        // SAFETY: values only refer to objects that are kept reachable while they are used
+        self.as_object().map(|object| unsafe { object.get() })
+    }
+
+    pub fn truthiness(&self) -> bool {
+        match self.as_bool() {
+            Some(b) => b,
+            None => !self.is_nil(),
+        }
+    }
+
+    pub fn is_number(&self) -> bool {
+        self.as_number().is_some()
+    }
+
+    pub fn is_string(&self) -> bool {
+        self.object()
+            .is_some_and(|object| matches!(&object.kind, ObjectKind::String(_)))
+    }
+
+    /// The kind of value, as named in error messages
+    pub fn type_name(&self) -> &'static str {
+        if self.is_nil() {
+            return "nil";
+        }
+        if self.as_bool().is_some() {
+            return "a boolean";
+        }
+        if self.is_number() {
+            return "a number";
+        }
+        match self.object().map(|object| &object.kind) {
+            Some(ObjectKind::String(_)) => "a string",
+            Some(ObjectKind::Class(_)) => "a class",
+            Some(ObjectKind::Instance(_)) => "an instance",
+            Some(_) => "a function",
+            None => unreachable!(),
+        }
+    }
+
+    pub fn as_function(&self) -> Option<&Function> {
+        self.object().and_then(Object::as_function)
+    }
+
+    pub fn as_string(&self) -> Option<&str> {
+        self.object()
+            .and_then(Object::as_string)
+            .map(InternedString::as_str)
+    }
+
+    pub fn as_symbol(&self) -> Option<Symbol> {
+        self.object()
+            .and_then(Object::as_string)
+            .map(|string| string.symbol)
     }
 }
-*/
 
 impl TryFrom<Value> for f64 {
     type Error = InterpretError;
 
     fn try_from(value: Value) -> Result<Self, Self::Error> {
-        match value {
-            Value::Number(n) => Ok(n),
-            _ => Err(InterpretError::Ice("Not a number")),
-        }
+        value.as_number().ok_or(InterpretError::Ice("Not a number"))
     }
 }
 
@@ -123,85 +478,124 @@ impl TryFrom<Value> for String {
     type Error = InterpretError;
 
     fn try_from(value: Value) -> Result<Self, Self::Error> {
-        match value {
-            Value::Object(p) => match &p.kind {
-                ObjectKind::String(s) => Ok(s.to_string()),
-                //~ _ => Err(InterpretError::Ice("Not a string")),
-            },
-            _ => Err(InterpretError::Ice("Not a string")),
-        }
+        value
+            .as_string()
+            .map(str::to_string)
+            .ok_or(InterpretError::Ice("Not a string"))
     }
 }
 
-impl From<f64> for Value {
-    fn from(n: f64) -> Self {
-        Value::Number(n)
+impl From<InternedString> for Object {
+    fn from(s: InternedString) -> Self {
+        Object::new(ObjectKind::String(s))
     }
 }
 
-impl From<bool> for Value {
-    fn from(n: bool) -> Self {
-        Value::Boolean(n)
+impl From<Function> for Object {
+    fn from(function: Function) -> Self {
+        Object::new(ObjectKind::Function(function))
     }
 }
 
-impl From<String> for Value {
-    fn from(s: String) -> Self {
-        Value::Object(
-            Object {
-                kind: ObjectKind::String(s),
-            }
-            .into(),
-        )
+impl From<Closure> for Object {
+    fn from(closure: Closure) -> Self {
+        Object::new(ObjectKind::Closure(closure))
     }
 }
 
-impl From<String> for Object {
-    fn from(s: String) -> Self {
-        Object {
-            kind: ObjectKind::String(s),
-        }
+impl From<Class> for Object {
+    fn from(class: Class) -> Self {
+        Object::new(ObjectKind::Class(class))
+    }
+}
+
+impl From<Instance> for Object {
+    fn from(instance: Instance) -> Self {
+        Object::new(ObjectKind::Instance(instance))
+    }
+}
+
+impl From<BoundMethod> for Object {
+    fn from(bound: BoundMethod) -> Self {
+        Object::new(ObjectKind::BoundMethod(bound))
+    }
+}
+
+impl From<Native> for Object {
+    fn from(native: Native) -> Self {
+        Object::new(ObjectKind::Native(native))
+    }
+}
+
+impl From<Upvalue> for Object {
+    fn from(upvalue: Upvalue) -> Self {
+        Object::new(ObjectKind::Upvalue(RefCell::new(upvalue)))
     }
 }
 
 impl std::fmt::Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            Value::Nil => write!(f, "nil"),
-            Value::Boolean(b) => write!(f, "{}", b),
-            Value::Number(n) => write!(f, "{}", n),
-            Value::Object(p) => match &p.kind {
-                ObjectKind::String(s) => write!(f, "{}", s),
-                //~ _ => write!(f, "Object#{:p}", p),
-            },
+        if let Some(b) = self.as_bool() {
+            return write!(f, "{}", b);
+        }
+        if let Some(n) = self.as_number() {
+            return write!(f, "{}", n);
+        }
+        let Some(object) = self.object() else {
+            return write!(f, "nil");
+        };
+
+        match &object.kind {
+            ObjectKind::String(s) => write!(f, "{}", s),
+            ObjectKind::Function(function) => write!(f, "{}", function),
+            ObjectKind::Closure(closure) => write!(f, "{}", closure.function()),
+            ObjectKind::Upvalue(_) => write!(f, "upvalue"),
+            ObjectKind::Class(class) => write!(f, "{}", class.name()),
+            ObjectKind::Instance(instance) => write!(f, "{} instance", instance.class().name()),
+            ObjectKind::BoundMethod(bound) => write!(f, "{}", bound.function()),
+            ObjectKind::Native(native) => write!(f, "<native fn {}>", native.name),
         }
     }
 }
 
 impl std::fmt::Debug for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            Value::Nil | Value::Boolean(_) | Value::Number(_) => write!(f, "#{}", self),
-            Value::Object(p) => match &p.kind {
-                ObjectKind::String(s) => write!(f, "String#\"{}\"", s.escape_debug()),
-                //~ _ => write!(f, "Object#{:p}", p),
+        let Some(object) = self.object() else {
+            return write!(f, "#{}", self);
+        };
+
+        match &object.kind {
+            ObjectKind::String(s) => write!(f, "String#\"{}\"", s.as_str().escape_debug()),
+            ObjectKind::Function(function) => write!(f, "Function#{}", function),
+            ObjectKind::Closure(closure) => write!(f, "Closure#{}", closure.function()),
+            ObjectKind::Upvalue(upvalue) => match &*upvalue.borrow() {
+                Upvalue::Open(slot) => write!(f, "Upvalue#open({})", slot),
+                Upvalue::Closed(value) => write!(f, "Upvalue#closed({:?})", value),
             },
+            ObjectKind::Class(class) => write!(f, "Class#{}", class.name()),
+            ObjectKind::Instance(instance) => {
+                write!(f, "Instance#{}#{:p}", instance.class().name(), object)
+            }
+            ObjectKind::BoundMethod(bound) => write!(f, "BoundMethod#{}", bound.function()),
+            ObjectKind::Native(native) => write!(f, "Native#{}", native.name),
         }
     }
 }
 
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
-        use Value::*;
-        match (self, other) {
-            (Nil, Nil) => true,
-            (Boolean(a), Boolean(b)) => a == b,
-            (Number(a), Number(b)) => a == b,
-            (Object(a), Object(b)) => match (&a.kind, &b.kind) {
-                (ObjectKind::String(s_a), ObjectKind::String(s_b)) => s_a == s_b,
-                //~ _ => Rc::ptr_eq(a, b),
-            },
-            (Nil, _) | (Boolean(_), _) | (Number(_), _) | (Object(_), _) => false,
+        if let (Some(a), Some(b)) = (self.as_number(), other.as_number()) {
+            return a == b;
+        }
+        if let (Some(a), Some(b)) = (self.object(), other.object()) {
+            return match (&a.kind, &b.kind) {
+                (ObjectKind::String(s_a), ObjectKind::String(s_b)) => s_a.symbol == s_b.symbol,
+                _ => std::ptr::eq(a, b),
+            };
+        }
+        if let (Some(a), Some(b)) = (self.as_bool(), other.as_bool()) {
+            return a == b;
         }
+        self.is_nil() && other.is_nil()
     }
 }
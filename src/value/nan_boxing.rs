@@ -0,0 +1,94 @@
+//! A `Value` packed into the bits of a single `f64`
+//!
+//! Numbers are stored as themselves. Everything else hides in the payload of a quiet NaN that
+//! no arithmetic produces: the low bits hold a tag for nil and the booleans, and objects set the
+//! sign bit and keep their pointer in the low 48 bits.
+
+use crate::heap::ObjRef;
+use crate::value::Object;
+
+/// The exponent and quiet bits, plus one more so real NaNs never look like boxed values
+const QUIET_NAN: u64 = 0x7ffc_0000_0000_0000;
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+
+const TAG_NIL: u64 = 1;
+const TAG_FALSE: u64 = 2;
+const TAG_TRUE: u64 = 3;
+
+const NIL: u64 = QUIET_NAN | TAG_NIL;
+const FALSE: u64 = QUIET_NAN | TAG_FALSE;
+const TRUE: u64 = QUIET_NAN | TAG_TRUE;
+
+/// A Lox value
+///
+/// As with the enum representation, a value holding an object is only made by the `Vm` and its
+/// compiler, which keep the object reachable for as long as the value is used.
+#[derive(Clone, Copy)]
+pub struct Value(u64);
+
+impl Value {
+    pub const NIL: Value = Value(NIL);
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == NIL
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.0 {
+            TRUE => Some(true),
+            FALSE => Some(false),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        if self.0 & QUIET_NAN != QUIET_NAN {
+            Some(f64::from_bits(self.0))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_object(&self) -> Option<ObjRef> {
+        if self.0 & (QUIET_NAN | SIGN_BIT) == QUIET_NAN | SIGN_BIT {
+            let pointer = (self.0 & !(QUIET_NAN | SIGN_BIT)) as usize as *const Object;
+            // SAFETY: only `From<ObjRef>` sets these bits, and it stores a live object's pointer
+            Some(unsafe { ObjRef::from_ptr(pointer) })
+        } else {
+            None
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        // a NaN with an unlucky payload would read back as some other value
+        if n.is_nan() {
+            Value(f64::NAN.to_bits())
+        } else {
+            Value(n.to_bits())
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        if b {
+            Value(TRUE)
+        } else {
+            Value(FALSE)
+        }
+    }
+}
+
+impl From<ObjRef> for Value {
+    fn from(object: ObjRef) -> Self {
+        let address = object.as_ptr() as usize as u64;
+        debug_assert_eq!(
+            address & (QUIET_NAN | SIGN_BIT),
+            0,
+            "object pointer does not fit in 48 bits"
+        );
+        Value(SIGN_BIT | QUIET_NAN | address)
+    }
+}
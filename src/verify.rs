@@ -0,0 +1,319 @@
+//! Checks that a function's bytecode is safe to run before the `Vm` trusts it
+//!
+//! The verifier decodes every instruction, checks its operands against the constant pool,
+//! globals and upvalues, then follows every path through the code to check that each
+//! instruction sees the same stack depth however it is reached and never pops more than the
+//! function pushed. Nested functions are verified along with the functions that contain them.
+
+use crate::chunk::OpCode;
+use crate::chunk::Operand;
+use crate::value::Function;
+use crate::value::Value;
+
+/// How deeply functions may be nested in each other, more than the parser lets source code nest
+/// them
+pub(crate) const NESTING_MAX: usize = 300;
+
+/// Why a function's bytecode was rejected
+#[derive(Debug)]
+pub struct VerifyError {
+    /// The name of the function containing the bad instruction, `None` for the script
+    pub function: Option<String>,
+    /// The offset of the bad instruction in its chunk
+    pub offset: usize,
+    pub message: String,
+}
+
+impl std::error::Error for VerifyError {}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "in {}() at {:04}: {}", name, self.offset, self.message),
+            None => write!(f, "in script at {:04}: {}", self.offset, self.message),
+        }
+    }
+}
+
+/// Verifies a script and every function nested in it, given how many global slots exist
+pub fn verify(script: &Function, global_count: usize) -> Result<(), VerifyError> {
+    // a script isn't created by a closure instruction, so it has nothing to capture
+    if script.upvalue_count > 0 {
+        return Err(VerifyError {
+            function: script.name.clone(),
+            offset: 0,
+            message: "the script can't capture variables".to_string(),
+        });
+    }
+
+    verify_function(script, global_count, 0)
+}
+
+/// Reads every instruction of a function in order, checking each one on its own but not how
+/// they fit together
+pub(crate) fn decode(
+    function: &Function,
+    global_count: usize,
+) -> Result<Vec<Instruction>, VerifyError> {
+    let verifier = Verifier {
+        function,
+        global_count,
+    };
+    verifier.decode()
+}
+
+fn verify_function(
+    function: &Function,
+    global_count: usize,
+    depth: usize,
+) -> Result<(), VerifyError> {
+    let verifier = Verifier {
+        function,
+        global_count,
+    };
+
+    if depth == NESTING_MAX {
+        return Err(verifier.error(0, "functions are nested too deeply"));
+    }
+
+    let instructions = verifier.decode()?;
+    verifier.check_stack(&instructions)?;
+
+    for constant in function.chunk.constants() {
+        if let Some(nested) = constant.as_function() {
+            verify_function(nested, global_count, depth + 1)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// An instruction with its operands read
+pub(crate) struct Instruction {
+    pub(crate) offset: usize,
+    pub(crate) opcode: OpCode,
+    pub(crate) operands: Vec<usize>,
+    /// Whether each captured variable is a local, and its slot or upvalue index
+    upvalues: Vec<(bool, usize)>,
+    /// The offset of the next instruction
+    end: usize,
+}
+
+struct Verifier<'a> {
+    function: &'a Function,
+    global_count: usize,
+}
+
+impl<'a> Verifier<'a> {
+    fn error(&self, offset: usize, message: impl Into<String>) -> VerifyError {
+        VerifyError {
+            function: self.function.name.clone(),
+            offset,
+            message: message.into(),
+        }
+    }
+
+    /// Reads every instruction in order, checking each one on its own
+    fn decode(&self) -> Result<Vec<Instruction>, VerifyError> {
+        let chunk = &self.function.chunk;
+        let code = chunk.code();
+        let mut instructions = vec![];
+
+        let mut offset = 0;
+        while offset < code.len() {
+            let Ok(opcode) = OpCode::try_from(code[offset]) else {
+                return Err(self.error(offset, format!("unknown opcode {}", code[offset])));
+            };
+
+            let mut end = offset + 1;
+            let mut operands = vec![];
+            let mut closure_function = None;
+
+            for operand in opcode.operands() {
+                let Some(bytes) = code.get(end..end + operand.size()) else {
+                    return Err(self.error(offset, format!("{:?} instruction is cut off", opcode)));
+                };
+                let value = bytes
+                    .iter()
+                    .fold(0, |int, byte| (int << 8) | *byte as usize);
+                end += operand.size();
+
+                match operand {
+                    Operand::Constant | Operand::LongConstant => {
+                        let Some(constant) = chunk.constants().get(value) else {
+                            let message = format!("constant {} is out of range", value);
+                            return Err(self.error(offset, message));
+                        };
+                        self.check_constant_type(offset, opcode, constant)?;
+                        closure_function = constant.as_function();
+                    }
+                    Operand::Global | Operand::LongGlobal => {
+                        if value >= self.global_count {
+                            let message = format!("global slot {} is out of range", value);
+                            return Err(self.error(offset, message));
+                        }
+                    }
+                    Operand::Byte | Operand::Jump | Operand::Loop => (),
+                }
+
+                operands.push(value);
+            }
+
+            if matches!(opcode, OpCode::GetUpvalue | OpCode::SetUpvalue)
+                && operands[0] >= self.function.upvalue_count
+            {
+                return Err(self.error(offset, format!("upvalue {} is out of range", operands[0])));
+            }
+
+            let mut upvalues = vec![];
+            if let Some(function) = closure_function {
+                for _ in 0..function.upvalue_count {
+                    let Some(&[is_local, index]) = code.get(end..end + 2) else {
+                        return Err(self.error(offset, "closure upvalues are cut off"));
+                    };
+                    if is_local > 1 {
+                        return Err(self.error(offset, "invalid closure upvalue kind"));
+                    }
+                    if is_local == 0 && index as usize >= self.function.upvalue_count {
+                        let message = format!("upvalue {} is out of range", index);
+                        return Err(self.error(offset, message));
+                    }
+                    upvalues.push((is_local == 1, index as usize));
+                    end += 2;
+                }
+            }
+
+            instructions.push(Instruction {
+                offset,
+                opcode,
+                operands,
+                upvalues,
+                end,
+            });
+            offset = end;
+        }
+
+        Ok(instructions)
+    }
+
+    fn check_constant_type(
+        &self,
+        offset: usize,
+        opcode: OpCode,
+        constant: &Value,
+    ) -> Result<(), VerifyError> {
+        use OpCode::*;
+        let message = match opcode {
+            Constant | LongConstant if constant.as_function().is_some() => {
+                "functions must be loaded with a closure instruction"
+            }
+            Constant | LongConstant => return Ok(()),
+            Closure | LongClosure if constant.as_function().is_none() => {
+                "closure constant is not a function"
+            }
+            Closure | LongClosure => return Ok(()),
+            _ if !constant.is_string() => "name constant is not a string",
+            _ => return Ok(()),
+        };
+        Err(self.error(offset, message))
+    }
+
+    /// Follows every path through the code, tracking the stack depth relative to the frame
+    fn check_stack(&self, instructions: &[Instruction]) -> Result<(), VerifyError> {
+        let index_of = |offset: usize| {
+            instructions
+                .binary_search_by_key(&offset, |instruction| instruction.offset)
+                .ok()
+        };
+
+        // the callee and arguments are already on the stack, and are never popped
+        let floor = 1 + self.function.arity;
+
+        let mut depths: Vec<Option<usize>> = vec![None; instructions.len()];
+        let mut pending = vec![(0, floor)];
+
+        while let Some((index, depth)) = pending.pop() {
+            let Some(instruction) = instructions.get(index) else {
+                let offset = self.function.chunk.len();
+                return Err(self.error(offset, "execution runs off the end of the code"));
+            };
+            let offset = instruction.offset;
+
+            match depths[index] {
+                Some(seen) if seen == depth => continue,
+                Some(seen) => {
+                    let message = format!(
+                        "stack depth is {} on one path and {} on another",
+                        seen, depth
+                    );
+                    return Err(self.error(offset, message));
+                }
+                None => depths[index] = Some(depth),
+            }
+
+            let (pops, pushes) = stack_effect(instruction);
+            if depth < floor + pops {
+                return Err(self.error(offset, "pops more values than the function pushed"));
+            }
+
+            if matches!(instruction.opcode, OpCode::GetLocal | OpCode::SetLocal)
+                && instruction.operands[0] >= depth
+            {
+                let message = format!("local slot {} is out of range", instruction.operands[0]);
+                return Err(self.error(offset, message));
+            }
+            for (is_local, slot) in &instruction.upvalues {
+                // a local function that calls itself captures the slot its closure is pushed to
+                if *is_local && *slot > depth {
+                    return Err(self.error(offset, format!("local slot {} is out of range", slot)));
+                }
+            }
+
+            let depth = depth - pops + pushes;
+
+            let jump_target = match instruction.opcode {
+                OpCode::Jump | OpCode::JumpIfFalse => {
+                    Some(instruction.end + instruction.operands[0])
+                }
+                OpCode::Loop => instruction.end.checked_sub(instruction.operands[0]),
+                _ => None,
+            };
+            if matches!(
+                instruction.opcode,
+                OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop
+            ) {
+                let Some(target) = jump_target.and_then(index_of) else {
+                    return Err(self.error(offset, "jump target is not an instruction"));
+                };
+                pending.push((target, depth));
+            }
+
+            match instruction.opcode {
+                OpCode::Return | OpCode::Jump | OpCode::Loop => (),
+                _ => pending.push((index + 1, depth)),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How many values an instruction pops, and then how many it pushes
+fn stack_effect(instruction: &Instruction) -> (usize, usize) {
+    use OpCode::*;
+    match instruction.opcode {
+        Constant | LongConstant | Nil | True | False | GetLocal | GetUpvalue | GetGlobal
+        | GetLongGlobal | Closure | LongClosure | Class | LongClass => (0, 1),
+        Pop | DefineGlobal | DefineLongGlobal | Print | CloseUpvalue | Return => (1, 0),
+        SetLocal | SetUpvalue | SetGlobal | SetLongGlobal | GetProperty | GetLongProperty | Not
+        | Negate | JumpIfFalse => (1, 1),
+        SetProperty | SetLongProperty | GetSuper | GetLongSuper | Equal | NotEqual | Greater
+        | GreaterEqual | Less | LessEqual | Add | Subtract | Multiply | Divide | Remainder
+        | Inherit | Method | LongMethod => (2, 1),
+        Jump | Loop => (0, 0),
+        Call => (instruction.operands[0] + 1, 1),
+        Invoke | LongInvoke => (instruction.operands[1] + 1, 1),
+        // the superclass sits above the receiver and arguments
+        SuperInvoke | LongSuperInvoke => (instruction.operands[1] + 2, 1),
+    }
+}
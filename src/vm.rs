@@ -1,60 +1,521 @@
+use crate::asm;
+use crate::bytecode;
 use crate::chunk::Chunk;
 use crate::chunk::OpCode;
 use crate::compiler::compile;
+use crate::diagnostic::Diagnostic;
+use crate::heap::Heap;
+use crate::heap::ObjRef;
+use crate::native;
+use crate::optimize::OptLevel;
+use crate::table::Globals;
+use crate::table::Symbol;
+use crate::value::update_table;
+use crate::value::BoundMethod;
+use crate::value::Class;
+use crate::value::Closure;
+use crate::value::Function;
+use crate::value::Instance;
+use crate::value::Native;
+use crate::value::NativeFn;
 use crate::value::Object;
+use crate::value::ObjectKind;
+use crate::value::Upvalue;
 use crate::value::Value;
+use crate::value::ValueRef;
+use crate::verify;
 
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::io::BufRead;
+use std::io::Write;
 use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 
-const STACK_MAX: usize = 256;
+/// The deepest calls can nest, counting the script itself
+pub(crate) const FRAMES_MAX: usize = 64;
+const STACK_MAX: usize = FRAMES_MAX * 256;
+
+/// How many instructions run between checks of the instruction limit and the interrupt flag
+const CHECK_INTERVAL: u64 = 1024;
+
+/// The verifier doesn't check that captured variables are closed before they are popped, so
+/// bytecode that pops one early leaves its upvalue pointing past the top of the stack
+const UPVALUE_OFF_STACK: InterpretError = InterpretError::Ice("Captured variable is off the stack");
+
+/// Debugging output the `Vm` prints alongside the program's own, how it compiles scripts, and the
+/// limits it runs under
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VmOptions {
+    /// Disassemble each instruction as it runs
+    pub trace: bool,
+    /// Print the stack before each instruction
+    pub dump_stack: bool,
+    /// Print the heap's objects before each instruction
+    pub dump_heap: bool,
+    /// Disassemble each function once it is compiled
+    pub disassemble: bool,
+    /// How much the compiler optimizes scripts
+    pub opt_level: OptLevel,
+    pub limits: Limits,
+}
+
+impl VmOptions {
+    pub fn trace(mut self, trace: bool) -> VmOptions {
+        self.trace = trace;
+        self
+    }
+
+    pub fn dump_stack(mut self, dump_stack: bool) -> VmOptions {
+        self.dump_stack = dump_stack;
+        self
+    }
+
+    pub fn dump_heap(mut self, dump_heap: bool) -> VmOptions {
+        self.dump_heap = dump_heap;
+        self
+    }
+
+    pub fn disassemble(mut self, disassemble: bool) -> VmOptions {
+        self.disassemble = disassemble;
+        self
+    }
+
+    pub fn opt_level(mut self, opt_level: OptLevel) -> VmOptions {
+        self.opt_level = opt_level;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> VmOptions {
+        self.limits = limits;
+        self
+    }
+}
+
+/// Bounds on the resources a script may use, for running code that isn't trusted
+///
+/// The memory limit is checked before each instruction, so a single instruction can go over it
+/// by the size of what it allocates before it is stopped. The stack limit is checked on each
+/// call, so the stack can grow past it by the temporaries of one function.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// The most instructions each call to one of the `interpret` methods may execute
+    pub instructions: u64,
+    /// The most values the stack may hold
+    pub stack: usize,
+    /// The most bytes the heap's objects may take up after a collection
+    pub memory: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            instructions: u64::MAX,
+            stack: STACK_MAX,
+            memory: usize::MAX,
+        }
+    }
+}
+
+impl Limits {
+    pub fn instructions(mut self, instructions: u64) -> Limits {
+        self.instructions = instructions;
+        self
+    }
+
+    pub fn stack(mut self, stack: usize) -> Limits {
+        self.stack = stack;
+        self
+    }
+
+    pub fn memory(mut self, memory: usize) -> Limits {
+        self.memory = memory;
+        self
+    }
+}
+
+/// Which limit stopped a script
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitError {
+    Instructions(u64),
+    Stack(usize),
+    Memory(usize),
+}
+
+impl std::error::Error for LimitError {}
+
+impl std::fmt::Display for LimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LimitError::Instructions(limit) => write!(f, "executed {} instructions", limit),
+            LimitError::Stack(limit) => write!(f, "stack grew past {} values", limit),
+            LimitError::Memory(limit) => write!(f, "heap grew past {} bytes", limit),
+        }
+    }
+}
 
-#[derive(Default)]
 pub struct Vm {
-    chunk: Box<Chunk>,
-    ip: usize,
-    debug: bool,
+    frames: Vec<CallFrame>,
+    options: VmOptions,
     stack: Vec<Value>,
-    objects: Vec<std::rc::Weak<Object>>,
-    globals: HashMap<String, Value>,
+    heap: Heap,
+    globals: Globals,
+    /// The name of initializer methods
+    init_symbol: Symbol,
+    /// Upvalues still pointing into the stack, sorted by slot
+    open_upvalues: Vec<ObjRef>,
+    /// Where scripts print to
+    output: Box<dyn Write>,
+    /// Where tracing, dumps and disassembly go
+    debug_output: Box<dyn Write>,
+    /// Where natives read input from, stdin if `None`
+    input: Option<Box<dyn BufRead>>,
+    /// Instructions executed since the last call to `run_function`
+    executed: u64,
+    /// When `executed` passes this, the limits are checked, so it is lowered when the heap grows
+    /// past its limit
+    next_check: u64,
+    /// Set from any thread to stop the running script
+    interrupt: Arc<AtomicBool>,
+}
+
+/// An in-memory writer whose clones share their contents, for capturing a `Vm`'s output
+#[derive(Clone, Default)]
+pub struct OutputBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl OutputBuffer {
+    /// Everything written so far, with invalid UTF-8 replaced
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for OutputBuffer {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(bytes)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An ongoing function call
+struct CallFrame {
+    closure: ObjRef,
+    ip: usize,
+    /// The index of the first stack slot the function can use
+    slots: usize,
+}
+
+impl CallFrame {
+    fn closure(&self) -> &Closure {
+        // SAFETY: the frames are roots, and `Vm::call` only pushes frames for closures
+        unsafe { self.closure.get() }.as_closure().unwrap()
+    }
+
+    fn function(&self) -> &Function {
+        self.closure().function()
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.function().chunk
+    }
+}
+
+impl Default for Vm {
+    fn default() -> Vm {
+        Vm::new()
+    }
 }
 
 impl Vm {
-    pub fn new(chunk: Box<Chunk>) -> Vm {
-        Vm {
-            chunk,
-            ip: 0,
-            debug: true,
+    pub fn new() -> Vm {
+        Vm::with_options(VmOptions::default())
+    }
+
+    pub fn with_options(options: VmOptions) -> Vm {
+        let mut heap = Heap::default();
+        let init_symbol = heap.intern_symbol("init");
+
+        let mut vm = Vm {
+            frames: Vec::with_capacity(FRAMES_MAX),
+            options,
             stack: Vec::with_capacity(STACK_MAX),
-            objects: vec![],
-            globals: HashMap::new(),
+            heap,
+            globals: Globals::default(),
+            init_symbol,
+            open_upvalues: vec![],
+            output: Box::new(std::io::stdout()),
+            debug_output: Box::new(std::io::stdout()),
+            input: None,
+            executed: 0,
+            next_check: 0,
+            interrupt: Arc::new(AtomicBool::new(false)),
+        };
+
+        for (name, arity, function) in native::STANDARD {
+            vm.define_native(name, *arity, *function);
         }
+
+        vm
+    }
+
+    /// A flag that stops the running script with `InterpretError::Interrupted` when set
+    ///
+    /// The flag is cleared whenever a script starts, so setting it while no script is running
+    /// does nothing, and once it has stopped a script, so the `Vm` can be used again.
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    /// Sends what scripts print to `output` instead of stdout
+    pub fn with_output(mut self, output: impl Write + 'static) -> Vm {
+        self.output = Box::new(output);
+        self
+    }
+
+    /// Sends tracing, dumps and disassembly to `output` instead of stdout
+    pub fn with_debug_output(mut self, output: impl Write + 'static) -> Vm {
+        self.debug_output = Box::new(output);
+        self
+    }
+
+    /// Has natives read input from `input` instead of stdin
+    pub fn with_input(mut self, input: impl BufRead + 'static) -> Vm {
+        self.input = Some(Box::new(input));
+        self
+    }
+
+    /// Where scripts print to, for natives that write output
+    pub fn output(&mut self) -> &mut dyn Write {
+        &mut self.output
+    }
+
+    /// Reads a line of input without its line ending, or `None` at the end of the input
+    pub fn read_line(&mut self) -> std::io::Result<Option<String>> {
+        let mut line = String::new();
+        let read = match &mut self.input {
+            Some(input) => input.read_line(&mut line)?,
+            None => std::io::stdin().read_line(&mut line)?,
+        };
+        if read == 0 {
+            return Ok(None);
+        }
+
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(Some(line))
+    }
+
+    /// Makes a Rust function callable from scripts as the global variable `name`, replacing
+    /// any value it already has
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let native = self.alloc(Native {
+            name: name.to_string(),
+            arity,
+            function,
+        });
+
+        // interning never collects, so the native survives until it is defined
+        let name = self.heap.intern_symbol(name);
+        let slot = self.globals.resolve(name);
+        self.globals.define(slot, native.into());
+    }
+
+    /// Makes a string value, for natives to return
+    ///
+    /// The string is only kept alive while the `Vm` stays borrowed, so it must be returned
+    /// before the `Vm` is used again.
+    pub fn new_string(&mut self, text: &str) -> ValueRef<'_> {
+        ValueRef::new(self.intern(text).into())
     }
 
     pub fn interpret(&mut self, source: &str) -> Result<(), InterpretError> {
-        self.chunk = Box::new(compile(source)?);
-        self.ip = 0;
-        //~ self.reset_stack(); // do we reset here or what? who knows
+        let function = self.compile(source)?;
+
+        // the compiler's output is trusted, but checking it in debug builds catches its bugs
+        if cfg!(debug_assertions) {
+            self.verify(function)?;
+        }
+
+        self.run_function(function)
+    }
+
+    /// Compiles a script without running it, and returns its listing
+    pub fn disassemble(&mut self, source: &str) -> Result<String, InterpretError> {
+        let function = self.compile(source)?;
+        Ok(self.disassembly(function))
+    }
+
+    fn compile(&mut self, source: &str) -> Result<ObjRef, InterpretError> {
+        let function = compile(
+            source,
+            &mut self.heap,
+            &mut self.globals,
+            self.options.opt_level,
+        )?;
+
+        if self.options.disassemble {
+            let listing = self.disassembly(function);
+            self.write_debug(&listing);
+        }
+
+        Ok(function)
+    }
+
+    fn disassembly(&self, function: ObjRef) -> String {
+        let mut listing = String::new();
+        let globals = |slot| self.global_name(slot);
+        // SAFETY: the function was just made, and nothing is collected while it is listed
+        unsafe { function.get() }
+            .as_function()
+            .unwrap()
+            .write_disassembly(&mut listing, &globals)
+            .unwrap();
+        listing
+    }
+
+    /// Writes debugging output, which is best effort and never stops the program
+    fn write_debug(&mut self, text: &str) {
+        let _ = self.debug_output.write_all(text.as_bytes());
+    }
+
+    fn global_name(&self, slot: usize) -> Option<&str> {
+        if slot < self.globals.len() {
+            self.heap.resolve(self.globals.name(slot))
+        } else {
+            None
+        }
+    }
+
+    /// Assembles a listing in the disassembler's format and runs it
+    pub fn interpret_assembly(&mut self, listing: &str) -> Result<(), InterpretError> {
+        let function = self.assemble(listing)?;
+        self.verify(function)?;
+        self.run_function(function)
+    }
+
+    /// Assembles a listing in the disassembler's format to the `.lxc` format without running it
+    pub fn assemble_bytecode(&mut self, listing: &str) -> Result<Vec<u8>, InterpretError> {
+        let function = self.assemble(listing)?;
+        self.verify(function)?;
+        // SAFETY: the function was just made, and nothing is collected while it is written
+        let function = unsafe { function.get() }.as_function().unwrap();
+        Ok(bytecode::write(function, &self.globals, &self.heap))
+    }
+
+    fn assemble(&mut self, listing: &str) -> Result<ObjRef, InterpretError> {
+        let function = asm::assemble(listing, &mut self.heap, &mut self.globals)
+            .map_err(InterpretError::AsmError)?;
+        let function = self.heap.alloc(function);
+
+        if self.options.disassemble {
+            let listing = self.disassembly(function);
+            self.write_debug(&listing);
+        }
+
+        Ok(function)
+    }
+
+    /// Runs a script compiled to the `.lxc` format
+    pub fn interpret_bytecode(&mut self, bytes: &[u8]) -> Result<(), InterpretError> {
+        let function = bytecode::read(bytes, &mut self.heap, &mut self.globals)
+            .map_err(InterpretError::LoadError)?;
+        self.verify(function)?;
+        self.run_function(function)
+    }
+
+    /// Compiles a script to the `.lxc` format without running it
+    pub fn compile_bytecode(&mut self, source: &str) -> Result<Vec<u8>, InterpretError> {
+        let function = self.compile(source)?;
+        // SAFETY: the function was just made, and nothing is collected while it is written
+        let function = unsafe { function.get() }.as_function().unwrap();
+        Ok(bytecode::write(function, &self.globals, &self.heap))
+    }
+
+    /// Runs a bare chunk as if it were a top-level script
+    pub fn interpret_chunk(&mut self, chunk: Chunk) -> Result<(), InterpretError> {
+        let function = self.heap.alloc(Function {
+            arity: 0,
+            upvalue_count: 0,
+            chunk,
+            name: None,
+        });
+        self.verify(function)?;
+        self.run_function(function)
+    }
+
+    fn verify(&self, function: ObjRef) -> Result<(), InterpretError> {
+        // SAFETY: the function was just made, and nothing is collected while it is verified
+        let function = unsafe { function.get() }.as_function().unwrap();
+        verify::verify(function, self.globals.len()).map_err(InterpretError::VerifyError)
+    }
+
+    /// Collects on every allocation when set, so unrooted objects are freed as soon as possible
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.heap.set_stress(stress);
+    }
+
+    /// The number of objects currently on the heap, reachable or not
+    pub fn object_count(&self) -> usize {
+        self.heap.object_count()
+    }
+
+    /// The name and value of every defined global variable, in the order they were first used
+    pub fn globals(&self) -> impl Iterator<Item = (&str, ValueRef<'_>)> + '_ {
+        self.globals
+            .iter()
+            .filter_map(|(name, value)| Some((self.heap.resolve(name)?, ValueRef::new(value?))))
+    }
+
+    fn run_function(&mut self, function: ObjRef) -> Result<(), InterpretError> {
+        // a native calling back into the `Vm` would throw away the script that called it
+        if !self.frames.is_empty() {
+            return Err(InterpretError::Reentrant);
+        }
+
+        let result = self.start(function);
+        if result.is_err() {
+            // this is the only way out of a failed script, so none of its state is left for the
+            // next one to trip over
+            self.reset_stack();
+        }
+        result
+    }
+
+    fn start(&mut self, function: ObjRef) -> Result<(), InterpretError> {
+        self.interrupt.store(false, Ordering::Relaxed);
+        self.executed = 0;
+        self.next_check = CHECK_INTERVAL.min(self.options.limits.instructions);
+
+        // keep the function rooted while its closure is allocated
+        self.push(function.into());
+        let closure = self.add_object(Closure {
+            function,
+            upvalues: vec![],
+        });
+        self.pop()?;
+
+        self.push(closure);
+        self.call_value(closure, 0)?;
         self.run()
     }
 
-    pub fn run(&mut self) -> Result<(), InterpretError> {
+    fn run(&mut self) -> Result<(), InterpretError> {
         loop {
-            if self.debug {
-                print!("    stack ");
-                for v in self.stack.iter() {
-                    print!("[ {:?} ]", v);
-                }
-                println!();
-                print!("  objects ");
-                for v in self.objects.iter() {
-                    match v.upgrade() {
-                        Some(p) => print!("[ Object#{:p} ]", p),
-                        None => print!("[ Empty ]"),
-                    }
-                }
-                println!();
-                self.chunk.disassemble_instruction(self.ip);
+            if self.options.dump_stack || self.options.dump_heap || self.options.trace {
+                self.write_state();
+            }
+
+            self.executed += 1;
+            if self.executed > self.next_check {
+                self.check_limits()?;
             }
 
             let instruction = self.read_byte();
@@ -63,91 +524,119 @@ impl Vm {
                 Ok(inst) => match inst {
                     Constant => {
                         let constant_id = self.read_byte() as usize;
-                        let constant = self.chunk.get_constant(constant_id);
-                        self.push(constant.clone());
+                        let constant = self.frame().chunk().get_constant(constant_id);
+                        self.push(*constant);
                     }
                     LongConstant => {
                         let constant_id = self.read_int(3);
-                        let constant = self.chunk.get_constant(constant_id);
-                        self.push(constant.clone());
+                        let constant = self.frame().chunk().get_constant(constant_id);
+                        self.push(*constant);
                     }
-                    Nil => self.push(Value::Nil),
-                    True => self.push(Value::Boolean(true)),
-                    False => self.push(Value::Boolean(false)),
+                    Nil => self.push(Value::NIL),
+                    True => self.push(true.into()),
+                    False => self.push(false.into()),
                     Pop => {
                         self.pop()?;
                     }
-                    GetGlobal => {
-                        let constant_id = self.read_byte();
-                        let constant = self.chunk.get_constant(constant_id as usize);
-                        let name = constant.as_string().unwrap().to_string();
-                        if let Some(value) = self.globals.get(&name) {
-                            self.push(value.clone());
-                        } else {
-                            let e = self.report_runtime_error("Undefined variable.");
-                            return Err(e);
+                    GetLocal => {
+                        let slot = self.frame().slots + self.read_byte() as usize;
+                        self.push(self.stack[slot]);
+                    }
+                    SetLocal => {
+                        let slot = self.frame().slots + self.read_byte() as usize;
+                        self.stack[slot] = *self.peek(0);
+                    }
+                    GetUpvalue => {
+                        let index = self.read_byte() as usize;
+                        let upvalue = self.frame().closure().upvalues[index];
+                        // SAFETY: the running closure keeps its upvalues reachable
+                        let upvalue = unsafe { upvalue.get() }.as_upvalue().unwrap();
+                        let value = match &*upvalue.borrow() {
+                            Upvalue::Open(slot) => {
+                                *self.stack.get(*slot).ok_or(UPVALUE_OFF_STACK)?
+                            }
+                            Upvalue::Closed(value) => *value,
+                        };
+                        self.push(value);
+                    }
+                    SetUpvalue => {
+                        let index = self.read_byte() as usize;
+                        let upvalue = self.frame().closure().upvalues[index];
+                        let value = *self.peek(0);
+                        // SAFETY: the running closure keeps its upvalues reachable
+                        let upvalue = unsafe { upvalue.get() }.as_upvalue().unwrap();
+                        let mut upvalue = upvalue.borrow_mut();
+                        match &mut *upvalue {
+                            Upvalue::Open(slot) => {
+                                *self.stack.get_mut(*slot).ok_or(UPVALUE_OFF_STACK)? = value
+                            }
+                            Upvalue::Closed(closed) => *closed = value,
                         }
                     }
+                    GetProperty => {
+                        let name = self.read_name(false)?;
+                        self.get_property(name)?;
+                    }
+                    GetLongProperty => {
+                        let name = self.read_name(true)?;
+                        self.get_property(name)?;
+                    }
+                    SetProperty => {
+                        let name = self.read_name(false)?;
+                        self.set_property(name)?;
+                    }
+                    SetLongProperty => {
+                        let name = self.read_name(true)?;
+                        self.set_property(name)?;
+                    }
+                    GetSuper => {
+                        let name = self.read_name(false)?;
+                        self.get_super(name)?;
+                    }
+                    GetLongSuper => {
+                        let name = self.read_name(true)?;
+                        self.get_super(name)?;
+                    }
+                    GetGlobal => {
+                        let slot = self.read_byte() as usize;
+                        self.get_global(slot)?;
+                    }
                     GetLongGlobal => {
-                        let constant_id = self.read_int(3);
-                        let constant = self.chunk.get_constant(constant_id);
-                        let name = constant.as_string().unwrap().to_string();
-                        if let Some(value) = self.globals.get(&name) {
-                            self.push(value.clone());
-                        } else {
-                            let e = self.report_runtime_error("Undefined variable.");
-                            return Err(e);
-                        }
+                        let slot = self.read_int(3);
+                        self.get_global(slot)?;
                     }
                     DefineGlobal => {
-                        let constant_id = self.read_byte();
-                        let constant = self.chunk.get_constant(constant_id as usize);
-                        let name = constant.as_string().unwrap().to_string();
-                        self.globals.insert(name, self.peek(0).clone());
+                        let slot = self.read_byte() as usize;
+                        self.globals.define(slot, *self.peek(0));
                         self.pop()?;
                     }
                     DefineLongGlobal => {
-                        let constant_id = self.read_int(3);
-                        let constant = self.chunk.get_constant(constant_id);
-                        let name = constant.as_string().unwrap().to_string();
-                        self.globals.insert(name, self.peek(0).clone());
+                        let slot = self.read_int(3);
+                        self.globals.define(slot, *self.peek(0));
                         self.pop()?;
                     }
                     SetGlobal => {
-                        let constant_id = self.read_byte();
-                        let constant = self.chunk.get_constant(constant_id as usize);
-                        let name = constant.as_string().unwrap().to_string();
-                        let val = self.peek(0).clone();
-                        if let std::collections::hash_map::Entry::Occupied(mut entry) =
-                            self.globals.entry(name)
-                        {
-                            entry.insert(val);
-                        } else {
-                            let e = self.report_runtime_error("Undefined variable.");
-                            return Err(e);
-                        }
+                        let slot = self.read_byte() as usize;
+                        self.set_global(slot)?;
                     }
                     SetLongGlobal => {
-                        let constant_id = self.read_int(3);
-                        let constant = self.chunk.get_constant(constant_id);
-                        let name = constant.as_string().unwrap().to_string();
-                        let val = self.peek(0).clone();
-                        if let std::collections::hash_map::Entry::Occupied(mut entry) =
-                            self.globals.entry(name)
-                        {
-                            entry.insert(val);
-                        } else {
-                            let e = self.report_runtime_error("Undefined variable.");
-                            return Err(e);
-                        }
+                        let slot = self.read_int(3);
+                        self.set_global(slot)?;
                     }
                     Equal => {
                         let b = self.pop()?;
                         let a = self.pop()?;
-                        self.push(Value::Boolean(a == b));
+                        self.push((a == b).into());
+                    }
+                    NotEqual => {
+                        let b = self.pop()?;
+                        let a = self.pop()?;
+                        self.push((a != b).into());
                     }
                     Greater => self.binary_cmp(greater_than)?,
+                    GreaterEqual => self.binary_cmp(greater_equal)?,
                     Less => self.binary_cmp(less_than)?,
+                    LessEqual => self.binary_cmp(less_equal)?,
                     Add => {
                         if self.peek(0).is_string() && self.peek(1).is_string() {
                             self.concatenate()?;
@@ -161,36 +650,125 @@ impl Vm {
                     Remainder => self.binary(std::ops::Rem::rem)?,
                     Not => {
                         let v = self.pop()?;
-                        self.push(Value::Boolean(!v.truthiness()));
+                        self.push((!v.truthiness()).into());
                     }
                     Negate => {
-                        if let Value::Number(_) = self.peek(0) {
+                        if self.peek(0).is_number() {
                             let v = self.pop()?;
                             self.push((-f64::try_from(v)?).into());
                         } else {
-                            let e = self.report_runtime_error("Operand must be a number.");
-                            return Err(e);
+                            return Err(self.runtime_error("Operand must be a number."));
                         }
                     }
                     Print => {
                         let v = self.pop()?;
-                        println!("{}", v);
+                        if let Err(e) = writeln!(self.output, "{}", v) {
+                            return Err(self.runtime_error(format!("Could not print: {}.", e)));
+                        }
+                    }
+                    Jump => {
+                        let offset = self.read_int(2);
+                        self.frame_mut().ip += offset;
+                    }
+                    JumpIfFalse => {
+                        let offset = self.read_int(2);
+                        if !self.peek(0).truthiness() {
+                            self.frame_mut().ip += offset;
+                        }
+                    }
+                    Loop => {
+                        let offset = self.read_int(2);
+                        self.frame_mut().ip -= offset;
+                    }
+                    Call => {
+                        let arg_count = self.read_byte() as usize;
+                        self.call_value(*self.peek(arg_count), arg_count)?;
+                    }
+                    Closure => {
+                        let constant_id = self.read_byte() as usize;
+                        self.closure(constant_id)?;
+                    }
+                    LongClosure => {
+                        let constant_id = self.read_int(3);
+                        self.closure(constant_id)?;
+                    }
+                    CloseUpvalue => {
+                        self.close_upvalues(self.stack.len() - 1)?;
+                        self.pop()?;
+                    }
+                    Invoke => {
+                        let name = self.read_name(false)?;
+                        let arg_count = self.read_byte() as usize;
+                        self.invoke(name, arg_count)?;
+                    }
+                    LongInvoke => {
+                        let name = self.read_name(true)?;
+                        let arg_count = self.read_byte() as usize;
+                        self.invoke(name, arg_count)?;
+                    }
+                    SuperInvoke => {
+                        let name = self.read_name(false)?;
+                        let arg_count = self.read_byte() as usize;
+                        self.super_invoke(name, arg_count)?;
+                    }
+                    LongSuperInvoke => {
+                        let name = self.read_name(true)?;
+                        let arg_count = self.read_byte() as usize;
+                        self.super_invoke(name, arg_count)?;
+                    }
+                    Class => {
+                        let name = self.read_constant(false);
+                        self.class(name)?;
+                    }
+                    LongClass => {
+                        let name = self.read_constant(true);
+                        self.class(name)?;
+                    }
+                    Inherit => self.inherit()?,
+                    Method => {
+                        let name = self.read_name(false)?;
+                        self.define_method(name)?;
+                    }
+                    LongMethod => {
+                        let name = self.read_name(true)?;
+                        self.define_method(name)?;
                     }
                     Return => {
-                        //~ println!("{:?}", self.pop()?);
-                        return Ok(());
+                        let result = self.pop()?;
+                        let frame = self
+                            .frames
+                            .pop()
+                            .ok_or(InterpretError::Ice("Returned with no call frame"))?;
+
+                        self.close_upvalues(frame.slots)?;
+
+                        self.stack.truncate(frame.slots);
+
+                        if self.frames.is_empty() {
+                            return Ok(());
+                        }
+
+                        self.push(result);
                     }
                 },
-                _ => {
-                    panic!("Unknown opcode {}", instruction);
-                }
+                // the verifier rejects these, so this is only reachable through a bug in it
+                Err(_) => return Err(InterpretError::Ice("Unknown opcode")),
             }
         }
     }
 
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
     fn read_byte(&mut self) -> u8 {
-        let byte = self.chunk.read(self.ip);
-        self.ip += 1;
+        let frame = self.frame_mut();
+        let byte = frame.chunk().read(frame.ip);
+        frame.ip += 1;
         byte
     }
 
@@ -202,8 +780,208 @@ impl Vm {
         int
     }
 
+    fn read_constant(&mut self, long: bool) -> Value {
+        let constant_id = if long {
+            self.read_int(3)
+        } else {
+            self.read_byte() as usize
+        };
+
+        *self.frame().chunk().get_constant(constant_id)
+    }
+
+    /// Reads a constant operand naming a property or method
+    fn read_name(&mut self, long: bool) -> Result<Symbol, InterpretError> {
+        self.read_constant(long)
+            .as_symbol()
+            .ok_or(InterpretError::Ice("Name is not a string"))
+    }
+
+    /// Finds out which limit was passed, or whether the script was interrupted
+    #[cold]
+    fn check_limits(&mut self) -> Result<(), InterpretError> {
+        let limits = self.options.limits;
+        let error = if self.interrupt.swap(false, Ordering::Relaxed) {
+            InterpretError::Interrupted
+        } else if self.executed > limits.instructions {
+            InterpretError::LimitError(LimitError::Instructions(limits.instructions))
+        } else {
+            // garbage doesn't count against the memory limit
+            if self.heap.bytes_allocated() > limits.memory {
+                self.collect_garbage();
+            }
+            if self.heap.bytes_allocated() <= limits.memory {
+                self.next_check = (self.executed + CHECK_INTERVAL).min(limits.instructions);
+                return Ok(());
+            }
+            InterpretError::LimitError(LimitError::Memory(limits.memory))
+        };
+
+        Err(error)
+    }
+
+    /// Writes whichever of the stack, heap and next instruction the options ask for
+    fn write_state(&mut self) {
+        use std::fmt::Write;
+
+        let mut state = String::new();
+        if self.options.dump_stack {
+            state.push_str("    stack ");
+            for v in self.stack.iter() {
+                write!(state, "[ {:?} ]", v).unwrap();
+            }
+            state.push('\n');
+        }
+        if self.options.dump_heap {
+            state.push_str("  objects ");
+            for object in self.heap.objects() {
+                write!(state, "[ Object#{:p} ]", object).unwrap();
+            }
+            state.push('\n');
+        }
+        if self.options.trace {
+            let frame = self.frame();
+            let globals = |slot| self.global_name(slot);
+            frame
+                .chunk()
+                .write_instruction(&mut state, frame.ip, &globals)
+                .unwrap();
+        }
+        self.write_debug(&state);
+    }
+
+    fn get_global(&mut self, slot: usize) -> Result<(), InterpretError> {
+        match self.globals.get(slot) {
+            Some(value) => {
+                self.push(value);
+                Ok(())
+            }
+            None => Err(self.undefined_variable(slot)),
+        }
+    }
+
+    fn set_global(&mut self, slot: usize) -> Result<(), InterpretError> {
+        if self.globals.set(slot, *self.peek(0)) {
+            Ok(())
+        } else {
+            Err(self.undefined_variable(slot))
+        }
+    }
+
+    fn undefined_variable(&mut self, slot: usize) -> InterpretError {
+        let name = self.global_name(slot).unwrap_or("?");
+        let message = format!("Undefined variable '{}'.", name);
+        self.runtime_error(message)
+    }
+
     fn reset_stack(&mut self) {
         self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), InterpretError> {
+        let callee_slot = self.stack.len() - arg_count - 1;
+
+        if let Some(object) = callee.as_object() {
+            // SAFETY: the callee is on the stack, which keeps it reachable during the call
+            match &unsafe { object.get() }.kind {
+                ObjectKind::Closure(_) => return self.call(object, arg_count),
+                ObjectKind::Class(class) => {
+                    let instance = self.add_object(Instance {
+                        class: object,
+                        fields: Default::default(),
+                    });
+                    self.stack[callee_slot] = instance;
+
+                    let initializer = class.methods.borrow().get(&self.init_symbol).cloned();
+                    return match initializer {
+                        Some(initializer) => self.call(initializer, arg_count),
+                        None if arg_count != 0 => Err(self.wrong_arg_count(0, arg_count)),
+                        None => Ok(()),
+                    };
+                }
+                ObjectKind::BoundMethod(bound) => {
+                    self.stack[callee_slot] = bound.receiver;
+                    return self.call(bound.method, arg_count);
+                }
+                ObjectKind::Native(native) => {
+                    return self.call_native(native.function, native.arity, arg_count)
+                }
+                _ => (),
+            }
+        }
+
+        Err(self.runtime_error("Can only call functions and classes."))
+    }
+
+    fn call(&mut self, closure: ObjRef, arg_count: usize) -> Result<(), InterpretError> {
+        // the verifier doesn't track types, so bytecode could try to call anything as a method
+        // SAFETY: the callee, or the class or bound method it came from, is on the stack
+        let Some(function) = unsafe { closure.get() }.as_closure() else {
+            return Err(InterpretError::Ice("Called a non-closure"));
+        };
+        let arity = function.function().arity;
+
+        if arg_count != arity {
+            return Err(self.wrong_arg_count(arity, arg_count));
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+        self.check_stack()?;
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots: self.stack.len() - arg_count - 1,
+        });
+
+        Ok(())
+    }
+
+    /// Calls a native with the arguments on top of the stack, replacing them and the callee
+    /// with its result
+    fn call_native(
+        &mut self,
+        function: NativeFn,
+        arity: usize,
+        arg_count: usize,
+    ) -> Result<(), InterpretError> {
+        if arg_count != arity {
+            return Err(self.wrong_arg_count(arity, arg_count));
+        }
+        self.check_stack()?;
+
+        // the arguments stay on the stack, so they survive any collection the native causes
+        let callee_slot = self.stack.len() - arg_count - 1;
+        let args: Vec<_> = self.stack[callee_slot + 1..]
+            .iter()
+            .map(|arg| ValueRef::new(*arg))
+            .collect();
+        let result = match function(self, &args) {
+            Ok(result) => result.value(),
+            Err(message) => return Err(self.runtime_error(message)),
+        };
+
+        self.stack.truncate(callee_slot);
+        self.push(result);
+        Ok(())
+    }
+
+    /// Fails if the stack has grown past its limit, as checked on each call
+    fn check_stack(&self) -> Result<(), InterpretError> {
+        let limit = self.options.limits.stack;
+        if self.stack.len() > limit {
+            return Err(InterpretError::LimitError(LimitError::Stack(limit)));
+        }
+        Ok(())
+    }
+
+    fn wrong_arg_count(&mut self, arity: usize, arg_count: usize) -> InterpretError {
+        let message = format!("Expected {} arguments but got {}.", arity, arg_count);
+        self.runtime_error(message)
     }
 
     fn peek(&self, depth: usize) -> &Value {
@@ -221,38 +999,302 @@ impl Vm {
     }
 
     fn binary(&mut self, f: fn(f64, f64) -> f64) -> Result<(), InterpretError> {
-        if let Value::Number(_) = self.peek(0) {
-            if let Value::Number(_) = self.peek(1) {
-                let b = self.pop()?.try_into()?;
-                let a = self.pop()?.try_into()?;
-                self.push(f(a, b).into());
-                return Ok(());
-            }
+        if self.peek(0).is_number() && self.peek(1).is_number() {
+            let b = self.pop()?.try_into()?;
+            let a = self.pop()?.try_into()?;
+            self.push(f(a, b).into());
+            return Ok(());
         }
 
-        let e = self.report_runtime_error("Both operands must be numbers");
-        Err(e)
+        Err(self.operands_error())
     }
 
     fn binary_cmp(&mut self, f: fn(f64, f64) -> bool) -> Result<(), InterpretError> {
-        if let Value::Number(_) = self.peek(0) {
-            if let Value::Number(_) = self.peek(1) {
-                let b = self.pop()?.try_into()?;
-                let a = self.pop()?.try_into()?;
-                self.push(f(a, b).into());
-                return Ok(());
+        if self.peek(0).is_number() && self.peek(1).is_number() {
+            let b = self.pop()?.try_into()?;
+            let a = self.pop()?.try_into()?;
+            self.push(f(a, b).into());
+            return Ok(());
+        }
+
+        Err(self.operands_error())
+    }
+
+    fn operands_error(&mut self) -> InterpretError {
+        let message = format!(
+            "Operands must be numbers, not {} and {}.",
+            self.peek(1).type_name(),
+            self.peek(0).type_name()
+        );
+        self.runtime_error(message)
+    }
+
+    fn class(&mut self, name: Value) -> Result<(), InterpretError> {
+        let Some(name) = name.as_object() else {
+            return Err(InterpretError::Ice("Class name is not a string"));
+        };
+
+        let class = self.add_object(Class {
+            name,
+            methods: Default::default(),
+        });
+        self.push(class);
+
+        Ok(())
+    }
+
+    /// Copies the superclass's methods down into the subclass on top of the stack
+    fn inherit(&mut self) -> Result<(), InterpretError> {
+        let Some(superclass) = self.peek(1).object().and_then(Object::as_class) else {
+            return Err(self.runtime_error("Superclass must be a class."));
+        };
+
+        let Some(subclass) = self.peek(0).object().and_then(Object::as_class) else {
+            return Err(InterpretError::Ice("Inheriting into a non-class"));
+        };
+
+        // the compiler rejects a class inheriting from itself, but bytecode can still do it, and
+        // it already has its own methods
+        if !std::ptr::eq(superclass, subclass) {
+            let methods = superclass.methods.borrow();
+            let grown = update_table(&subclass.methods, |table| {
+                table.extend(methods.iter().map(|(name, method)| (*name, *method)));
+            });
+            drop(methods);
+            self.grow(grown);
+        }
+
+        self.pop()?;
+        Ok(())
+    }
+
+    fn pop_class(&mut self) -> Result<ObjRef, InterpretError> {
+        let class = self.pop()?;
+        match class.as_object() {
+            Some(object) if class.object().and_then(Object::as_class).is_some() => Ok(object),
+            _ => Err(InterpretError::Ice("Superclass is not a class")),
+        }
+    }
+
+    fn get_super(&mut self, name: Symbol) -> Result<(), InterpretError> {
+        let superclass = self.pop_class()?;
+        self.bind_method(superclass, name)
+    }
+
+    fn super_invoke(&mut self, name: Symbol, arg_count: usize) -> Result<(), InterpretError> {
+        let superclass = self.pop_class()?;
+        self.invoke_from_class(superclass, name, arg_count)
+    }
+
+    fn define_method(&mut self, name: Symbol) -> Result<(), InterpretError> {
+        let method = match self.peek(0).as_object() {
+            Some(method) if self.peek(0).object().and_then(Object::as_closure).is_some() => method,
+            _ => return Err(InterpretError::Ice("Method is not a closure")),
+        };
+
+        let grown = match self.peek(1).object().and_then(Object::as_class) {
+            Some(class) => update_table(&class.methods, |table| {
+                table.insert(name, method);
+            }),
+            _ => return Err(InterpretError::Ice("Method defined outside a class")),
+        };
+        self.grow(grown);
+
+        self.pop()?;
+        Ok(())
+    }
+
+    /// The instance `depth` slots down the stack, if that is one
+    fn peek_instance(&self, depth: usize) -> Option<&Instance> {
+        self.peek(depth).object()?.as_instance()
+    }
+
+    fn get_property(&mut self, name: Symbol) -> Result<(), InterpretError> {
+        let Some(instance) = self.peek_instance(0) else {
+            return Err(self.runtime_error("Only instances have properties."));
+        };
+
+        let field = instance.fields.borrow().get(&name).cloned();
+        let class = instance.class;
+        if let Some(value) = field {
+            self.pop()?;
+            self.push(value);
+            return Ok(());
+        }
+
+        self.bind_method(class, name)
+    }
+
+    fn set_property(&mut self, name: Symbol) -> Result<(), InterpretError> {
+        let Some(instance) = self.peek_instance(1) else {
+            return Err(self.runtime_error("Only instances have fields."));
+        };
+
+        let value = *self.peek(0);
+        let grown = update_table(&instance.fields, |table| {
+            table.insert(name, value);
+        });
+        self.grow(grown);
+        self.pop()?;
+        self.pop()?;
+        self.push(value);
+
+        Ok(())
+    }
+
+    /// Replaces the instance on top of the stack with its method `name`, bound to it
+    fn bind_method(&mut self, class: ObjRef, name: Symbol) -> Result<(), InterpretError> {
+        let method = Self::find_method(class, name);
+        let Some(method) = method else {
+            return Err(self.undefined_property(name));
+        };
+        // methods are checked as they are defined, but `BoundMethod::function` relies on this
+        let closure = Value::from(method);
+        if closure.object().and_then(Object::as_closure).is_none() {
+            return Err(InterpretError::Ice("Method is not a closure"));
+        }
+
+        // a superclass has already been popped, so its method is kept on the stack while the
+        // bound method is allocated
+        self.push(method.into());
+        let bound = self.add_object(BoundMethod {
+            receiver: *self.peek(1),
+            method,
+        });
+        self.pop()?;
+        self.pop()?;
+        self.push(bound);
+
+        Ok(())
+    }
+
+    fn invoke(&mut self, name: Symbol, arg_count: usize) -> Result<(), InterpretError> {
+        let Some(instance) = self.peek_instance(arg_count) else {
+            return Err(self.runtime_error("Only instances have methods."));
+        };
+
+        // a field holding a function shadows a method of the same name
+        let field = instance.fields.borrow().get(&name).cloned();
+        let class = instance.class;
+        if let Some(field) = field {
+            let callee_slot = self.stack.len() - arg_count - 1;
+            self.stack[callee_slot] = field;
+            return self.call_value(field, arg_count);
+        }
+
+        self.invoke_from_class(class, name, arg_count)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: ObjRef,
+        name: Symbol,
+        arg_count: usize,
+    ) -> Result<(), InterpretError> {
+        match Self::find_method(class, name) {
+            Some(method) => self.call(method, arg_count),
+            None => Err(self.undefined_property(name)),
+        }
+    }
+
+    /// Looks up a method of a class that is on the stack, reachable from it, or has just been
+    /// popped from it
+    fn find_method(class: ObjRef, name: Symbol) -> Option<ObjRef> {
+        // SAFETY: nothing has been collected since the class was on the stack
+        let class = unsafe { class.get() }.as_class().unwrap();
+        class.methods.borrow().get(&name).cloned()
+    }
+
+    fn undefined_property(&mut self, name: Symbol) -> InterpretError {
+        let name = self.heap.resolve(name).unwrap_or("?");
+        let message = format!("Undefined property '{}'.", name);
+        self.runtime_error(message)
+    }
+
+    fn closure(&mut self, constant_id: usize) -> Result<(), InterpretError> {
+        let constant = *self.frame().chunk().get_constant(constant_id);
+        let Some(function) = constant.as_function() else {
+            return Err(InterpretError::Ice("Closure of a non-function"));
+        };
+        let upvalue_count = function.upvalue_count;
+        let function = constant.as_object().unwrap();
+
+        let mut upvalues = Vec::with_capacity(upvalue_count);
+
+        for _ in 0..upvalue_count {
+            let is_local = self.read_byte() == 1;
+            let index = self.read_byte() as usize;
+
+            if is_local {
+                let slot = self.frame().slots + index;
+                upvalues.push(self.capture_upvalue(slot));
+            } else {
+                upvalues.push(self.frame().closure().upvalues[index]);
+            }
+        }
+
+        let closure = self.add_object(Closure { function, upvalues });
+        self.push(closure);
+        Ok(())
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let position = self.open_upvalues.binary_search_by_key(&slot, |upvalue| {
+            // SAFETY: open upvalues are roots
+            match &*unsafe { upvalue.get() }.as_upvalue().unwrap().borrow() {
+                Upvalue::Open(slot) => *slot,
+                Upvalue::Closed(_) => unreachable!(),
+            }
+        });
+
+        match position {
+            Ok(index) => self.open_upvalues[index],
+            Err(index) => {
+                let upvalue = self.alloc(Upvalue::Open(slot));
+                self.open_upvalues.insert(index, upvalue);
+                upvalue
             }
         }
+    }
+
+    /// Moves every captured variable at or above `last` off the stack and into its upvalue
+    fn close_upvalues(&mut self, last: usize) -> Result<(), InterpretError> {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            // SAFETY: open upvalues are roots
+            let mut upvalue = unsafe { upvalue.get() }.as_upvalue().unwrap().borrow_mut();
+            let slot = match &*upvalue {
+                Upvalue::Open(slot) => *slot,
+                Upvalue::Closed(_) => unreachable!(),
+            };
 
-        let e = self.report_runtime_error("Both operands must be numbers");
-        Err(e)
+            if slot < last {
+                break;
+            }
+
+            *upvalue = Upvalue::Closed(*self.stack.get(slot).ok_or(UPVALUE_OFF_STACK)?);
+            drop(upvalue);
+            self.open_upvalues.pop();
+        }
+        Ok(())
     }
 
-    fn report_runtime_error(&mut self, message: &'static str) -> InterpretError {
-        let line = self.chunk.get_line(self.ip);
-        eprintln!("[line {}] in script", line);
-        self.reset_stack();
-        InterpretError::RuntimeError(message)
+    /// Builds an error with a trace of the calls in progress
+    fn runtime_error(&self, message: impl Into<String>) -> InterpretError {
+        let trace = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| TraceFrame {
+                function: frame.function().name.clone(),
+                // the ip has already moved past the failing instruction
+                line: frame.chunk().get_line(frame.ip - 1),
+            })
+            .collect();
+
+        InterpretError::RuntimeError(RuntimeError {
+            message: message.into(),
+            trace,
+        })
     }
 
     fn concatenate(&mut self) -> Result<(), InterpretError> {
@@ -260,8 +1302,7 @@ impl Vm {
         let a = TryInto::<String>::try_into(self.pop()?)?;
 
         let string = format!("{}{}", a, b);
-
-        let v = self.add_object(string);
+        let v = self.intern(&string).into();
 
         self.push(v);
 
@@ -269,16 +1310,76 @@ impl Vm {
     }
 
     fn add_object<T: Into<Object>>(&mut self, o: T) -> Value {
-        let rc = Rc::new(o.into());
-        self.objects.push(Rc::downgrade(&rc));
-        Value::Object(rc)
+        self.alloc(o).into()
     }
 
-    /*
-    fn trace(&mut self) -> { todo!() }
+    /// Allocates an object, collecting garbage first if needed
+    ///
+    /// Any object the caller holds onto must be reachable from the roots, such as the stack,
+    /// before calling this.
+    fn alloc<T: Into<Object>>(&mut self, o: T) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+
+        let object = self.heap.alloc(o);
+        self.check_memory();
+        object
+    }
+
+    /// Interns a string, collecting garbage first if needed
+    fn intern(&mut self, text: &str) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+
+        let string = self.heap.intern(text);
+        self.check_memory();
+        string
+    }
+
+    /// Accounts for an object having grown, such as an instance gaining a field
+    fn grow(&mut self, bytes: usize) {
+        self.heap.grow(bytes);
+        self.check_memory();
+    }
+
+    /// Has the limits checked before the next instruction if the heap has grown past its limit
+    fn check_memory(&mut self) {
+        if self.heap.bytes_allocated() > self.options.limits.memory {
+            self.next_check = self.executed;
+        }
+    }
 
-    fn resolve_cycles(&mut self) -> { todo!() }
-    */
+    pub fn collect_garbage(&mut self) {
+        self.mark_roots();
+        self.heap.trace_references();
+        self.heap.sweep();
+    }
+
+    fn mark_roots(&mut self) {
+        for value in self.stack.iter() {
+            self.heap.mark_value(*value);
+        }
+
+        for frame in self.frames.iter() {
+            self.heap.mark_object(frame.closure);
+        }
+
+        for upvalue in self.open_upvalues.iter() {
+            self.heap.mark_object(*upvalue);
+        }
+
+        // names are kept alive even while undefined, so their slots stay resolvable
+        for (name, value) in self.globals.iter() {
+            self.heap.mark_symbol(name);
+            if let Some(value) = value {
+                self.heap.mark_value(value);
+            }
+        }
+
+        self.heap.mark_symbol(self.init_symbol);
+    }
 }
 
 fn greater_than(a: f64, b: f64) -> bool {
@@ -287,11 +1388,73 @@ fn greater_than(a: f64, b: f64) -> bool {
 fn less_than(a: f64, b: f64) -> bool {
     a < b
 }
+// the fused comparisons behave like the pair of instructions they replace, so they are true
+// when either operand is NaN
+#[allow(clippy::neg_cmp_op_on_partial_ord)]
+fn greater_equal(a: f64, b: f64) -> bool {
+    !(a < b)
+}
+#[allow(clippy::neg_cmp_op_on_partial_ord)]
+fn less_equal(a: f64, b: f64) -> bool {
+    !(a > b)
+}
+
+/// An error raised by a running script
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub message: String,
+    /// The calls in progress when the error happened, innermost first
+    pub trace: Vec<TraceFrame>,
+}
+
+impl RuntimeError {
+    /// The line of the instruction that failed
+    pub fn line(&self) -> Option<usize> {
+        self.trace.first().map(|frame| frame.line)
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in &self.trace {
+            write!(f, "\n{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+/// A call in progress, and the line it had reached
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceFrame {
+    /// The name of the function called, `None` for the script
+    pub function: Option<String>,
+    pub line: usize,
+}
+
+impl std::fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {}()", self.line, name),
+            None => write!(f, "[line {}] in script", self.line),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum InterpretError {
-    CompileError(&'static str),
-    RuntimeError(&'static str),
+    CompileError(Vec<Diagnostic>),
+    RuntimeError(RuntimeError),
+    AsmError(asm::AsmError),
+    LoadError(bytecode::LoadError),
+    VerifyError(verify::VerifyError),
+    LimitError(LimitError),
+    /// The script was stopped through `Vm::interrupt_handle`
+    Interrupted,
+    /// A native tried to run a script while the `Vm` was already running one
+    Reentrant,
     Ice(&'static str),
 }
 
@@ -301,8 +1464,20 @@ impl std::fmt::Display for InterpretError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         use InterpretError::*;
         match self {
-            CompileError(s) => write!(f, "Compile Error: {}", s),
-            RuntimeError(s) => write!(f, "Runtime Error: {}", s),
+            CompileError(diagnostics) => {
+                write!(f, "Compile Error:")?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n{}", diagnostic)?;
+                }
+                Ok(())
+            }
+            RuntimeError(e) => write!(f, "Runtime Error: {}", e),
+            AsmError(e) => write!(f, "Assembly Error: {}", e),
+            LoadError(e) => write!(f, "Load Error: {}", e),
+            VerifyError(e) => write!(f, "Verify Error: {}", e),
+            LimitError(e) => write!(f, "Limit Error: {}", e),
+            Interrupted => write!(f, "Interrupted"),
+            Reentrant => write!(f, "Cannot run a script while another is running"),
             Ice(s) => write!(f, "Internal Compiler Error: {}", s),
         }
     }
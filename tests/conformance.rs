@@ -0,0 +1,35 @@
+//! Runs the scripts in `tests/lox`, which say what they should do in `// expect:` comments
+//!
+//! Set `BYLOX_TEST_FILTER` to only run the scripts whose path contains it.
+
+use bylox::conformance;
+
+#[test]
+fn lox_scripts() {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+    let filter = std::env::var("BYLOX_TEST_FILTER").ok();
+    let tests = conformance::find_tests(&dir, filter.as_deref()).unwrap();
+    assert!(
+        filter.is_some() || !tests.is_empty(),
+        "no scripts in {}",
+        dir.display()
+    );
+
+    let jobs = std::thread::available_parallelism().map_or(1, |jobs| jobs.get());
+    let failures: Vec<String> = conformance::run_all(&tests, jobs)
+        .into_iter()
+        .filter(|outcome| !outcome.passed())
+        .map(|outcome| {
+            let path = outcome.path.strip_prefix(&dir).unwrap_or(&outcome.path);
+            format!("{}:\n{}", path.display(), outcome.failures.join("\n"))
+        })
+        .collect();
+
+    assert!(
+        failures.is_empty(),
+        "{} of {} scripts failed\n\n{}",
+        failures.len(),
+        tests.len(),
+        failures.join("\n\n")
+    );
+}
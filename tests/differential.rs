@@ -0,0 +1,39 @@
+//! Checks that the `Vm` and the reference interpreter agree on the scripts in `tests/lox` and on
+//! a batch of generated programs
+
+use bylox::differential;
+
+fn assert_agree(programs: impl Iterator<Item = (String, String)>) {
+    let mismatches: Vec<String> = programs
+        .filter_map(|(name, source)| {
+            let mismatch = differential::check(&source)?;
+            Some(format!("{}:\n{}", name, mismatch))
+        })
+        .collect();
+
+    assert!(
+        mismatches.is_empty(),
+        "{} programs mismatched\n\n{}",
+        mismatches.len(),
+        mismatches.join("\n")
+    );
+}
+
+#[test]
+fn lox_scripts() {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lox");
+    let scripts = bylox::conformance::find_tests(&dir, None).unwrap();
+
+    assert_agree(scripts.into_iter().map(|path| {
+        let source = std::fs::read_to_string(&path).unwrap();
+        (path.display().to_string(), source)
+    }));
+}
+
+#[test]
+fn random_programs() {
+    assert_agree((0..200).map(|seed| {
+        let name = format!("random program (--seed {})", seed);
+        (name, differential::generate(seed))
+    }));
+}
@@ -0,0 +1,54 @@
+//! Runs the fuzz targets over their corpus in `fuzz/corpus`, so inputs that once found bugs keep
+//! being checked without a fuzzer
+
+use bylox::fuzz;
+
+fn replay(target: &str, run: fn(&[u8])) {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("fuzz/corpus")
+        .join(target);
+
+    let mut count = 0;
+    for entry in std::fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        let data = std::fs::read(&path).unwrap();
+        if let Err(panic) = std::panic::catch_unwind(|| run(&data)) {
+            eprintln!("{} failed on {}", target, path.display());
+            std::panic::resume_unwind(panic);
+        }
+        count += 1;
+    }
+    assert!(count > 0, "no corpus in {}", dir.display());
+}
+
+#[test]
+fn scan() {
+    replay("scan", fuzz::scan);
+}
+
+#[test]
+fn compile() {
+    replay("compile", fuzz::compile);
+}
+
+#[test]
+fn execute() {
+    replay("execute", fuzz::execute);
+}
+
+#[test]
+fn grammar() {
+    replay("grammar", fuzz::grammar);
+
+    // the corpus is small, so add some inputs of every length
+    let mut state = 1u32;
+    for len in 0..300 {
+        let data: Vec<u8> = (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect();
+        fuzz::grammar(&data);
+    }
+}